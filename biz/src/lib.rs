@@ -1,6 +1,10 @@
-use std::io::{self, BufRead};
+use std::{
+    io::{self, BufRead},
+    time::Duration,
+};
 
-use custom::{get_custom, register_node};
+use custom::{broadcast_notice, get_custom, register_node};
+use frame_common::data::{AckLevel, AnycastStrategy, Priority};
 use frame_common::get_runtime;
 
 use tokio::runtime::Runtime;
@@ -19,6 +23,83 @@ enum Command {
         to: Box<String>,
         content: Box<String>,
     },
+    SendReliableMsg {
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+    },
+    SendTrackedMsg {
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+    },
+    SendPriorityMsg {
+        from: Box<String>,
+        to: Box<String>,
+        priority: Priority,
+        content: Box<String>,
+    },
+    SendLaterMsg {
+        from: Box<String>,
+        to: Box<String>,
+        delay_ms: u64,
+        content: Box<String>,
+    },
+    SendTtlMsg {
+        from: Box<String>,
+        to: Box<String>,
+        ttl_ms: u64,
+        content: Box<String>,
+    },
+    Request {
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+    },
+    SendGroupMsg {
+        from: Box<String>,
+        group: Box<String>,
+        content: Box<String>,
+    },
+    SendAnyMsg {
+        from: Box<String>,
+        group: Box<String>,
+        content: Box<String>,
+    },
+    SendKeyMsg {
+        from: Box<String>,
+        group: Box<String>,
+        key: Box<String>,
+        content: Box<String>,
+    },
+    Subscribe {
+        name: Box<String>,
+        pattern: Box<String>,
+    },
+    Unsubscribe {
+        name: Box<String>,
+        pattern: Box<String>,
+    },
+    Publish {
+        from: Box<String>,
+        topic: Box<String>,
+        content: Box<String>,
+    },
+    AllowBroadcast {
+        name: Box<String>,
+        group: Box<String>,
+    },
+    Broadcast {
+        from: Box<String>,
+        content: Box<String>,
+    },
+    Notice {
+        content: Box<String>,
+    },
+    DeadLetters {
+        action: Box<String>,
+        id: Option<u64>,
+    },
     Shutdown,
 }
 
@@ -59,6 +140,181 @@ pub fn launch_service() {
                     println!("send msg failed,error={}", error);
                 }
             }
+            Command::SendReliableMsg { from, to, content } => {
+                let sent = machine
+                    .send_reliable_message(&rt, from, to, content)
+                    .and_then(|sending| rt.block_on(sending).map_err(|err| err.to_string())?);
+                match sent {
+                    Ok(attempts) => println!("message delivered after {} attempts", attempts),
+                    Err(error) => println!("send reliable msg failed,error={}", error),
+                }
+            }
+            Command::SendTrackedMsg { from, to, content } => {
+                let tracked = machine.send_tracked_message(
+                    &rt,
+                    from,
+                    to,
+                    content,
+                    AckLevel::Handled,
+                    Duration::from_secs(5),
+                );
+                match tracked {
+                    Ok(mut tracking) => {
+                        for level in [AckLevel::Delivered, AckLevel::Handled] {
+                            match rt.block_on(tracking.wait_for(level)) {
+                                Ok(()) => println!("message {:?}", level),
+                                Err(error) => {
+                                    println!("wait ack failed,error={}", error);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Err(error) => println!("send tracked msg failed,error={}", error),
+                }
+            }
+            Command::SendPriorityMsg {
+                from,
+                to,
+                priority,
+                content,
+            } => {
+                if let Err(error) =
+                    &machine.send_priority_message(&rt, from, to, content, priority)
+                {
+                    println!("send priority msg failed,error={}", error);
+                }
+            }
+            Command::SendLaterMsg {
+                from,
+                to,
+                delay_ms,
+                content,
+            } => {
+                let delay = Duration::from_millis(delay_ms);
+                if let Err(error) = &machine.send_delayed_message(&rt, from, to, content, delay) {
+                    println!("send later msg failed,error={}", error);
+                }
+            }
+            Command::SendTtlMsg {
+                from,
+                to,
+                ttl_ms,
+                content,
+            } => {
+                let ttl = Duration::from_millis(ttl_ms);
+                if let Err(error) =
+                    &machine.send_expiring_message(&rt, from, to, content, ttl, true)
+                {
+                    println!("send ttl msg failed,error={}", error);
+                }
+            }
+            Command::Request { from, to, content } => {
+                let reply = machine
+                    .request(&rt, from, to, content, Duration::from_secs(5))
+                    .and_then(|call| rt.block_on(call.wait()));
+                match reply {
+                    Ok(reply) => println!("reply from {}: {}", reply.from_name, reply.message),
+                    Err(error) => println!("request failed,error={}", error),
+                }
+            }
+            Command::SendGroupMsg { from, group, content } => {
+                if let Err(error) =
+                    &machine.send_group_message(&rt, from, group, content, true, true)
+                {
+                    println!("send group msg failed,error={}", error);
+                }
+            }
+            Command::SendAnyMsg { from, group, content } => {
+                if let Err(error) = &machine.send_anycast_message(
+                    &rt,
+                    from,
+                    group,
+                    content,
+                    AnycastStrategy::RoundRobin,
+                ) {
+                    println!("send anycast msg failed,error={}", error);
+                }
+            }
+            Command::SendKeyMsg {
+                from,
+                group,
+                key,
+                content,
+            } => {
+                if let Err(error) =
+                    &machine.send_partitioned_message(&rt, from, group, &key, content)
+                {
+                    println!("send key msg failed,error={}", error);
+                }
+            }
+            Command::Subscribe { name, pattern } => {
+                if let Err(error) = &machine.subscribe(&rt, name, &pattern) {
+                    println!("subscribe failed,error={}", error);
+                }
+            }
+            Command::Unsubscribe { name, pattern } => {
+                if let Err(error) = &machine.unsubscribe(&rt, name, &pattern) {
+                    println!("unsubscribe failed,error={}", error);
+                }
+            }
+            Command::Publish {
+                from,
+                topic,
+                content,
+            } => {
+                if let Err(error) = &machine.publish(&rt, from, &topic, content) {
+                    println!("publish failed,error={}", error);
+                }
+            }
+            Command::AllowBroadcast { name, group } => {
+                if let Err(error) = relayer.allow_broadcast(&name, &group) {
+                    println!("allow broadcast failed,error={}", error);
+                }
+            }
+            Command::Broadcast { from, content } => {
+                if let Err(error) = &machine.send_broadcast_message(&rt, from, content, true, false)
+                {
+                    println!("broadcast failed,error={}", error);
+                }
+            }
+            Command::Notice { content } => match broadcast_notice(&relayer, &content) {
+                Ok(count) => println!("notice reach {} nodes", count),
+                Err(error) => println!("notice failed,error={}", error),
+            },
+            Command::DeadLetters { action, id } => match (action.as_str(), id) {
+                ("list", _) => match relayer.dead_letters() {
+                    Ok(letters) => {
+                        println!("{} dead letters", letters.len());
+                        for letter in letters {
+                            println!(
+                                "{}: {} to {}, {}",
+                                letter.id, letter.source, letter.target, letter.reason
+                            );
+                        }
+                    }
+                    Err(error) => println!("list dead letters failed,error={}", error),
+                },
+                ("show", Some(id)) => match relayer.inspect_dead_letter(id) {
+                    Ok(letter) => println!(
+                        "{}: on {}, {}, message={:?}",
+                        letter.id, letter.connection, letter.reason, letter.message
+                    ),
+                    Err(error) => println!("show dead letter failed,error={}", error),
+                },
+                ("purge", id) => match relayer.purge_dead_letters(id) {
+                    Ok(count) => println!("purged {} dead letters", count),
+                    Err(error) => println!("purge dead letters failed,error={}", error),
+                },
+                ("redeliver", Some(id)) => match rt.block_on(relayer.redeliver_dead_letter(id)) {
+                    Ok(()) => println!("dead letter {} redelivered", id),
+                    Err(error) => println!("redeliver dead letter failed,error={}", error),
+                },
+                (action, id) => println!(
+                    "dead letters failed,error=unsupported action {} with id {:?}, use 'list', 'show;id', 'purge;id', 'purge;all' or 'redeliver;id'",
+                    action, id
+                ),
+            },
             Command::Shutdown => {
                 flag = true;
                 break;
@@ -112,6 +368,208 @@ fn parse_command(input: &str) -> Result<Command, &str> {
                 content: Box::new((*infos.get(2).unwrap()).to_string()),
             };
         }
+        "SendReliableMsg" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 3 {
+                return Err("Error! SendReliableMsg info should be 'from;to;content'");
+            }
+            command = Command::SendReliableMsg {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                to: Box::new((*infos.get(1).unwrap()).to_string()),
+                content: Box::new((*infos.get(2).unwrap()).to_string()),
+            };
+        }
+        "SendTrackedMsg" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 3 {
+                return Err("Error! SendTrackedMsg info should be 'from;to;content'");
+            }
+            command = Command::SendTrackedMsg {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                to: Box::new((*infos.get(1).unwrap()).to_string()),
+                content: Box::new((*infos.get(2).unwrap()).to_string()),
+            };
+        }
+        "SendPriorityMsg" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 4 {
+                return Err("Error! SendPriorityMsg info should be 'from;to;priority;content'");
+            }
+            let priority = match infos.get(2).unwrap().trim() {
+                "urgent" => Priority::Urgent,
+                "high" => Priority::High,
+                "normal" => Priority::Normal,
+                "bulk" => Priority::Bulk,
+                _ => return Err("Error! priority should be urgent, high, normal or bulk"),
+            };
+            command = Command::SendPriorityMsg {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                to: Box::new((*infos.get(1).unwrap()).to_string()),
+                priority,
+                content: Box::new((*infos.get(3).unwrap()).to_string()),
+            };
+        }
+        "SendLaterMsg" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 4 {
+                return Err("Error! SendLaterMsg info should be 'from;to;delay_ms;content'");
+            }
+            let delay_ms = match infos.get(2).unwrap().trim().parse::<u64>() {
+                Ok(delay_ms) => delay_ms,
+                Err(_) => return Err("Error! SendLaterMsg delay_ms should be a number"),
+            };
+            command = Command::SendLaterMsg {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                to: Box::new((*infos.get(1).unwrap()).to_string()),
+                delay_ms,
+                content: Box::new((*infos.get(3).unwrap()).to_string()),
+            };
+        }
+        "SendTtlMsg" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 4 {
+                return Err("Error! SendTtlMsg info should be 'from;to;ttl_ms;content'");
+            }
+            let ttl_ms = match infos.get(2).unwrap().trim().parse::<u64>() {
+                Ok(ttl_ms) => ttl_ms,
+                Err(_) => return Err("Error! SendTtlMsg ttl_ms should be a number"),
+            };
+            command = Command::SendTtlMsg {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                to: Box::new((*infos.get(1).unwrap()).to_string()),
+                ttl_ms,
+                content: Box::new((*infos.get(3).unwrap()).to_string()),
+            };
+        }
+        "Request" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 3 {
+                return Err("Error! Request info should be 'from;to;content'");
+            }
+            command = Command::Request {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                to: Box::new((*infos.get(1).unwrap()).to_string()),
+                content: Box::new((*infos.get(2).unwrap()).to_string()),
+            };
+        }
+        "SendGroupMsg" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 3 {
+                return Err("Error! SendGroupMsg info should be 'from;group;content'");
+            }
+            command = Command::SendGroupMsg {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                group: Box::new((*infos.get(1).unwrap()).to_string()),
+                content: Box::new((*infos.get(2).unwrap()).to_string()),
+            };
+        }
+        "SendAnyMsg" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 3 {
+                return Err("Error! SendAnyMsg info should be 'from;group;content'");
+            }
+            command = Command::SendAnyMsg {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                group: Box::new((*infos.get(1).unwrap()).to_string()),
+                content: Box::new((*infos.get(2).unwrap()).to_string()),
+            };
+        }
+        "SendKeyMsg" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 4 {
+                return Err("Error! SendKeyMsg info should be 'from;group;key;content'");
+            }
+            command = Command::SendKeyMsg {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                group: Box::new((*infos.get(1).unwrap()).to_string()),
+                key: Box::new((*infos.get(2).unwrap()).to_string()),
+                content: Box::new((*infos.get(3).unwrap()).to_string()),
+            };
+        }
+        "Subscribe" | "Unsubscribe" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 2 {
+                return Err("Error! Subscribe info should be 'name;pattern'");
+            }
+            let name = Box::new((*infos.get(0).unwrap()).to_string());
+            let pattern = Box::new((*infos.get(1).unwrap()).to_string());
+            command = if *command_str == "Subscribe" {
+                Command::Subscribe { name, pattern }
+            } else {
+                Command::Unsubscribe { name, pattern }
+            };
+        }
+        "Publish" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 3 {
+                return Err("Error! Publish info should be 'from;topic;content'");
+            }
+            command = Command::Publish {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                topic: Box::new((*infos.get(1).unwrap()).to_string()),
+                content: Box::new((*infos.get(2).unwrap()).to_string()),
+            };
+        }
+        "AllowBroadcast" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 2 {
+                return Err("Error! AllowBroadcast info should be 'name;group'");
+            }
+            command = Command::AllowBroadcast {
+                name: Box::new((*infos.get(0).unwrap()).to_string()),
+                group: Box::new((*infos.get(1).unwrap()).to_string()),
+            };
+        }
+        "Broadcast" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            if infos.len() != 2 {
+                return Err("Error! Broadcast info should be 'from;content'");
+            }
+            command = Command::Broadcast {
+                from: Box::new((*infos.get(0).unwrap()).to_string()),
+                content: Box::new((*infos.get(1).unwrap()).to_string()),
+            };
+        }
+        "Notice" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            command = Command::Notice {
+                content: Box::new(info.trim().to_string()),
+            };
+        }
+        "DeadLetters" => {
+            let info = inputs.get(1).unwrap().replace('}', "");
+            let infos: Vec<&str> = info.trim().split(';').collect();
+            let action = infos.get(0).unwrap().trim();
+            let id = match (action, infos.get(1).map(|id| id.trim())) {
+                ("list", None) | ("purge", Some("all")) => None,
+                ("show" | "purge" | "redeliver", Some(id)) => match id.parse::<u64>() {
+                    Ok(id) => Some(id),
+                    Err(_) => return Err("Error! dead letter id should be a number"),
+                },
+                _ => {
+                    return Err(
+                        "Error! DeadLetters info should be 'list', 'show;id', 'purge;id', 'purge;all' or 'redeliver;id'",
+                    )
+                }
+            };
+            command = Command::DeadLetters {
+                action: Box::new(action.to_string()),
+                id,
+            };
+        }
         _ => return Err("command not support"),
     }
     Ok(command)
@@ -18,6 +18,58 @@ fn test_input() {
     assert_eq!(true, res.is_err());
     res = parse_command("SendMsg{A1;A2;this is A1, to A group}");
     assert_eq!(true, res.is_ok());
+    res = parse_command("SendGroupMsg{A1;B;this is A1, to every B}");
+    assert!(res.is_ok());
+    res = parse_command("SendGroupMsg{A1;this is A1}");
+    assert!(res.is_err());
+    res = parse_command("SendAnyMsg{A1;B;any B will do}");
+    assert!(res.is_ok());
+    res = parse_command("SendKeyMsg{A1;B;order-42;order 42 shipped}");
+    assert!(res.is_ok());
+    res = parse_command("SendKeyMsg{A1;B;order 42 shipped}");
+    assert!(res.is_err());
+    res = parse_command("SendReliableMsg{A1;B1;must arrive}");
+    assert!(res.is_ok());
+    res = parse_command("SendTrackedMsg{A1;B1;did you get it}");
+    assert!(res.is_ok());
+    res = parse_command("SendPriorityMsg{A1;B1;urgent;stop the export}");
+    assert!(res.is_ok());
+    res = parse_command("SendPriorityMsg{A1;B1;asap;stop the export}");
+    assert!(res.is_err());
+    res = parse_command("SendLaterMsg{A1;B1;60000;stand-up in 5 minutes}");
+    assert!(res.is_ok());
+    res = parse_command("SendLaterMsg{A1;B1;stand-up in 5 minutes}");
+    assert!(res.is_err());
+    res = parse_command("SendTtlMsg{A1;B1;500;flash sale}");
+    assert!(res.is_ok());
+    res = parse_command("SendTtlMsg{A1;B1;soon;flash sale}");
+    assert!(res.is_err());
+    res = parse_command("Request{A1;B1;how are you}");
+    assert!(res.is_ok());
+    res = parse_command("Request{A1;how are you}");
+    assert!(res.is_err());
+    res = parse_command("Subscribe{B1;orders.*}");
+    assert!(res.is_ok());
+    res = parse_command("Unsubscribe{B1;orders.*}");
+    assert!(res.is_ok());
+    res = parse_command("Publish{A1;orders.created;order 42 created}");
+    assert!(res.is_ok());
+    res = parse_command("Publish{A1;order 42 created}");
+    assert!(res.is_err());
+    res = parse_command("AllowBroadcast{A1;A}");
+    assert!(res.is_ok());
+    res = parse_command("Broadcast{A1;this is A1, to everyone}");
+    assert!(res.is_ok());
+    res = parse_command("Notice{relayer restarts at noon}");
+    assert!(res.is_ok());
+    res = parse_command("DeadLetters{list}");
+    assert!(res.is_ok());
+    res = parse_command("DeadLetters{redeliver;3}");
+    assert!(res.is_ok());
+    res = parse_command("DeadLetters{purge;all}");
+    assert!(res.is_ok());
+    res = parse_command("DeadLetters{show}");
+    assert!(res.is_err());
 }
 
 #[test]
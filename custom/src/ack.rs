@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use frame_common::{
+    data::{Ack, AckLevel, BridgeMessage, Router},
+    id::NodeId,
+};
+use log::debug;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AckStatus {
+    /// no status event yet
+    Sent,
+    Reached(AckLevel),
+    /// the relayer bounced the message
+    Failed(String),
+}
+
+struct Tracker {
+    /// source id the status events must come from
+    responder: NodeId,
+    level: AckLevel,
+    reached: Option<AckLevel>,
+    status: watch::Sender<AckStatus>,
+}
+
+/// Messages of one machine still waiting for status events, by ack id.
+#[derive(Clone, Default)]
+pub struct AckTable {
+    next_id: Arc<AtomicU64>,
+    trackers: Arc<Mutex<HashMap<u64, Tracker>>>,
+}
+
+impl AckTable {
+    /// Track a message to `responder` that asks for acks up to `level`.
+    pub fn start(
+        &self,
+        responder: &NodeId,
+        level: AckLevel,
+        timeout: Duration,
+    ) -> Result<Tracking, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = watch::channel(AckStatus::Sent);
+        let tracker = Tracker {
+            responder: responder.clone(),
+            level,
+            reached: None,
+            status: tx,
+        };
+        self.trackers
+            .lock()
+            .map_err(|err| err.to_string())?
+            .insert(id, tracker);
+        Ok(Tracking {
+            id,
+            timeout,
+            status: rx,
+            table: self.clone(),
+        })
+    }
+
+    /// Apply a status event, or the relayer bouncing a tracked message.
+    /// Returns false if nobody tracks it any more.
+    pub fn resolve(&self, message: &BridgeMessage) -> bool {
+        let (id, status) = match (message.ack, &message.error_msg) {
+            (Some(Ack::Reached { id, level }), None) => (id, AckStatus::Reached(level)),
+            (Some(Ack::Wanted { id, .. }), Some(error)) => {
+                (id, AckStatus::Failed(error.to_string()))
+            }
+            _ => return false,
+        };
+        let mut lock = match self.trackers.lock() {
+            Ok(lock) => lock,
+            Err(_) => return false,
+        };
+        let tracker = match lock.get_mut(&id) {
+            Some(tracker) => tracker,
+            None => return false,
+        };
+        if let AckStatus::Reached(level) = status {
+            if tracker.responder != message.get_source_id() {
+                debug!(
+                    "drop ack {} from unexpected node {}",
+                    id,
+                    message.get_source_id()
+                );
+                return false;
+            }
+            // status only moves forward
+            if tracker.reached >= Some(level) {
+                return true;
+            }
+            tracker.reached = Some(level);
+        }
+        let done = match status {
+            AckStatus::Reached(level) => level >= tracker.level,
+            _ => true,
+        };
+        let _ = tracker.status.send(status);
+        if done {
+            lock.remove(&id);
+        }
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.trackers.lock().map(|lock| lock.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn forget(&self, id: u64) {
+        if let Ok(mut lock) = self.trackers.lock() {
+            lock.remove(&id);
+        }
+    }
+}
+
+/// Status events of one sent message. Dropping it stops tracking, later
+/// events are discarded.
+pub struct Tracking {
+    id: u64,
+    timeout: Duration,
+    status: watch::Receiver<AckStatus>,
+    table: AckTable,
+}
+
+impl Tracking {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn status(&self) -> AckStatus {
+        self.status.borrow().clone()
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(*self.status.borrow(), AckStatus::Failed(_))
+    }
+
+    /// Wait until the message reached `level`, at most the timeout given
+    /// when it was sent.
+    pub async fn wait_for(&mut self, level: AckLevel) -> Result<(), String> {
+        self.wait_for_timeout(level, self.timeout).await
+    }
+
+    pub async fn wait_for_timeout(
+        &mut self,
+        level: AckLevel,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        let id = self.id;
+        let status = &mut self.status;
+        let finished = move |status: &AckStatus| match status {
+            AckStatus::Reached(reached) if *reached >= level => Some(Ok(())),
+            AckStatus::Failed(error) => Some(Err(error.clone())),
+            _ => None,
+        };
+        let reached = async move {
+            loop {
+                if let Some(result) = finished(&status.borrow()) {
+                    return result;
+                }
+                if status.changed().await.is_err() {
+                    return finished(&status.borrow())
+                        .unwrap_or_else(|| Err(format!("message {} is no longer tracked", id)));
+                }
+            }
+        };
+        tokio::time::timeout(timeout, reached)
+            .await
+            .map_err(|_| format!("ack {} timed out", id))?
+    }
+}
+
+impl Drop for Tracking {
+    fn drop(&mut self) {
+        self.table.forget(self.id);
+    }
+}
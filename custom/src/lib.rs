@@ -1,13 +1,18 @@
 use std::{sync::MutexGuard, time::Duration};
 
-use frame_common::{data::BridgeMessage};
+use frame_common::data::{AckLevel, BridgeMessage, ControlEvent};
+use log::error;
 use machine::Machine;
 use relayer::Relayer;
+use rpc::Replier;
 use threadpool::ThreadPool;
 use tokio::{sync::mpsc::{Sender}, runtime::Runtime};
 
+pub mod ack;
 pub mod machine;
 pub mod relayer;
+pub mod reliable;
+pub mod rpc;
 mod node;
 
 pub fn register_node(rt:&Runtime,name:&str,group:&str,addr:&str,machine:&mut Machine,relayer:&Relayer<BridgeMessage>)->Result<(),String>{
@@ -15,24 +20,89 @@ pub fn register_node(rt:&Runtime,name:&str,group:&str,addr:&str,machine:&mut Mac
     Ok(())
 }
 
+pub fn unregister_node(rt:&Runtime,name:&str,machine:&mut Machine,relayer:&Relayer<BridgeMessage>)->Result<(),String>{
+    rt.block_on(machine.unregister_node(relayer,name))
+}
+
+pub fn migrate_node(rt:&Runtime,name:&str,addr:&str,from:&mut Machine,to:&mut Machine,relayer:&Relayer<BridgeMessage>)->Result<(),String>{
+    rt.block_on(from.migrate_node(relayer,name,to,addr))
+}
+
 pub fn get_custom()->Result<(Machine,Relayer<BridgeMessage>),String>{
-    let machine=Machine::new();
+    let mut machine=Machine::new();
     let mut relayer=Relayer::<BridgeMessage>::new();
     relayer.launch();
     if !relayer.is_ready(){
         return Err("launch relayer failed".to_string());
     }
+    machine.pin_relayer_key(relayer.get_public_key().ok_or("relayer has no identity key")?);
     Ok((machine,relayer)) 
 }
 
-pub fn receive_msg(message:BridgeMessage,mutex_pool:MutexGuard<ThreadPool>){
+pub fn receive_msg(message:BridgeMessage,mutex_pool:MutexGuard<ThreadPool>,replier:Replier){
     print!("{}: receive msg from {}: ",message.to_name,message.from_name);
     println!("{}",message.message);
-    match message.to_group.as_str(){
-        "A"=>mutex_pool.execute(move || message_to_a(&message.message)),
-        "B"=>mutex_pool.execute(move || message_to_b(&message.message)),
-        "C"=>mutex_pool.execute(move || message_to_c(&message.message)),
-        _=> println!("no special task for the group"),
+    match group_handler(&message.to_group){
+        Some(handler)=>mutex_pool.execute(move || {
+            handler(&message.message);
+            ack_handled(&replier,&message);
+        }),
+        None=> {
+            println!("no special task for the group");
+            mutex_pool.execute(move || ack_handled(&replier,&message));
+        }
+    }
+}
+
+/// Run the group handler on a request and send what it returns back as the response.
+pub fn receive_request(message:BridgeMessage,mutex_pool:MutexGuard<ThreadPool>,replier:Replier){
+    print!("{}: receive request from {}: ",message.to_name,message.from_name);
+    println!("{}",message.message);
+    let handler=group_handler(&message.to_group);
+    mutex_pool.execute(move || {
+        let reply=handler.and_then(|handler| handler(&message.message)).unwrap_or_default();
+        if let Err(error)=replier.reply(&message,reply){
+            error!("send response failed,error={}",error);
+        }
+        ack_handled(&replier,&message);
+    });
+}
+
+fn ack_handled(replier:&Replier,message:&BridgeMessage){
+    if let Err(error)=replier.blocking_send_ack(message,AckLevel::Handled){
+        error!("send handled ack failed,error={}",error);
+    }
+}
+
+/// Handlers return the reply for requests, it's ignored for plain messages.
+fn group_handler(group:&str)->Option<fn(&str)->Option<String>>{
+    match group{
+        "A"=>Some(message_to_a),
+        "B"=>Some(message_to_b),
+        "C"=>Some(message_to_c),
+        _=>None,
+    }
+}
+
+pub fn receive_control(message:BridgeMessage){
+    match message.control{
+        Some(ControlEvent::DeliveryReport(results))=>{
+            println!("{}: delivery report for message to group {}",message.from_name,message.to_group);
+            for result in results{
+                match result.error{
+                    None=>println!("  {}: delivered",result.target),
+                    Some(error)=>println!("  {}: failed, {}",result.target,error),
+                }
+            }
+        }
+        None=>(),
+    }
+}
+
+pub fn receive_expired(message:BridgeMessage){
+    match message.error_msg{
+        Some(error)=>println!("{}: message to {} dropped by relayer, {}: {}",message.from_name,message.to_name,error,message.message),
+        None=>println!("{}: message to {} expired: {}",message.to_name,message.from_name,message.message),
     }
 }
 
@@ -66,16 +136,29 @@ fn message_from_c(){
     println!("MsgFromC: do things for group c before send");
 }
 
-fn message_to_a(msg:&str){
+fn message_to_a(msg:&str)->Option<String>{
     println!("MsgToA: do some task for group a");
+    Some(format!("group a done: {}",msg))
 }
 
-fn message_to_b(msg:&str){
+fn message_to_b(msg:&str)->Option<String>{
     println!("MsgToB: do some task for group b");
+    Some(format!("group b done: {}",msg))
 }
 
-fn message_to_c(msg:&str){
+fn message_to_c(msg:&str)->Option<String>{
     println!("MsgToC: do some task for group c");
+    Some(format!("group c done: {}",msg))
+}
+
+/// Operator notice to every node, sent as the relayer itself.
+pub fn broadcast_notice(relayer:&Relayer<BridgeMessage>,content:&str)->Result<usize,String>{
+    let notice=BridgeMessage{
+        from_name:Box::new("relayer".to_string()),
+        message:Box::new(content.to_string()),
+        ..Default::default()
+    };
+    relayer.broadcast(notice)
 }
 
 pub fn get_relayer() -> Result<Relayer<BridgeMessage>,String>{
@@ -89,9 +172,300 @@ pub fn get_relayer() -> Result<Relayer<BridgeMessage>,String>{
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use frame_common::get_runtime;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn test_request_response() {
+        let rt = get_runtime();
+        let (mut machine, relayer) = get_custom().unwrap();
+        register_node(&rt, "A1", "A", "127.0.0.1:18787", &mut machine, &relayer).unwrap();
+        register_node(&rt, "B1", "B", "127.0.0.1:19787", &mut machine, &relayer).unwrap();
+
+        let call = machine
+            .request(
+                &rt,
+                Box::new("A1".to_string()),
+                Box::new("B1".to_string()),
+                Box::new("ping".to_string()),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        let reply = rt.block_on(call.wait()).unwrap();
+        let responder = frame_common::id::NodeId::new(&reply.from_name, &reply.from_group).unwrap();
+        assert_eq!("B1@B", responder.to_string());
+        assert_eq!("group b done: ping", reply.message.as_str());
+        assert_eq!(0, machine.pending_requests());
+    }
+
+    #[test]
+    fn test_call_timeout_runs_from_send() {
+        let rt = get_runtime();
+        let pending = rpc::PendingTable::default();
+        let responder = frame_common::id::NodeId::new("B1", "B").unwrap();
+        let call = pending.start(&responder, Duration::from_millis(50)).unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+
+        // the time spent before waiting counts too
+        let waited = std::time::Instant::now();
+        assert!(rt.block_on(call.wait()).unwrap_err().contains("timed out"));
+        assert!(waited.elapsed() < Duration::from_millis(40));
+        assert_eq!(0, pending.len());
+    }
+
+    #[test]
+    fn test_unregister_node() {
+        let rt = get_runtime();
+        let (mut machine, relayer) = get_custom().unwrap();
+        register_node(&rt, "A1", "A", "127.0.0.1:18789", &mut machine, &relayer).unwrap();
+        register_node(&rt, "B1", "B", "127.0.0.1:19789", &mut machine, &relayer).unwrap();
+
+        unregister_node(&rt, "B1", &mut machine, &relayer).unwrap();
+        assert!(unregister_node(&rt, "B1", &mut machine, &relayer).is_err());
+        assert!(relayer.unregister_node("B1", "B").is_err());
+        assert!(machine
+            .send_message(
+                &rt,
+                Box::new("A1".to_string()),
+                Box::new("B1".to_string()),
+                Box::new("hello".to_string()),
+            )
+            .is_err());
+
+        // the port is free, so the node can come back on it
+        register_node(&rt, "B1", "B", "127.0.0.1:19789", &mut machine, &relayer).unwrap();
+        let call = machine
+            .request(
+                &rt,
+                Box::new("A1".to_string()),
+                Box::new("B1".to_string()),
+                Box::new("back".to_string()),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        assert_eq!("group b done: back", rt.block_on(call.wait()).unwrap().message.as_str());
+    }
+
+    #[test]
+    fn test_unsent_fail_back_to_node() {
+        let rt = get_runtime();
+        let (_, relayer) = get_custom().unwrap();
+        // the node trusts another relayer, so nothing it sends leaves it
+        let mut stranded = Machine::new();
+        stranded.pin_relayer_key(frame_common::get_rsa().unwrap().1);
+        register_node(&rt, "C1", "C", "127.0.0.1:18782", &mut stranded, &relayer).unwrap();
+
+        let mut tracking = stranded
+            .send_tracked_message(
+                &rt,
+                Box::new("C1".to_string()),
+                Box::new("C1".to_string()),
+                Box::new("never sent".to_string()),
+                AckLevel::Delivered,
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        unregister_node(&rt, "C1", &mut stranded, &relayer).unwrap();
+
+        // the failure reaches the node though the relayer never signed it
+        let error = rt.block_on(tracking.wait_for(AckLevel::Delivered)).unwrap_err();
+        assert_eq!("node C1 unregistered, message not sent", error);
+        assert!(tracking.is_failed());
+    }
+
+    #[test]
+    fn test_migrate_node() {
+        let rt = get_runtime();
+        let (mut machine, relayer) = get_custom().unwrap();
+        let mut other = Machine::new();
+        other.pin_relayer_key(relayer.get_public_key().unwrap());
+        register_node(&rt, "A1", "A", "127.0.0.1:18786", &mut machine, &relayer).unwrap();
+        register_node(&rt, "B1", "B", "127.0.0.1:19786", &mut machine, &relayer).unwrap();
+
+        migrate_node(&rt, "A1", "127.0.0.1:18785", &mut machine, &mut other, &relayer).unwrap();
+        assert!(migrate_node(&rt, "A1", "127.0.0.1:18785", &mut machine, &mut other, &relayer).is_err());
+        assert!(relayer.begin_migration("C1", "C").is_err());
+
+        // back again on its first port, which the move released
+        migrate_node(&rt, "A1", "127.0.0.1:18786", &mut other, &mut machine, &relayer).unwrap();
+        let call = machine
+            .request(
+                &rt,
+                Box::new("A1".to_string()),
+                Box::new("B1".to_string()),
+                Box::new("moved".to_string()),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        assert_eq!("group b done: moved", rt.block_on(call.wait()).unwrap().message.as_str());
+    }
+
+    #[test]
+    fn test_tracked_message_acks() {
+        let rt = get_runtime();
+        let (mut machine, relayer) = get_custom().unwrap();
+        register_node(&rt, "A1", "A", "127.0.0.1:18788", &mut machine, &relayer).unwrap();
+        register_node(&rt, "B1", "B", "127.0.0.1:19788", &mut machine, &relayer).unwrap();
+
+        let mut tracking = machine
+            .send_tracked_message(
+                &rt,
+                Box::new("A1".to_string()),
+                Box::new("B1".to_string()),
+                Box::new("track me".to_string()),
+                AckLevel::Handled,
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        rt.block_on(tracking.wait_for(AckLevel::Delivered)).unwrap();
+        rt.block_on(tracking.wait_for(AckLevel::Handled)).unwrap();
+        assert_eq!(ack::AckStatus::Reached(AckLevel::Handled), tracking.status());
+
+        // a group without a handler still acks once the message was taken
+        register_node(&rt, "D1", "D", "127.0.0.1:19784", &mut machine, &relayer).unwrap();
+        let mut tracking = machine
+            .send_tracked_message(
+                &rt,
+                Box::new("A1".to_string()),
+                Box::new("D1".to_string()),
+                Box::new("no handler".to_string()),
+                AckLevel::Handled,
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        rt.block_on(tracking.wait_for(AckLevel::Handled)).unwrap();
+
+        // the relayer bouncing a tracked message fails the tracking
+        let acks = ack::AckTable::default();
+        let mut tracking = acks
+            .start(&"B2@B".parse().unwrap(), AckLevel::Delivered, Duration::from_secs(5))
+            .unwrap();
+        let bounce = BridgeMessage {
+            error_msg: Some(Box::new("can't find target".to_string())),
+            ack: Some(frame_common::data::Ack::Wanted {
+                id: tracking.id(),
+                level: AckLevel::Delivered,
+            }),
+            ..Default::default()
+        };
+        assert!(acks.resolve(&bounce));
+        let error = rt.block_on(tracking.wait_for(AckLevel::Delivered)).unwrap_err();
+        assert_eq!("can't find target", error);
+        assert!(acks.is_empty());
+    }
+
+    #[test]
+    fn test_reliable_message_acked() {
+        let rt = get_runtime();
+        let (mut machine, relayer) = get_custom().unwrap();
+        register_node(&rt, "A1", "A", "127.0.0.1:18783", &mut machine, &relayer).unwrap();
+        register_node(&rt, "B1", "B", "127.0.0.1:19783", &mut machine, &relayer).unwrap();
+
+        let sending = machine
+            .send_reliable_message(
+                &rt,
+                Box::new("A1".to_string()),
+                Box::new("B1".to_string()),
+                Box::new("at least once".to_string()),
+            )
+            .unwrap();
+        assert_eq!(Ok(1), rt.block_on(sending).unwrap());
+    }
+
+    #[test]
+    fn test_retry_until_acked() {
+        let rt = get_runtime();
+        let policy = reliable::RetryPolicy {
+            max_attempts: 4,
+            ack_timeout: Duration::from_millis(20),
+            backoff: 2,
+            max_ack_timeout: Duration::from_millis(50),
+        };
+        assert_eq!(Duration::from_millis(40), policy.ack_timeout(2));
+        assert_eq!(Duration::from_millis(50), policy.ack_timeout(3));
+
+        let acks = ack::AckTable::default();
+        let mut tracking = acks
+            .start(&"B1@B".parse().unwrap(), AckLevel::Delivered, Duration::from_secs(5))
+            .unwrap();
+        let ack = BridgeMessage {
+            from_name: Box::new("B1".to_string()),
+            from_group: Box::new("B".to_string()),
+            ack: Some(frame_common::data::Ack::Reached {
+                id: tracking.id(),
+                level: AckLevel::Delivered,
+            }),
+            ..Default::default()
+        };
+        // the first two sends get lost
+        let attempts = rt.block_on(reliable::deliver_at_least_once(
+            &policy,
+            &mut tracking,
+            |attempt| {
+                if attempt == 3 {
+                    acks.resolve(&ack);
+                }
+                async { Ok(()) }
+            },
+        ));
+        assert_eq!(Ok(3), attempts);
+
+        let mut tracking = acks
+            .start(&"B1@B".parse().unwrap(), AckLevel::Delivered, Duration::from_secs(5))
+            .unwrap();
+        let sends = std::cell::Cell::new(0);
+        let res = rt.block_on(reliable::deliver_at_least_once(
+            &policy,
+            &mut tracking,
+            |_| {
+                sends.set(sends.get() + 1);
+                async { Err("channel closed".to_string()) }
+            },
+        ));
+        assert!(res.unwrap_err().starts_with("gave up after 4 attempts"));
+        assert_eq!(4, sends.get());
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let dedup = reliable::DedupWindow::new(Duration::from_millis(50), 2);
+        assert!(dedup.first_seen("A1A", 1));
+        assert!(!dedup.first_seen("A1A", 1));
+        assert!(dedup.first_seen("A2A", 1));
+        // over capacity, the oldest id is forgotten
+        assert!(dedup.first_seen("A1A", 2));
+        assert!(dedup.first_seen("A1A", 1));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(dedup.first_seen("A1A", 2));
+    }
+
+    #[test]
+    fn test_pending_timeout_and_cancel() {
+        let rt = get_runtime();
+        let pending = rpc::PendingTable::default();
+        let call = pending.start(&"B1@B".parse().unwrap(), Duration::from_millis(50)).unwrap();
+        assert!(rt.block_on(call.wait()).unwrap_err().contains("timed out"));
+        assert!(pending.is_empty());
+
+        let call = pending.start(&"B1@B".parse().unwrap(), Duration::from_secs(5)).unwrap();
+        let id = call.id();
+        let response = |name: &str| BridgeMessage {
+            from_name: Box::new(name.to_string()),
+            from_group: Box::new("B".to_string()),
+            correlation: Some(frame_common::data::Correlation::Response(id)),
+            ..Default::default()
+        };
+        // only the node the request went to may answer it
+        assert!(!pending.resolve(response("B2")));
+        assert_eq!(1, pending.len());
+        call.cancel();
+        assert!(pending.is_empty());
+        assert!(!pending.resolve(response("B1")));
+    }
 }
@@ -3,14 +3,35 @@ use std::{
     error::Error,
     sync::{Arc, Mutex},
     thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use frame_client::{listen_clients_register, LaunchInfo};
-use frame_common::{data::Router, get_runtime, sign};
+use frame_common::{
+    data::{
+        Ack, AckLevel, AnycastStrategy, Correlation, Delivery, Expiry, Message, Priority, Router,
+    },
+    get_runtime,
+    id::NodeId,
+    now_millis, relayer_verify, sign,
+};
+use log::{debug, error};
+use rsa::RsaPublicKey;
 use threadpool::Builder;
-use tokio::sync::mpsc::{self, Receiver};
+use tokio::{
+    sync::{
+        mpsc::{self, Receiver},
+        oneshot,
+    },
+    task::JoinHandle,
+};
 
-use crate::node::Node;
+use crate::{
+    ack::{AckTable, Tracking},
+    node::Node,
+    reliable::{deliver_at_least_once, DedupWindow, RetryPolicy},
+    rpc::{PendingCall, PendingTable, Replier},
+};
 
 use super::*;
 
@@ -19,6 +40,11 @@ pub struct Machine {
     client_register: Sender<LaunchInfo<BridgeMessage>>,
     pool: Arc<Mutex<ThreadPool>>,
     nodes: HashMap<String, Node>,
+    relayer_key: Option<RsaPublicKey>,
+    pending: PendingTable,
+    acks: AckTable,
+    retry_policy: RetryPolicy,
+    dedup: DedupWindow,
 }
 
 impl Machine {
@@ -35,9 +61,30 @@ impl Machine {
             client_register: Machine::get_client_regiser(),
             pool,
             nodes: HashMap::new(),
+            relayer_key: None,
+            pending: PendingTable::default(),
+            acks: AckTable::default(),
+            retry_policy: RetryPolicy::default(),
+            dedup: DedupWindow::default(),
         }
     }
 
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Which message ids the machine remembers to drop resent copies. Must be
+    /// called before registering nodes.
+    pub fn set_dedup_window(&mut self, dedup: DedupWindow) {
+        self.dedup = dedup;
+    }
+
+    /// Pin the relayer's public key. Messages claiming to come from the
+    /// relayer are dropped unless they verify against it.
+    pub fn pin_relayer_key(&mut self, relayer_key: RsaPublicKey) {
+        self.relayer_key = Some(relayer_key);
+    }
+
     pub fn send_message(
         &self,
         rt: &Runtime,
@@ -45,47 +92,413 @@ impl Machine {
         to: Box<String>,
         content: Box<String>,
     ) -> Result<(), String> {
-        let name = from.clone().to_string();
-        let to_name = to.clone().to_string();
-        let sender = self
+        let to_group = self
             .nodes
-            .get(&name)
-            .and_then(|node| node.get_input())
-            .ok_or("sender do not exist or init!")?;
-        let from_group = self
+            .get(to.as_str())
+            .map(|node| node.get_group())
+            .ok_or("receiver do not exist or init!")?;
+
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_name: to,
+            to_group: Box::new(to_group.to_string()),
+            message: content,
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Send `content` to `to` in the `priority` lane. On the sender's
+    /// connection and in the relayer it overtakes messages of lower lanes.
+    pub fn send_priority_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+        priority: Priority,
+    ) -> Result<(), String> {
+        let to_group = self
             .nodes
-            .get(&name)
+            .get(to.as_str())
             .map(|node| node.get_group())
-            .ok_or("sender group do not exist or init!")?;
-        let priv_key = self
+            .ok_or("receiver do not exist or init!")?;
+
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_name: to,
+            to_group: Box::new(to_group.to_string()),
+            message: content,
+            priority: Some(priority),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Send `content` to `to` and track it. The target machine sends back a
+    /// status event when the message reaches it and, with `AckLevel::Handled`,
+    /// another one once the node's handler ran on it.
+    pub fn send_tracked_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+        level: AckLevel,
+        timeout: Duration,
+    ) -> Result<Tracking, String> {
+        let to_group = self
             .nodes
-            .get(&name)
-            .map(|node| node.get_private_key())
-            .ok_or("private key do not exist!")?;
+            .get(to.as_str())
+            .map(|node| node.get_group())
+            .ok_or("receiver do not exist or init!")?;
 
+        let tracking = self
+            .acks
+            .start(&NodeId::new(&to, to_group)?, level, timeout)?;
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_name: to,
+            to_group: Box::new(to_group.to_string()),
+            message: content,
+            ack: Some(Ack::Wanted {
+                id: tracking.id(),
+                level,
+            }),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)?;
+        Ok(tracking)
+    }
+
+    /// Send `content` to `to`, for the relayer to deliver at `deliver_at`.
+    pub fn send_scheduled_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+        deliver_at: SystemTime,
+    ) -> Result<(), String> {
         let to_group = self
             .nodes
-            .get(&to_name)
+            .get(to.as_str())
             .map(|node| node.get_group())
             .ok_or("receiver do not exist or init!")?;
+        let deliver_at = deliver_at
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| err.to_string())?;
 
-        let mut bridge_message = BridgeMessage {
-            from_name: Box::new(name),
-            from_group: Box::new(from_group.to_string()),
-            to_name: Box::new(to_name),
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_name: to,
             to_group: Box::new(to_group.to_string()),
             message: content,
-            error_msg: None,
-            sig: None,
+            deliver_at: Some(deliver_at.as_millis() as u64),
+            ..Default::default()
         };
+        self.sign_and_send(rt, bridge_message)
+    }
 
-        let sig = sign(&bridge_message.get_source_id(), priv_key)?;
-        bridge_message.sig = Some(sig);
+    /// Send `content` to `to`, for the relayer to deliver once `delay` passed.
+    pub fn send_delayed_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+        delay: Duration,
+    ) -> Result<(), String> {
+        self.send_scheduled_message(rt, from, to, content, SystemTime::now() + delay)
+    }
+
+    /// Send `content` to `to`, to be dropped by whichever hop holds it once
+    /// `ttl` passed. With `notify` the sender gets a notice when that happens.
+    pub fn send_expiring_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+        ttl: Duration,
+        notify: bool,
+    ) -> Result<(), String> {
+        let to_group = self
+            .nodes
+            .get(to.as_str())
+            .map(|node| node.get_group())
+            .ok_or("receiver do not exist or init!")?;
 
-        send_msg(sender, &rt, bridge_message);
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_name: to,
+            to_group: Box::new(to_group.to_string()),
+            message: content,
+            expiry: Some(Expiry::Deadline {
+                deadline_ms: now_millis() + ttl.as_millis() as u64,
+                notify,
+            }),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Send `content` to `to` at least once. The message is resent under the
+    /// same id until `to`'s machine acks it or the retry policy gives up, and
+    /// that machine drops copies it has seen already. The returned task
+    /// resolves to the number of sends it took.
+    pub fn send_reliable_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+    ) -> Result<JoinHandle<Result<u32, String>>, String> {
+        let to_group = self
+            .nodes
+            .get(to.as_str())
+            .map(|node| node.get_group())
+            .ok_or("receiver do not exist or init!")?;
+
+        let policy = self.retry_policy;
+        let mut tracking = self.acks.start(
+            &NodeId::new(&to, to_group)?,
+            AckLevel::Delivered,
+            policy.max_ack_timeout,
+        )?;
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_name: to,
+            to_group: Box::new(to_group.to_string()),
+            message: content,
+            ack: Some(Ack::Wanted {
+                id: tracking.id(),
+                level: AckLevel::Delivered,
+            }),
+            msg_id: Some(rand::random()),
+            ..Default::default()
+        };
+        let (sender, bridge_message) = self.prepare(bridge_message)?;
+        let sender = sender.clone();
+        Ok(rt.spawn(async move {
+            deliver_at_least_once(&policy, &mut tracking, move |_| {
+                let sender = sender.clone();
+                let bridge_message = bridge_message.clone();
+                async move {
+                    sender
+                        .send(bridge_message)
+                        .await
+                        .map_err(|err| err.to_string())
+                }
+            })
+            .await
+        }))
+    }
+
+    /// Send `content` to `to` as a request. The returned call resolves to the
+    /// response `to`'s handler sends back, or fails after `timeout`, when the
+    /// relayer bounces the request, or when the call is cancelled.
+    pub fn request(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to: Box<String>,
+        content: Box<String>,
+        timeout: Duration,
+    ) -> Result<PendingCall, String> {
+        let to_group = self
+            .nodes
+            .get(to.as_str())
+            .map(|node| node.get_group())
+            .ok_or("receiver do not exist or init!")?;
+
+        let call = self
+            .pending
+            .start(&NodeId::new(&to, to_group)?, timeout)?;
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_name: to,
+            to_group: Box::new(to_group.to_string()),
+            message: content,
+            correlation: Some(Correlation::Request(call.id())),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)?;
+        Ok(call)
+    }
+
+    /// Number of requests still waiting for a response.
+    pub fn pending_requests(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Send `content` to every node registered in `to_group`, wherever it runs.
+    /// With `report` the relayer answers with a delivery report per member.
+    pub fn send_group_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to_group: Box<String>,
+        content: Box<String>,
+        exclude_sender: bool,
+        report: bool,
+    ) -> Result<(), String> {
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_group,
+            message: content,
+            delivery: Some(Delivery::Multicast {
+                exclude_sender,
+                report,
+            }),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Send `content` to every registered node of every group. The relayer
+    /// bounces it unless `from` was allowed to broadcast.
+    pub fn send_broadcast_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        content: Box<String>,
+        exclude_sender: bool,
+        report: bool,
+    ) -> Result<(), String> {
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            message: content,
+            delivery: Some(Delivery::Broadcast {
+                exclude_sender,
+                report,
+            }),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Send `content` to one member of `to_group`, picked by the relayer with
+    /// `strategy` among the members that are connected and healthy.
+    pub fn send_anycast_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to_group: Box<String>,
+        content: Box<String>,
+        strategy: AnycastStrategy,
+    ) -> Result<(), String> {
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_group,
+            message: content,
+            delivery: Some(Delivery::Anycast(strategy)),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Send `content` to the member of `to_group` that owns `key`, so every
+    /// message about the same entity reaches the same member.
+    pub fn send_partitioned_message(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        to_group: Box<String>,
+        key: &str,
+        content: Box<String>,
+    ) -> Result<(), String> {
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            to_group,
+            message: content,
+            delivery: Some(Delivery::Partitioned { key: key.to_string() }),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Send `content` to every node subscribed to a pattern matching `topic`.
+    pub fn publish(
+        &self,
+        rt: &Runtime,
+        from: Box<String>,
+        topic: &str,
+        content: Box<String>,
+    ) -> Result<(), String> {
+        let bridge_message = BridgeMessage {
+            from_name: from,
+            message: content,
+            delivery: Some(Delivery::Publish {
+                topic: topic.to_string(),
+            }),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Receive messages published to topics matching `pattern` on node `name`,
+    /// until it unsubscribes or disconnects. `*` matches one dot separated
+    /// segment and `#` any number of them.
+    pub fn subscribe(&self, rt: &Runtime, name: Box<String>, pattern: &str) -> Result<(), String> {
+        let bridge_message = BridgeMessage {
+            from_name: name,
+            delivery: Some(Delivery::Subscribe {
+                pattern: pattern.to_string(),
+            }),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    pub fn unsubscribe(&self, rt: &Runtime, name: Box<String>, pattern: &str) -> Result<(), String> {
+        let bridge_message = BridgeMessage {
+            from_name: name,
+            delivery: Some(Delivery::Unsubscribe {
+                pattern: pattern.to_string(),
+            }),
+            ..Default::default()
+        };
+        self.sign_and_send(rt, bridge_message)
+    }
+
+    /// Fill in the sender's group and signature, then hand the message to the
+    /// sender node's connection.
+    fn sign_and_send(&self, rt: &Runtime, bridge_message: BridgeMessage) -> Result<(), String> {
+        let (sender, bridge_message) = self.prepare(bridge_message)?;
+        send_msg(sender, rt, bridge_message);
         Ok(())
     }
 
+    /// Fill in the sender's group and signature, and find the sender node's
+    /// connection.
+    fn prepare(
+        &self,
+        mut bridge_message: BridgeMessage,
+    ) -> Result<(&Sender<BridgeMessage>, BridgeMessage), String> {
+        let name = bridge_message.from_name.to_string();
+        let sender = self
+            .nodes
+            .get(&name)
+            .and_then(|node| node.get_input())
+            .ok_or("sender do not exist or init!")?;
+        let source_id = self
+            .nodes
+            .get(&name)
+            .map(|node| node.get_source_id())
+            .ok_or("sender group do not exist or init!")?;
+        let priv_key = self
+            .nodes
+            .get(&name)
+            .map(|node| node.get_private_key())
+            .ok_or("private key do not exist!")?;
+
+        bridge_message.from_group = Box::new(source_id.group().to_string());
+        let sig = sign(&source_id.to_string(), priv_key)?;
+        bridge_message.sig = Some(sig);
+        Ok((sender, bridge_message))
+    }
+
     pub async fn register_node(
         &mut self,
         relayer: &Relayer<BridgeMessage>,
@@ -93,21 +506,41 @@ impl Machine {
         group: &str,
         addr: &str,
     ) -> Result<(), String> {
-        let mut node = Node::new(addr, name, group)?;
+        let node = Node::new(addr, name, group)?;
 
         if self.nodes.contains_key(node.get_name()) {
             return Err("node already exitst".to_string());
         }
+        self.start_node(relayer, node).await
+    }
 
+    /// Listen for the relayer as `node`, run its handler and register it.
+    async fn start_node(
+        &mut self,
+        relayer: &Relayer<BridgeMessage>,
+        mut node: Node,
+    ) -> Result<(), String> {
         let (input_tx, input_rx): (Sender<BridgeMessage>, Receiver<BridgeMessage>) =
             mpsc::channel(32);
         let (output_tx, output_rx): (Sender<BridgeMessage>, Receiver<BridgeMessage>) =
             mpsc::channel(32);
 
-        let machine_register_info = node.build_machine_register_info(input_rx, output_tx);
+        let (notices, local_rx): (Sender<BridgeMessage>, Receiver<BridgeMessage>) =
+            mpsc::channel(32);
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let machine_register_info = node.build_machine_register_info(input_rx, output_tx, stop_rx, self.relayer_key.clone());
         let task_register_info = CustomTaskInfo {
             receiver: output_rx,
+            local: local_rx,
             pool: self.pool.clone(),
+            relayer_key: self.relayer_key.clone(),
+            pending: self.pending.clone(),
+            acks: self.acks.clone(),
+            dedup: self.dedup.clone(),
+            replier: Replier {
+                input: input_tx.clone(),
+                private_key: node.get_private_key().clone(),
+            },
         };
 
         self.client_register
@@ -129,11 +562,93 @@ impl Machine {
             .await?;
 
         node.input = Some(input_tx);
+        node.stop = Some(stop_tx);
+        node.notices = Some(notices);
         self.nodes.insert(node.get_name().to_string(), node);
 
         Ok(())
     }
 
+    /// Take the node `name` off the relayer and this machine. Its connection
+    /// is closed and its port released, and messages it queued but didn't
+    /// send yet come back to it with an error.
+    pub async fn unregister_node(
+        &mut self,
+        relayer: &Relayer<BridgeMessage>,
+        name: &str,
+    ) -> Result<(), String> {
+        let node = self.nodes.get(name).ok_or("node do not exist!")?;
+        relayer.unregister_node(node.get_name(), node.get_group())?;
+
+        let mut node = self.nodes.remove(name).ok_or("node do not exist!")?;
+        let unsent = Machine::stop_node(&mut node).await;
+        let reason = format!("node {} unregistered, message not sent", name);
+        if let Some(notices) = node.notices.take() {
+            for mut message in unsent {
+                message.set_error_msg(Box::new(reason.clone()));
+                if let Err(error) = notices.send(message).await {
+                    error!("send unsent message back failed,error={}", error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the node `name` to the machine `to`, listening on `addr` there.
+    /// It keeps its keys, the relayer parks its messages meanwhile, and what
+    /// it queued here and didn't send goes out from `to`. If it can't start
+    /// on `to` it comes back here. Requests it still waits on time out here.
+    pub async fn migrate_node(
+        &mut self,
+        relayer: &Relayer<BridgeMessage>,
+        name: &str,
+        to: &mut Machine,
+        addr: &str,
+    ) -> Result<(), String> {
+        if to.nodes.contains_key(name) {
+            return Err("node already exitst".to_string());
+        }
+        let node = self.nodes.get(name).ok_or("node do not exist!")?;
+        relayer.begin_migration(node.get_name(), node.get_group())?;
+
+        let mut node = self.nodes.remove(name).ok_or("node do not exist!")?;
+        let unsent = Machine::stop_node(&mut node).await;
+        let moved = match to.start_node(relayer, node.moved_to(addr)).await {
+            Ok(()) => to,
+            Err(error) => {
+                error!("start {} on the new machine failed,error={}", name, error);
+                let addr = node.get_addr().to_string();
+                self.start_node(relayer, node.moved_to(&addr)).await?;
+                self
+            }
+        };
+        let input = moved
+            .nodes
+            .get(name)
+            .and_then(|node| node.get_input())
+            .ok_or("node do not exist!")?;
+        for message in unsent {
+            input.send(message).await.map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Close the node's connection and release its port. Returns what it
+    /// queued and didn't send, in the order it would have gone out.
+    async fn stop_node(node: &mut Node) -> Vec<BridgeMessage> {
+        node.input = None;
+        let stop = match node.stop.take() {
+            Some(stop) => stop,
+            None => return Vec::new(),
+        };
+        let (unsent_tx, unsent_rx) = oneshot::channel();
+        if stop.send(unsent_tx).is_err() {
+            return Vec::new();
+        }
+        // the listener is gone before the unsent messages come back
+        unsent_rx.await.unwrap_or_default()
+    }
+
     fn register_custom_tasks() -> Sender<CustomTaskInfo> {
         let rt = get_runtime();
         let (tx, rx) = mpsc::channel(8);
@@ -169,17 +684,111 @@ impl Machine {
         Ok(())
     }
 
+    /// A message claiming to come from the relayer must carry its signature,
+    /// over a nonce not seen within the dedup window.
+    fn check_relayer_sig(
+        message: &BridgeMessage,
+        relayer_key: Option<&RsaPublicKey>,
+        dedup: &DedupWindow,
+    ) -> Result<(), String> {
+        if !message.is_from_relayer() {
+            return Ok(());
+        }
+        let relayer_key = relayer_key.ok_or("relayer key not pinned")?;
+        relayer_verify(message, relayer_key)?;
+        let nonce = message.relayer_nonce.ok_or("miss relayer nonce")?;
+        if !dedup.first_seen("relayer", nonce) {
+            return Err("relayer message replayed".to_string());
+        }
+        Ok(())
+    }
+
     async fn launch_custom_task(mut task: CustomTaskInfo) {
-        while let Some(message) = task.receiver.recv().await {
+        loop {
+            // failures the machine hands back itself carry no relayer signature
+            let (message, local) = tokio::select! {
+                Some(message) = task.local.recv() => (message, true),
+                Some(message) = task.receiver.recv() => (message, false),
+                else => break,
+            };
+            if local {
+                debug!("message from {} failed on this machine", message.get_source_id());
+            } else if let Err(error) = Machine::check_relayer_sig(&message, task.relayer_key.as_ref(), &task.dedup) {
+                error!("drop message claiming to be from relayer,error={}", error);
+                continue;
+            }
+            if message.control.is_some() {
+                receive_control(message);
+                continue;
+            }
+            // status events for, or bounces of, messages this node sent
+            let tracked = task.acks.resolve(&message);
+            if let Some(Ack::Reached { .. }) = message.ack {
+                if !tracked {
+                    debug!("drop ack nobody waits for");
+                }
+                continue;
+            }
+            if let Some(Expiry::Expired) = message.expiry {
+                task.pending.resolve(message.clone());
+                receive_expired(message);
+                continue;
+            }
+            if message.is_expired() {
+                debug!("drop expired message from {}", message.get_source_id());
+                if let Err(error) = task.replier.send_expired(&message).await {
+                    error!("send expiry notice failed,error={}", error);
+                }
+                continue;
+            }
+            let bounced = message.error_msg.is_some();
+            if !bounced {
+                if let Err(error) = task.replier.send_ack(&message, AckLevel::Delivered).await {
+                    error!("send delivered ack failed,error={}", error);
+                }
+                // a resent copy still gets its ack, the first one may be lost
+                if let Some(msg_id) = message.msg_id {
+                    if !task.dedup.first_seen(&message.get_source_id().to_string(), msg_id) {
+                        debug!(
+                            "drop duplicate message {} from {}",
+                            msg_id,
+                            message.get_source_id()
+                        );
+                        continue;
+                    }
+                }
+            }
+            match message.correlation {
+                Some(Correlation::Request(_)) if !bounced => {
+                    let mutex_pool = task.pool.lock().unwrap();
+                    receive_request(message, mutex_pool, task.replier.clone());
+                    continue;
+                }
+                Some(_) => {
+                    if !task.pending.resolve(message) {
+                        debug!("drop response nobody waits for");
+                    }
+                    continue;
+                }
+                None if tracked => continue,
+                None => (),
+            }
             let mutex_pool = task.pool.lock().unwrap();
-            receive_msg(message, mutex_pool);
+            receive_msg(message, mutex_pool, task.replier.clone());
         }
     }
 }
 
 pub struct CustomTaskInfo {
     pub receiver: Receiver<BridgeMessage>,
+    /// messages the machine fails itself, like ones left unsent at unregister
+    pub local: Receiver<BridgeMessage>,
     pub pool: Arc<Mutex<ThreadPool>>,
+    pub relayer_key: Option<RsaPublicKey>,
+    pub pending: PendingTable,
+    pub acks: AckTable,
+    pub dedup: DedupWindow,
+    pub replier: Replier,
 }
 
 pub fn get_client_regiser() -> Sender<LaunchInfo<BridgeMessage>> {
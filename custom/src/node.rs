@@ -1,32 +1,60 @@
-use frame_client::LaunchInfo;
-use frame_common::{get_rsa, data::BridgeMessage};
+use frame_client::{LaunchInfo, Unsent};
+use frame_common::{get_rsa, data::BridgeMessage, id::NodeId};
 use frame_relayer::RegisterInfo;
 use rsa::{RsaPrivateKey, RsaPublicKey};
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{mpsc::{Receiver, Sender}, oneshot};
 
 pub struct Node{
     pub input:Option<Sender<BridgeMessage>>,
+    /// takes the node off the machine, handing back what it didn't send
+    pub stop:Option<oneshot::Sender<Unsent<BridgeMessage>>>,
+    /// where the machine hands the node messages it failed itself
+    pub notices:Option<Sender<BridgeMessage>>,
     addr: Box<String>,
     name:Box<String>,
     group:Box<String>,
+    id:NodeId,
     private_key:RsaPrivateKey, 
     public_key:RsaPublicKey,
 }
 
 impl Node{
     pub fn new(addr:&str,name:&str,group:&str)->Result<Node,String>{
+        let id = NodeId::new(name, group)?;
         let (pri_key, pub_key) = get_rsa()?;
         let node=Node{
             addr: Box::new(addr.to_string()),
             name: Box::new(name.to_string()),
             group: Box::new(group.to_string()),
+            id,
             private_key: pri_key,
             public_key: pub_key,
             input:None,
+            stop:None,
+            notices:None,
         };
         Ok(node)
     }
 
+    /// The same node, keys and all, listening on `addr`.
+    pub fn moved_to(&self,addr:&str)->Node{
+        Node{
+            addr: Box::new(addr.to_string()),
+            name: self.name.clone(),
+            group: self.group.clone(),
+            id: self.id.clone(),
+            private_key: self.private_key.clone(),
+            public_key: self.public_key.clone(),
+            input:None,
+            stop:None,
+            notices:None,
+        }
+    }
+
+    pub fn get_addr(&self) -> &String {
+        self.addr.as_ref()
+    }
+
     pub fn get_input(&self)->Option<&Sender<BridgeMessage>>{
         self.input.as_ref()
     }
@@ -39,9 +67,8 @@ impl Node{
         self.group.as_ref()
     }
 
-    pub fn get_source_id(&self) -> Box<String> {
-        let ans: String = self.name.to_string() + &(self.group.to_string());
-        Box::new(ans)
+    pub fn get_source_id(&self) -> &NodeId {
+        &self.id
     }
 
     pub fn get_private_key(&self)->&RsaPrivateKey{
@@ -72,12 +99,16 @@ impl Node{
         &self,
         biz_input: Receiver<BridgeMessage>,
         biz_output: Sender<BridgeMessage>,
+        stop: oneshot::Receiver<Unsent<BridgeMessage>>,
+        relayer_key: Option<RsaPublicKey>,
     ) -> LaunchInfo<BridgeMessage> {
         LaunchInfo {
             addr: self.addr.clone(),
             name: self.name.clone(),
             input: Box::new(biz_input),
             output: Box::new(biz_output),
+            stop: Box::new(stop),
+            relayer_key,
         }
     }
 }
\ No newline at end of file
@@ -1,36 +1,204 @@
-use std::{sync::{Arc, Mutex}, collections::HashMap, thread};
+use std::{sync::Arc, thread};
 
-use frame_common::{data::{Router, Message}, get_runtime};
-use frame_relayer::{RouteTable, PubKeyTable, RegisterInfo, listen_relayer_register};
-use rsa::RsaPublicKey;
+use frame_common::{data::{Router, Message}, get_rsa, get_runtime, id::NodeId};
+use frame_relayer::{
+    audit::{AuditLog, AuditTable},
+    cluster::Cluster,
+    deadletter::{DeadLetter, DeadLetterInfo, DeadLetters},
+    federation::Federation,
+    parking::{Parking, ParkingLimits},
+    schedule::Scheduler,
+    verify::Verifier,
+    begin_migration, connect_peer, listen_peers, listen_relayer_register, register_key,
+    redeliver_dead_letter, relayer_broadcast, unregister_node, RegisterInfo, RelayerLimits,
+    RelayerState,
+};
+use log::error;
+use rsa::{RsaPrivateKey, RsaPublicKey};
 use serde::{Serialize, de::DeserializeOwned};
-use tokio::sync::mpsc::{Sender, Receiver, self};
+use tokio::{net::TcpListener, sync::mpsc::{Sender, Receiver, self}};
 
 pub struct Relayer<Contract>
 where
-    Contract: Send + 'static + Serialize + DeserializeOwned + Router<String> + Message + Clone,
+    Contract: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
 {
-    route_table: Option<RouteTable<Contract>>,
-    pub_keys: Option<PubKeyTable>,
+    state: Option<RelayerState<Contract>>,
     register: Option<Sender<RegisterInfo>>,
+    audit: Option<AuditTable>,
+    identity_key: Option<RsaPrivateKey>,
+    public_key: Option<RsaPublicKey>,
+    limits: RelayerLimits,
+    legacy_id_signatures: bool,
+    verifier: Option<Verifier>,
+    parking: Option<ParkingLimits>,
+    durable_dir: Option<String>,
+    dead_letter_capacity: Option<usize>,
+    federation: Option<Arc<Federation<Contract>>>,
+    cluster: Option<Arc<Cluster>>,
 }
 
 impl<Contract> Relayer<Contract>
 where
-    Contract: Send + 'static + Serialize + DeserializeOwned + Router<String> + Message + Clone,
+    Contract: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
 {
     pub fn new() -> Relayer<Contract> {
         Relayer {
-            route_table: None,
-            pub_keys: None,
+            state: None,
             register: None,
+            audit: None,
+            identity_key: None,
+            public_key: None,
+            limits: RelayerLimits::default(),
+            legacy_id_signatures: false,
+            verifier: None,
+            parking: None,
+            durable_dir: None,
+            dead_letter_capacity: None,
+            federation: None,
+            cluster: None,
         }
     }
+
+    /// Number of signature workers and size of the verified cache. Must be
+    /// called before `launch`.
+    pub fn set_verifier(&mut self, verifier: Verifier) {
+        self.verifier = Some(verifier);
+    }
+
+    /// Frame, payload and bad input limits for every connection. Must be
+    /// called before `launch`.
+    pub fn set_limits(&mut self, limits: RelayerLimits) {
+        self.limits = limits;
+    }
+
+    /// Also take signatures over the run-together id that nodes from before
+    /// `name@group` ids sign. For migrating such nodes only: it lets `A1`+`B`
+    /// and `A`+`1B` sign for each other again, and goes away once no node
+    /// needs it. Must be called before `launch`.
+    pub fn set_legacy_id_signatures(&mut self, accept: bool) {
+        self.legacy_id_signatures = accept;
+    }
+
+    /// Queue messages for registered nodes that are offline and deliver them
+    /// when the node connects again, instead of bouncing them. Must be called
+    /// before `launch`.
+    pub fn set_store_and_forward(&mut self, limits: ParkingLimits) {
+        self.parking = Some(limits);
+    }
+
+    /// Keep the store-and-forward queues and the scheduled messages in
+    /// write-ahead logs in `dir`, so parked and scheduled messages survive a
+    /// restart. Must be called before `launch`.
+    pub fn set_durable_queue(&mut self, dir: &str) {
+        self.durable_dir = Some(dir.to_string());
+    }
+
+    /// How many undeliverable messages the dead-letter queue keeps, 1024 by
+    /// default. Must be called before `launch`.
+    pub fn set_dead_letter_capacity(&mut self, capacity: usize) {
+        self.dead_letter_capacity = Some(capacity);
+    }
+
+    /// Join a federation of relayers as `relayer_id`, routing to the nodes of
+    /// linked peer relayers. Must be called before `launch`.
+    pub fn set_federation(&mut self, relayer_id: &str) {
+        self.federation = Some(Arc::new(Federation::new(relayer_id)));
+    }
+
+    /// Accept links from the peer relayer `relayer_id`, which proves itself
+    /// with the identity key behind `key`.
+    pub fn add_peer(&self, relayer_id: &str, key: RsaPublicKey) -> Result<(), String> {
+        let federation = self.federation.as_ref().ok_or("federation not set")?;
+        federation.add_peer(relayer_id, key)
+    }
+
+    /// Run as instance `instance_id` of a relayer cluster. `members` lists
+    /// the id and cluster address of every instance, this one included, in
+    /// the same order on every instance: the first one up leads, and the next
+    /// one still up takes over when it fails. Every instance needs the same
+    /// identity key. Must be called before `launch`.
+    pub fn set_cluster(&mut self, instance_id: &str, members: Vec<(String, String)>) {
+        self.cluster = Some(Arc::new(Cluster::new(instance_id, members)));
+    }
+
+    /// Id of the leading cluster instance, if known. Only the leader takes
+    /// registrations.
+    pub fn cluster_leader(&self) -> Option<String> {
+        self.cluster.as_ref().and_then(|cluster| cluster.leader())
+    }
+
+    /// Use a fixed identity key instead of generating one on launch, so the
+    /// key machines pin survives a relayer restart. Must be called before `launch`.
+    pub fn set_identity_key(&mut self, identity_key: RsaPrivateKey) {
+        self.identity_key = Some(identity_key);
+    }
+
+    /// The key machines should pin to check relayer-signed messages.
+    pub fn get_public_key(&self) -> Option<RsaPublicKey> {
+        self.public_key.clone()
+    }
+
+    /// Record every routed message in a hash-chained log at `path`, its head
+    /// signed with the identity key. Must be called after `set_identity_key`,
+    /// if at all, and before `launch`.
+    pub fn set_audit_log(&mut self, path: &str) -> Result<(), String> {
+        let identity_key = match &self.identity_key {
+            Some(identity_key) => identity_key.clone(),
+            None => {
+                let (private_key, _) = get_rsa()?;
+                self.identity_key = Some(private_key.clone());
+                private_key
+            }
+        };
+        self.audit = Some(AuditLog::open_table(path, identity_key)?);
+        Ok(())
+    }
+
     pub fn launch(&mut self) {
-        self.route_table = Some(Arc::new(Mutex::new(HashMap::new())));
-        self.pub_keys = Some(Arc::new(Mutex::new(HashMap::new())));
-        let clone_route_table = self.route_table.as_ref().unwrap().clone();
-        let clone_pub_keys = self.pub_keys.as_ref().unwrap().clone();
+        let identity_key = match self.identity_key.take() {
+            Some(identity_key) => identity_key,
+            None => match get_rsa() {
+                Ok((private_key, _)) => private_key,
+                Err(error) => {
+                    error!("relayer generate identity key failed,error={}", error);
+                    return;
+                }
+            },
+        };
+        self.public_key = Some(RsaPublicKey::from(&identity_key));
+        let mut state = RelayerState::<Contract>::new(identity_key);
+        state.audit = self.audit.clone();
+        state.limits = self.limits;
+        state.legacy_id_signatures = self.legacy_id_signatures;
+        if let Some(verifier) = self.verifier.take() {
+            state.verifier = Arc::new(verifier);
+        }
+        state.federation = self.federation.clone();
+        state.cluster = self.cluster.clone();
+        if let Some(capacity) = self.dead_letter_capacity {
+            state.dead_letters = Arc::new(DeadLetters::new(capacity));
+        }
+        state.parking = match (self.parking, &self.durable_dir) {
+            (Some(limits), Some(dir)) => match Parking::open(limits, dir, Router::get_target_id) {
+                Ok(parking) => Some(Arc::new(parking)),
+                Err(error) => {
+                    error!("relayer open durable queue failed,error={}", error);
+                    return;
+                }
+            },
+            (Some(limits), None) => Some(Arc::new(Parking::new(limits))),
+            (None, _) => None,
+        };
+        if let Some(dir) = &self.durable_dir {
+            match Scheduler::open(&format!("{}/scheduled", dir)) {
+                Ok(scheduler) => state.scheduler = Arc::new(scheduler),
+                Err(error) => {
+                    error!("relayer open durable schedule failed,error={}", error);
+                    return;
+                }
+            }
+        }
+        self.state = Some(state.clone());
         let rt = get_runtime();
         let (relayer_register_tx, relayer_register_rx): (
             Sender<RegisterInfo>,
@@ -39,19 +207,14 @@ where
         thread::spawn(move || {
             rt.block_on(listen_relayer_register::<Contract>(
                 relayer_register_rx,
-                clone_route_table,
-                clone_pub_keys,
+                state,
             ))
         });
         self.register = Some(relayer_register_tx);
     }
 
     pub fn is_ready(&self) -> bool {
-        match self.route_table {
-            None => return false,
-            _ => (),
-        }
-        match self.pub_keys {
+        match self.state {
             None => return false,
             _ => (),
         }
@@ -59,6 +222,9 @@ where
             None => return false,
             _ => (),
         }
+        if self.public_key.is_none() {
+            return false;
+        }
         true
     }
 
@@ -67,10 +233,9 @@ where
         register_info: RegisterInfo,
         pub_key: RsaPublicKey,
     ) -> Result<(), String> {
-        match &self.pub_keys {
-            Some(pub_key_map) => {
-                let mut lock = pub_key_map.lock().map_err(|err| err.to_string())?;
-                lock.insert((&register_info.get_source_id()).to_string(), pub_key);
+        match &self.state {
+            Some(state) => {
+                register_key(state, &register_info, pub_key)?;
             }
             None => return Err("relayer not ready".to_string()),
         }
@@ -85,4 +250,85 @@ where
         }
         Ok(())
     }
+
+    /// Forget the node `name` of `group`: its connection is closed, its route,
+    /// key and broadcast right are dropped, and messages parked for it are
+    /// bounced to their senders.
+    pub fn unregister_node(&self, name: &str, group: &str) -> Result<(), String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?;
+        unregister_node(state, &NodeId::new(name, group)?)?;
+        Ok(())
+    }
+
+    /// Start moving the node `name` of `group` to another machine. Messages
+    /// for it wait until it registers again under the same key, and are
+    /// bounced if it isn't back in time.
+    pub fn begin_migration(&self, name: &str, group: &str) -> Result<(), String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?;
+        begin_migration(state, &NodeId::new(name, group)?)
+    }
+
+    /// Allow the node `name` of `group` to broadcast to every node.
+    pub fn allow_broadcast(&self, name: &str, group: &str) -> Result<(), String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?;
+        let mut lock = state.broadcasters.lock().map_err(|err| err.to_string())?;
+        lock.insert(NodeId::new(name, group)?);
+        Ok(())
+    }
+
+    /// Send an operator notice to every registered node, signed by the
+    /// relayer. Returns how many nodes it reached.
+    pub fn broadcast(&self, notice: Contract) -> Result<usize, String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?;
+        relayer_broadcast(state, notice)
+    }
+
+    /// Messages the relayer couldn't deliver, oldest first.
+    pub fn dead_letters(&self) -> Result<Vec<DeadLetterInfo>, String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?;
+        Ok(state.dead_letters.list())
+    }
+
+    pub fn inspect_dead_letter(&self, id: u64) -> Result<DeadLetter<Contract>, String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?;
+        state
+            .dead_letters
+            .inspect(id)
+            .ok_or_else(|| format!("no dead letter {}", id))
+    }
+
+    /// Drop dead letter `id`, or every one with `None`. Returns how many went.
+    pub fn purge_dead_letters(&self, id: Option<u64>) -> Result<usize, String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?;
+        match id {
+            Some(id) if state.dead_letters.purge(id) => Ok(1),
+            Some(id) => Err(format!("no dead letter {}", id)),
+            None => Ok(state.dead_letters.purge_all()),
+        }
+    }
+
+    /// Route dead letter `id` again. If it fails again it is queued under a
+    /// new id.
+    pub async fn redeliver_dead_letter(&self, id: u64) -> Result<(), String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?;
+        redeliver_dead_letter(state, id).await
+    }
+
+    /// Accept links of peer relayers on `addr`.
+    pub async fn listen_peers(&self, addr: &str) -> Result<(), String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?.clone();
+        let listener = TcpListener::bind(addr).await.map_err(|err| err.to_string())?;
+        tokio::spawn(async move {
+            if let Err(error) = listen_peers(state, listener).await {
+                error!("relayer listen peers failed,error={}", error);
+            }
+        });
+        Ok(())
+    }
+
+    /// Link to the peer relayer listening on `addr`. Returns its id.
+    pub async fn connect_peer(&self, addr: &str) -> Result<String, String> {
+        let state = self.state.as_ref().ok_or("relayer not ready")?.clone();
+        connect_peer(state, addr).await
+    }
 }
@@ -0,0 +1,134 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use frame_common::data::AckLevel;
+use log::debug;
+
+use crate::ack::Tracking;
+
+/// How often and how patiently at-least-once messages are resent.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// sends in total, the first one included
+    pub max_attempts: u32,
+    /// wait for the ack of the first send
+    pub ack_timeout: Duration,
+    /// every retry waits this many times longer than the one before
+    pub backoff: u32,
+    pub max_ack_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            ack_timeout: Duration::from_secs(1),
+            backoff: 2,
+            max_ack_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait for the ack after send number `attempt`, from 1.
+    pub fn ack_timeout(&self, attempt: u32) -> Duration {
+        let factor = self.backoff.saturating_pow(attempt.saturating_sub(1));
+        self.ack_timeout
+            .saturating_mul(factor)
+            .min(self.max_ack_timeout)
+    }
+}
+
+/// Call `send` until `tracking` reports the message delivered or the policy
+/// gives up. Returns how many sends it took. A bounce isn't retried.
+pub async fn deliver_at_least_once<F, Fut>(
+    policy: &RetryPolicy,
+    tracking: &mut Tracking,
+    mut send: F,
+) -> Result<u32, String>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let mut last_error = "no attempt".to_string();
+    for attempt in 1..=policy.max_attempts.max(1) {
+        if let Err(error) = send(attempt).await {
+            debug!(
+                "send {} of message {} failed,error={}",
+                attempt,
+                tracking.id(),
+                error
+            );
+        }
+        match tracking
+            .wait_for_timeout(AckLevel::Delivered, policy.ack_timeout(attempt))
+            .await
+        {
+            Ok(()) => return Ok(attempt),
+            Err(error) if tracking.is_failed() => return Err(error),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(format!(
+        "gave up after {} attempts,error={}",
+        policy.max_attempts, last_error
+    ))
+}
+
+/// Message ids a machine has already seen, so handlers see a resent message
+/// once. Ids are forgotten after `window`, or sooner once there are more
+/// than `capacity` of them.
+#[derive(Clone)]
+pub struct DedupWindow {
+    window: Duration,
+    capacity: usize,
+    seen: Arc<Mutex<SeenIds>>,
+}
+
+#[derive(Default)]
+struct SeenIds {
+    ids: HashSet<(String, u64)>,
+    order: VecDeque<(Instant, (String, u64))>,
+}
+
+impl Default for DedupWindow {
+    fn default() -> Self {
+        DedupWindow::new(Duration::from_secs(300), 100_000)
+    }
+}
+
+impl DedupWindow {
+    pub fn new(window: Duration, capacity: usize) -> DedupWindow {
+        DedupWindow {
+            window,
+            capacity,
+            seen: Arc::new(Mutex::new(SeenIds::default())),
+        }
+    }
+
+    /// Record message `msg_id` of `source`. False if it was seen already.
+    pub fn first_seen(&self, source: &str, msg_id: u64) -> bool {
+        let mut seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(_) => return true,
+        };
+        let now = Instant::now();
+        while let Some((at, _)) = seen.order.front() {
+            if now.duration_since(*at) < self.window && seen.order.len() < self.capacity {
+                break;
+            }
+            let (_, key) = seen.order.pop_front().unwrap();
+            seen.ids.remove(&key);
+        }
+        let key = (source.to_string(), msg_id);
+        if !seen.ids.insert(key.clone()) {
+            return false;
+        }
+        seen.order.push_back((now, key));
+        true
+    }
+}
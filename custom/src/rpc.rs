@@ -0,0 +1,234 @@
+//! Request/response calls between nodes.
+//!
+//! A request carries a correlation id the machine keeps in its
+//! [`PendingTable`]; the target's handler answers through a [`Replier`] with
+//! the same id, and the response goes to whoever waits on the call. A call
+//! fails once its timeout, counted from when it was sent, runs out, or when
+//! the relayer bounces the request. Dropping the call cancels it.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use frame_common::{
+    data::{Ack, AckLevel, BridgeMessage, Correlation, Expiry, Message, Router},
+    id::NodeId,
+    sign,
+};
+use log::debug;
+use rsa::RsaPrivateKey;
+use tokio::{
+    sync::{mpsc::Sender, oneshot},
+    time::Instant,
+};
+
+struct Waiter {
+    /// source id the response must come from
+    responder: NodeId,
+    reply: oneshot::Sender<Result<BridgeMessage, String>>,
+}
+
+/// Requests of one machine still waiting for their response, by correlation id.
+#[derive(Clone, Default)]
+pub struct PendingTable {
+    next_id: Arc<AtomicU64>,
+    waiters: Arc<Mutex<HashMap<u64, Waiter>>>,
+}
+
+impl PendingTable {
+    /// Register a request to `responder` and get the call waiting for it. The
+    /// timeout runs from now.
+    pub fn start(&self, responder: &NodeId, timeout: Duration) -> Result<PendingCall, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        let waiter = Waiter {
+            responder: responder.clone(),
+            reply: tx,
+        };
+        self.waiters
+            .lock()
+            .map_err(|err| err.to_string())?
+            .insert(id, waiter);
+        Ok(PendingCall {
+            id,
+            deadline: Instant::now() + timeout,
+            reply: rx,
+            table: self.clone(),
+        })
+    }
+
+    /// Hand a response, or the relayer bouncing a request, to whoever waits
+    /// for it. Returns false if nobody does any more.
+    pub fn resolve(&self, message: BridgeMessage) -> bool {
+        let id = match message.correlation {
+            Some(Correlation::Response(id)) | Some(Correlation::Request(id)) => id,
+            None => return false,
+        };
+        let mut lock = match self.waiters.lock() {
+            Ok(lock) => lock,
+            Err(_) => return false,
+        };
+        let bounced = message.error_msg.is_some();
+        match lock.get(&id) {
+            Some(waiter) if bounced || waiter.responder == message.get_source_id() => (),
+            Some(_) => {
+                debug!(
+                    "drop response {} from unexpected node {}",
+                    id,
+                    message.get_source_id()
+                );
+                return false;
+            }
+            None => return false,
+        }
+        let waiter = lock.remove(&id).unwrap();
+        let result = match message.error_msg {
+            Some(error) if bounced => Err(error.to_string()),
+            _ => Ok(message),
+        };
+        waiter.reply.send(result).is_ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.lock().map(|lock| lock.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn forget(&self, id: u64) {
+        if let Ok(mut lock) = self.waiters.lock() {
+            lock.remove(&id);
+        }
+    }
+}
+
+/// A request waiting for its response. Dropping it cancels the request, a
+/// response arriving later is discarded.
+pub struct PendingCall {
+    id: u64,
+    deadline: Instant,
+    reply: oneshot::Receiver<Result<BridgeMessage, String>>,
+    table: PendingTable,
+}
+
+impl PendingCall {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wait for the response, until the timeout given to the request ran out
+    /// since it was sent.
+    pub async fn wait(mut self) -> Result<BridgeMessage, String> {
+        match tokio::time::timeout_at(self.deadline, &mut self.reply).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err("request cancelled".to_string()),
+            Err(_) => Err(format!("request {} timed out", self.id)),
+        }
+    }
+
+    pub fn cancel(self) {}
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        self.table.forget(self.id);
+    }
+}
+
+/// Sends responses on behalf of one node.
+#[derive(Clone)]
+pub struct Replier {
+    pub input: Sender<BridgeMessage>,
+    pub private_key: RsaPrivateKey,
+}
+
+impl Replier {
+    /// Answer `request` with `content`. Blocks, so call it off the runtime.
+    pub fn reply(&self, request: &BridgeMessage, content: String) -> Result<(), String> {
+        let id = match request.correlation {
+            Some(Correlation::Request(id)) => id,
+            _ => return Err("message is not a request".to_string()),
+        };
+        let response = BridgeMessage {
+            message: Box::new(content),
+            correlation: Some(Correlation::Response(id)),
+            ..Default::default()
+        };
+        let response = self.answer(request, response)?;
+        self.input
+            .blocking_send(response)
+            .map_err(|err| err.to_string())
+    }
+
+    /// Tell the sender of `message` it expired before it was handled, if the
+    /// sender asked.
+    pub async fn send_expired(&self, message: &BridgeMessage) -> Result<(), String> {
+        if !message.wants_expiry_notice() {
+            return Ok(());
+        }
+        let notice = BridgeMessage {
+            message: message.message.clone(),
+            expiry: Some(Expiry::Expired),
+            ..Default::default()
+        };
+        let notice = self.answer(message, notice)?;
+        self.input.send(notice).await.map_err(|err| err.to_string())
+    }
+
+    /// Tell the sender of `message` it reached `level`, if the sender asked.
+    pub async fn send_ack(&self, message: &BridgeMessage, level: AckLevel) -> Result<(), String> {
+        match self.ack(message, level)? {
+            Some(ack) => self.input.send(ack).await.map_err(|err| err.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Same as `send_ack`, for threads outside the runtime.
+    pub fn blocking_send_ack(
+        &self,
+        message: &BridgeMessage,
+        level: AckLevel,
+    ) -> Result<(), String> {
+        match self.ack(message, level)? {
+            Some(ack) => self.input.blocking_send(ack).map_err(|err| err.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn ack(
+        &self,
+        message: &BridgeMessage,
+        level: AckLevel,
+    ) -> Result<Option<BridgeMessage>, String> {
+        let id = match message.ack {
+            Some(Ack::Wanted { id, level: wanted }) if level <= wanted => id,
+            _ => return Ok(None),
+        };
+        let ack = BridgeMessage {
+            ack: Some(Ack::Reached { id, level }),
+            ..Default::default()
+        };
+        self.answer(message, ack).map(Some)
+    }
+
+    /// Address `answer` back to the sender of `to` and sign it.
+    fn answer(
+        &self,
+        to: &BridgeMessage,
+        mut answer: BridgeMessage,
+    ) -> Result<BridgeMessage, String> {
+        answer.from_name = to.to_name.clone();
+        answer.from_group = to.to_group.clone();
+        answer.to_name = to.from_name.clone();
+        answer.to_group = to.from_group.clone();
+        answer.sig = Some(sign(&answer.get_source_id().to_string(), &self.private_key)?);
+        Ok(answer)
+    }
+}
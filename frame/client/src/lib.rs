@@ -1,10 +1,24 @@
 #[cfg(test)]
 mod tests;
 
-use std::error::Error;
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use frame_common::parse_message_list;
+use frame_common::{
+    codec::{FrameDecoder, DELIMITER},
+    data::{Message, RelayerAuth},
+    lane::Lanes,
+    parse_message, relayer_auth_data, verify,
+};
 use log::{debug, error, info};
+use rsa::RsaPublicKey;
 use serde::{de::DeserializeOwned, Serialize};
 
 use tokio::{
@@ -13,24 +27,43 @@ use tokio::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpListener,
     },
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{self, error::TryRecvError, Receiver, Sender},
+        oneshot,
+    },
 };
 
+/// A relayer that doesn't answer the challenge in time is dropped.
+const RELAYER_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest frame taken from the relayer, a message at the relayer's size
+/// limit with its signatures and notices added.
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Where a stopped node's connection hands back what the node queued and
+/// wasn't sent yet, in the order it would have gone out.
+pub type Unsent<T> = oneshot::Sender<Vec<T>>;
+
 pub struct LaunchInfo<T>
 where
-    T: Send + 'static + Serialize + DeserializeOwned,
+    T: Send + 'static + Serialize + DeserializeOwned + Message,
 {
     pub name: Box<String>,
     pub addr: Box<String>,
     pub input: Box<Receiver<T>>,
     pub output: Box<Sender<T>>,
+    /// the machine sends on it to take the node off; the listener is
+    /// released before the unsent messages are handed back
+    pub stop: Box<oneshot::Receiver<Unsent<T>>>,
+    /// key a connecting relayer has to prove it holds; without one no
+    /// relayer is taken
+    pub relayer_key: Option<RsaPublicKey>,
 }
 
 pub async fn listen_clients_register<T>(
     mut clients_rx: Receiver<LaunchInfo<T>>,
 ) -> Result<(), Box<dyn Error>>
 where
-    T: Send + 'static + Serialize + DeserializeOwned,
+    T: Send + 'static + Serialize + DeserializeOwned + Message,
 {
     let future = tokio::spawn(async move {
         while let Some(lauch_info) = clients_rx.recv().await {
@@ -40,6 +73,8 @@ where
                     &lauch_info.addr,
                     *lauch_info.input,
                     *lauch_info.output,
+                    *lauch_info.stop,
+                    lauch_info.relayer_key,
                     (&lauch_info.name).to_string(),
                 )
                 .await;
@@ -65,67 +100,302 @@ where
     Ok(())
 }
 
+/// Listen for the relayer on `addr`. A connection counts only once it proved
+/// to hold `relayer_key`. A relayer connecting later, like a cluster instance
+/// taking over, replaces the one before: the node's messages go to the newest
+/// connection, and every connection is read until it closes. Once the machine
+/// stops the node the listener is released, and the messages not sent yet
+/// are handed back.
 async fn client_listen<T>(
     addr: &str,
     input: Receiver<T>,
     output: Sender<T>,
+    stop: oneshot::Receiver<Unsent<T>>,
+    relayer_key: Option<RsaPublicKey>,
     who: String,
 ) -> Result<(), Box<dyn Error>>
 where
-    T: Send + 'static + Serialize + DeserializeOwned,
+    T: Send + 'static + Serialize + DeserializeOwned + Message,
 {
     let listener = TcpListener::bind(addr).await?;
-    let (stream, _) = listener.accept().await?;
-    let (reader, writer) = stream.into_split();
+    let addr = addr.to_string();
 
     debug!("addr={} listen has build", addr);
 
+    let (writers_tx, writers_rx) = mpsc::channel(4);
+    // the stop reaches the sender only once the listener is gone
+    let (handoff_tx, handoff_rx) = oneshot::channel();
     let who_clone = who.clone();
+    let notices = output.clone();
     tokio::spawn(async move {
-        do_send(input, writer, who_clone).await;
+        do_send(input, writers_rx, notices, Some(handoff_rx), who_clone).await;
     });
-    let who_clone = who.clone();
+    let mut stop = Some(stop);
     tokio::spawn(async move {
-        do_receive(output, reader, who_clone).await;
+        let unsent = loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                unsent = stopped(&mut stop) => break Some(unsent),
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    error!("{} accept relayer failed,error={}", who, error);
+                    break None;
+                }
+            };
+            let writers_tx = writers_tx.clone();
+            let output = output.clone();
+            let relayer_key = relayer_key.clone();
+            let addr = addr.clone();
+            let who = who.clone();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.into_split();
+                let auth = authenticate(&mut reader, &mut writer, &addr, relayer_key.as_ref());
+                let answered = tokio::time::timeout(RELAYER_AUTH_TIMEOUT, auth).await;
+                let (decoder, frames) = match answered {
+                    Ok(Ok(read)) => read,
+                    Ok(Err(error)) => {
+                        error!("{} refuse relayer connection,error={}", who, error);
+                        return;
+                    }
+                    Err(_) => {
+                        error!("{} refuse relayer connection,error=no answer", who);
+                        return;
+                    }
+                };
+                info!("{} connected by relayer", who);
+                let closed = Arc::new(AtomicBool::new(false));
+                let outbound = Outbound {
+                    writer,
+                    closed: closed.clone(),
+                };
+                if writers_tx.send(outbound).await.is_err() {
+                    return;
+                }
+                do_receive(output, reader, decoder, frames, who).await;
+                closed.store(true, Ordering::Relaxed);
+            });
+        };
+        drop(listener);
+        if let Some(unsent) = unsent {
+            info!("{} stop listening for relayer", who);
+            let _ = handoff_tx.send(unsent);
+        }
     });
     Ok(())
 }
 
-async fn do_send<T>(mut input: Receiver<T>, mut writer: OwnedWriteHalf, who: String)
-where
-    T: Send + 'static + Serialize + DeserializeOwned,
+/// Resolves once the machine stops the node, never if it no longer can.
+async fn stopped<T>(stop: &mut Option<oneshot::Receiver<T>>) -> T {
+    if let Some(receiver) = stop {
+        if let Ok(value) = receiver.await {
+            return value;
+        }
+    }
+    *stop = None;
+    std::future::pending().await
+}
+
+/// Write half of a relayer connection, and whether its read half saw it close.
+struct Outbound {
+    writer: OwnedWriteHalf,
+    closed: Arc<AtomicBool>,
+}
+
+/// Write what the node sends to the relayer. Messages waiting for the stream
+/// are sorted into priority lanes, at most `LANE_BUFFER` of them. A message
+/// that expired while queued here is dropped, and its notice goes straight
+/// back to the node. A message the connection fails to take waits for the
+/// next relayer connection. Once stopped, every message left is handed back
+/// on the `Unsent` the stop carries.
+async fn do_send<T>(
+    mut input: Receiver<T>,
+    mut writers: Receiver<Outbound>,
+    notices: Sender<T>,
+    mut stop: Option<oneshot::Receiver<Unsent<T>>>,
+    who: String,
+) where
+    T: Send + 'static + Serialize + DeserializeOwned + Message,
 {
-    while let Some(raw_msg) = input.recv().await {
+    const LANE_BUFFER: usize = 64;
+    let mut outbound = tokio::select! {
+        // the stop first, the writers close right after it
+        biased;
+        unsent = stopped(&mut stop) => {
+            let _ = unsent.send(take_unsent(input, Lanes::default()).await);
+            return;
+        }
+        outbound = writers.recv() => match outbound {
+            Some(outbound) => outbound,
+            None => return,
+        },
+    };
+    let mut lanes = Lanes::default();
+    let mut closed = false;
+    let stopped_by = 'sending: loop {
+        if let Some(Ok(unsent)) = stop.as_mut().map(oneshot::Receiver::try_recv) {
+            break Some(unsent);
+        }
+        if lanes.is_empty() {
+            tokio::select! {
+                biased;
+                unsent = stopped(&mut stop) => break Some(unsent),
+                raw_msg = input.recv() => match raw_msg {
+                    Some(raw_msg) => lanes.push(raw_msg.get_priority(), raw_msg),
+                    None => break None,
+                },
+            }
+        }
+        // take what else is waiting, so urgent messages can overtake
+        while !closed && lanes.len() < LANE_BUFFER {
+            match input.try_recv() {
+                Ok(raw_msg) => lanes.push(raw_msg.get_priority(), raw_msg),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => closed = true,
+            }
+        }
+        let mut raw_msg = match lanes.pop() {
+            Some(raw_msg) => raw_msg,
+            None => continue,
+        };
+        if raw_msg.is_expired() {
+            debug!("{} drop expired message", who);
+            if raw_msg.wants_expiry_notice() {
+                raw_msg.set_expired();
+                if let Err(error) = notices.send(raw_msg).await {
+                    error!("send expiry notice failed,error={}", error);
+                }
+            }
+            continue;
+        }
         let res = serde_json::to_string(&raw_msg);
         match res {
             Ok(mut serialized) => {
                 serialized.push_str("/*1^/");
                 debug!("sender get message={}", serialized);
-                if let Err(error) = writer.write_all(serialized.as_bytes()).await {
-                    error!("sender error to write to stream; error = {}", error);
+                loop {
+                    while let Ok(newer) = writers.try_recv() {
+                        outbound = newer;
+                    }
+                    let res = if outbound.closed.load(Ordering::Relaxed) {
+                        Err("relayer connection closed".to_string())
+                    } else {
+                        outbound
+                            .writer
+                            .write_all(serialized.as_bytes())
+                            .await
+                            .map_err(|err| err.to_string())
+                    };
+                    match res {
+                        Ok(()) => break,
+                        Err(error) => {
+                            error!("sender error to write to stream; error = {}", error);
+                            tokio::select! {
+                                biased;
+                                unsent = stopped(&mut stop) => {
+                                    lanes.push(raw_msg.get_priority(), raw_msg);
+                                    break 'sending Some(unsent);
+                                }
+                                newer = writers.recv() => match newer {
+                                    Some(newer) => outbound = newer,
+                                    None => return,
+                                },
+                            }
+                        }
+                    }
                 }
             }
             Err(error) => error!("sender serialize message error,error={}", error),
         }
+    };
+    if let Some(unsent) = stopped_by {
+        let unsent_messages = take_unsent(input, lanes).await;
+        info!("{} hand back {} unsent messages", who, unsent_messages.len());
+        let _ = unsent.send(unsent_messages);
     }
 }
 
-async fn do_receive<T>(output: Sender<T>, mut reader: OwnedReadHalf, who: String)
+/// Everything the node queued and wasn't written, in the order it would have
+/// gone out.
+async fn take_unsent<T>(mut input: Receiver<T>, mut lanes: Lanes<T>) -> Vec<T>
 where
-    T: Send + 'static + Serialize + DeserializeOwned,
+    T: Send + 'static + Serialize + DeserializeOwned + Message,
+{
+    input.close();
+    while let Some(raw_msg) = input.recv().await {
+        lanes.push(raw_msg.get_priority(), raw_msg);
+    }
+    std::iter::from_fn(|| lanes.pop()).collect()
+}
+
+/// Challenge the relayer that connected to sign a fresh nonce with the key
+/// the node pinned. Returns the decoder of the connection, with the frames
+/// that came right after the answer.
+async fn authenticate(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+    addr: &str,
+    relayer_key: Option<&RsaPublicKey>,
+) -> Result<(FrameDecoder, VecDeque<Result<String, String>>), String> {
+    let relayer_key = relayer_key.ok_or("relayer key not pinned")?;
+    let nonce: u64 = rand::random();
+    let mut challenge =
+        serde_json::to_string(&RelayerAuth::Challenge { nonce }).map_err(|err| err.to_string())?;
+    challenge.push_str(DELIMITER);
+    writer
+        .write_all(challenge.as_bytes())
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+    let mut frames = VecDeque::new();
+    let mut buf = [0; 4096];
+    while frames.is_empty() {
+        let size = reader.read(&mut buf).await.map_err(|err| err.to_string())?;
+        if size == 0 {
+            return Err("relayer closed before answering".to_string());
+        }
+        frames.extend(decoder.push(&buf[..size]));
+    }
+    let answer = frames.pop_front().unwrap()?;
+    match parse_message::<RelayerAuth>(&answer)? {
+        RelayerAuth::Answer { sig } => verify(&relayer_auth_data(nonce, addr), relayer_key, &sig)?,
+        RelayerAuth::Challenge { .. } => return Err("relayer didn't answer".to_string()),
+    }
+    Ok((decoder, frames))
+}
+
+/// Hand what the relayer sends to the node, starting with `frames` already
+/// split off the stream.
+async fn do_receive<T>(
+    output: Sender<T>,
+    mut reader: OwnedReadHalf,
+    mut decoder: FrameDecoder,
+    mut frames: VecDeque<Result<String, String>>,
+    who: String,
+) where
+    T: Send + 'static + Serialize + DeserializeOwned + Message,
 {
     let mut buf = [0; 4096];
-    while let Ok(size) = reader.read(&mut buf).await {
-        if size != 0 {
-            let serialized = String::from_utf8_lossy(&mut buf[0..size]);
-            debug!("{} receiver get message={}", who, serialized);
-            let mut item_list = parse_message_list::<T>(&serialized);
-            while !item_list.is_empty() {
-                let item = item_list.remove(0);
-                if let Err(error) = output.send(item).await {
-                    error!("receive then send out failed,error={}", error);
+    loop {
+        for frame in frames.drain(..) {
+            debug!("{} receiver get message={:?}", who, frame);
+            let item = match frame.and_then(|frame| parse_message::<T>(&frame)) {
+                Ok(item) => item,
+                Err(error) => {
+                    error!("{} receive bad frame,error={}", who, error);
+                    continue;
                 }
+            };
+            if let Err(error) = output.send(item).await {
+                error!("receive then send out failed,error={}", error);
             }
         }
+        let size = match reader.read(&mut buf).await {
+            Ok(size) if size > 0 => size,
+            _ => break,
+        };
+        frames.extend(decoder.push(&buf[..size]));
     }
+    info!("{} relayer connection closed", who);
 }
@@ -5,12 +5,19 @@ use std::{
 };
 
 use tokio::{
-    io::AsyncWriteExt,
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     runtime::Runtime,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{mpsc::{self, Receiver, Sender}, oneshot},
 };
 
+use frame_common::{
+    codec::{FrameDecoder, DELIMITER},
+    data::{BridgeMessage, RelayerAuth},
+    get_rsa, parse_message, relayer_auth_data, sign,
+};
+use rsa::RsaPrivateKey;
+
 use crate::{listen_clients_register, LaunchInfo};
 
 fn get_runtime() -> Runtime {
@@ -19,63 +26,101 @@ fn get_runtime() -> Runtime {
 
 async fn get_stream(
     addr: &str,
-    launch_info: LaunchInfo<String>,
-    sender: Sender<LaunchInfo<String>>,
+    launch_info: LaunchInfo<BridgeMessage>,
+    sender: Sender<LaunchInfo<BridgeMessage>>,
+    relayer_key: &RsaPrivateKey,
 ) -> TcpStream {
     sender.send(launch_info).await;
 
     thread::sleep(Duration::from_secs(1));
-    TcpStream::connect(addr).await.unwrap()
+    connect_relayer(addr, relayer_key).await
+}
+
+/// Connect to the node on `addr` as a relayer holding `relayer_key`.
+async fn connect_relayer(addr: &str, relayer_key: &RsaPrivateKey) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut decoder = FrameDecoder::new(4096);
+    let mut buf = [0; 4096];
+    let nonce = loop {
+        let size = stream.read(&mut buf).await.unwrap();
+        assert!(size > 0, "connection closed");
+        if let Some(frame) = decoder.push(&buf[..size]).pop() {
+            match parse_message(&frame.unwrap()).unwrap() {
+                RelayerAuth::Challenge { nonce } => break nonce,
+                RelayerAuth::Answer { .. } => panic!("expect a challenge"),
+            }
+        }
+    };
+    let sig = sign(&relayer_auth_data(nonce, addr), relayer_key).unwrap();
+    let answer = serde_json::to_string(&RelayerAuth::Answer { sig }).unwrap() + DELIMITER;
+    stream.write_all(answer.as_bytes()).await.unwrap();
+    stream
+}
+
+fn bridge_message(content: &str) -> BridgeMessage {
+    BridgeMessage {
+        message: Box::new(content.to_string()),
+        ..Default::default()
+    }
+}
+
+fn text_message(content: &str) -> String {
+    serde_json::to_string(&bridge_message(content)).unwrap()
 }
 
 #[test]
 fn test_client() {
     let rt = get_runtime();
     let rt1 = get_runtime();
+    let (relayer_pr, relayer_pu) = get_rsa().unwrap();
 
     let (all_clients_tx, all_clients_rx): (
-        Sender<LaunchInfo<String>>,
-        Receiver<LaunchInfo<String>>,
+        Sender<LaunchInfo<BridgeMessage>>,
+        Receiver<LaunchInfo<BridgeMessage>>,
     ) = mpsc::channel(32);
     thread::spawn(move || {
         rt1.block_on(listen_clients_register(all_clients_rx));
     });
 
-    let (input_tx1, input_rx1): (Sender<String>, Receiver<String>) = mpsc::channel(32);
-    let (output_tx1, output_rx1): (Sender<String>, Receiver<String>) = mpsc::channel(32);
+    let (input_tx1, input_rx1): (Sender<BridgeMessage>, Receiver<BridgeMessage>) = mpsc::channel(32);
+    let (output_tx1, output_rx1): (Sender<BridgeMessage>, Receiver<BridgeMessage>) = mpsc::channel(32);
     let addr = "127.0.0.1:8787";
     let launch_info = LaunchInfo {
         addr: Box::new(addr.to_string()),
         input: Box::new(input_rx1),
         output: Box::new(output_tx1),
         name: Box::new("A1".to_string()),
+        stop: Box::new(oneshot::channel().1),
+        relayer_key: Some(relayer_pu.clone()),
     };
     let all_clients_tx_1 = all_clients_tx.clone();
-    let mut r1 = rt.block_on(async { get_stream(addr, launch_info, all_clients_tx_1).await });
+    let mut r1 = rt.block_on(async { get_stream(addr, launch_info, all_clients_tx_1, &relayer_pr).await });
 
-    let (input_tx2, input_rx2): (Sender<String>, Receiver<String>) = mpsc::channel(32);
-    let (output_tx2, _output_rx2): (Sender<String>, Receiver<String>) = mpsc::channel(32);
+    let (input_tx2, input_rx2): (Sender<BridgeMessage>, Receiver<BridgeMessage>) = mpsc::channel(32);
+    let (output_tx2, _output_rx2): (Sender<BridgeMessage>, Receiver<BridgeMessage>) = mpsc::channel(32);
     let addr = "127.0.0.1:9787";
     let launch_info = LaunchInfo {
         addr: Box::new(addr.to_string()),
         input: Box::new(input_rx2),
         output: Box::new(output_tx2),
         name: Box::new("B1".to_string()),
+        stop: Box::new(oneshot::channel().1),
+        relayer_key: Some(relayer_pu),
     };
     let all_clients_tx_2 = all_clients_tx.clone();
-    let mut r2 = rt.block_on(async { get_stream(addr, launch_info, all_clients_tx_2).await });
+    let mut r2 = rt.block_on(async { get_stream(addr, launch_info, all_clients_tx_2, &relayer_pr).await });
 
     rt.block_on(async {
-        r1.write_all("\"response to 1\"".as_bytes()).await;
+        r1.write_all(text_message("response to 1").as_bytes()).await;
     });
 
     rt.block_on(async {
-        r2.write_all("\"response to 2\"".as_bytes()).await;
+        r2.write_all(text_message("response to 2").as_bytes()).await;
     });
 
     rt.block_on(async {
-        input_tx1.send("message from 1".to_string()).await;
-        input_tx2.send("message from 2".to_string()).await;
+        input_tx1.send(bridge_message("message from 1")).await;
+        input_tx2.send(bridge_message("message from 2")).await;
     });
     loop {}
 }
@@ -93,3 +138,117 @@ fn test_bind() {
         println!("bind2");
     });
 }
+
+async fn read_message(stream: &mut TcpStream) -> String {
+    let mut decoder = FrameDecoder::new(4096);
+    let mut buf = [0; 4096];
+    loop {
+        let size = stream.read(&mut buf).await.unwrap();
+        assert!(size > 0, "connection closed");
+        if let Some(frame) = decoder.push(&buf[..size]).pop() {
+            let msg: BridgeMessage = serde_json::from_str(&frame.unwrap()).unwrap();
+            return *msg.message;
+        }
+    }
+}
+
+#[test]
+fn test_next_relayer_takes_over() {
+    let rt = get_runtime();
+    let rt1 = get_runtime();
+    let (relayer_pr, relayer_pu) = get_rsa().unwrap();
+
+    let (all_clients_tx, all_clients_rx): (
+        Sender<LaunchInfo<BridgeMessage>>,
+        Receiver<LaunchInfo<BridgeMessage>>,
+    ) = mpsc::channel(32);
+    thread::spawn(move || {
+        rt1.block_on(listen_clients_register(all_clients_rx));
+    });
+
+    let (input_tx, input_rx): (Sender<BridgeMessage>, Receiver<BridgeMessage>) = mpsc::channel(32);
+    let (output_tx, mut output_rx): (Sender<BridgeMessage>, Receiver<BridgeMessage>) = mpsc::channel(32);
+    let addr = "127.0.0.1:8797";
+    let launch_info = LaunchInfo {
+        addr: Box::new(addr.to_string()),
+        input: Box::new(input_rx),
+        output: Box::new(output_tx),
+        name: Box::new("A1".to_string()),
+        stop: Box::new(oneshot::channel().1),
+        relayer_key: Some(relayer_pu),
+    };
+    let all_clients_tx_1 = all_clients_tx.clone();
+    let mut first = rt.block_on(async { get_stream(addr, launch_info, all_clients_tx_1, &relayer_pr).await });
+
+    rt.block_on(async {
+        input_tx.send(bridge_message("before")).await.unwrap();
+        assert_eq!("before", read_message(&mut first).await);
+    });
+
+    // the relayer goes away, what the node sends meanwhile waits for the next one
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut second = rt.block_on(async {
+        input_tx.send(bridge_message("meanwhile")).await.unwrap();
+        let mut second = connect_relayer(addr, &relayer_pr).await;
+        assert_eq!("meanwhile", read_message(&mut second).await);
+
+        second.write_all((text_message("from next relayer") + "/*1^/").as_bytes()).await.unwrap();
+        let received = output_rx.recv().await.unwrap();
+        assert_eq!("from next relayer", received.message.as_str());
+        second
+    });
+
+    // a connection without the relayer key doesn't take the node's messages
+    let (imposter_pr, _) = get_rsa().unwrap();
+    rt.block_on(async {
+        let mut imposter = connect_relayer(addr, &imposter_pr).await;
+        let mut buf = [0; 16];
+        let closed = tokio::time::timeout(Duration::from_secs(5), imposter.read(&mut buf)).await;
+        assert_eq!(0, closed.unwrap().unwrap());
+        input_tx.send(bridge_message("after imposter")).await.unwrap();
+        assert_eq!("after imposter", read_message(&mut second).await);
+    });
+}
+
+#[test]
+fn test_stop_releases_listener() {
+    let rt = get_runtime();
+    let rt1 = get_runtime();
+
+    let (all_clients_tx, all_clients_rx): (
+        Sender<LaunchInfo<BridgeMessage>>,
+        Receiver<LaunchInfo<BridgeMessage>>,
+    ) = mpsc::channel(32);
+    thread::spawn(move || {
+        let _ = rt1.block_on(listen_clients_register(all_clients_rx));
+    });
+
+    let (input_tx, input_rx): (Sender<BridgeMessage>, Receiver<BridgeMessage>) = mpsc::channel(32);
+    let (output_tx, _output_rx): (Sender<BridgeMessage>, Receiver<BridgeMessage>) = mpsc::channel(32);
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let addr = "127.0.0.1:8798";
+    let launch_info = LaunchInfo {
+        addr: Box::new(addr.to_string()),
+        input: Box::new(input_rx),
+        output: Box::new(output_tx),
+        name: Box::new("A1".to_string()),
+        stop: Box::new(stop_rx),
+        relayer_key: None,
+    };
+
+    rt.block_on(async {
+        assert!(all_clients_tx.send(launch_info).await.is_ok());
+        // no relayer connected, so both stay queued
+        input_tx.send(bridge_message("first")).await.unwrap();
+        input_tx.send(bridge_message("second")).await.unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        let (unsent_tx, unsent_rx) = oneshot::channel();
+        assert!(stop_tx.send(unsent_tx).is_ok());
+        let unsent: Vec<String> = unsent_rx.await.unwrap().into_iter().map(|msg| *msg.message).collect();
+        assert_eq!(vec!["first", "second"], unsent);
+        assert!(input_tx.send(bridge_message("late")).await.is_err());
+        TcpListener::bind(addr).await.unwrap();
+    });
+}
@@ -0,0 +1,117 @@
+//! Splitting a byte stream into delimited JSON frames without trusting the peer.
+
+pub const DELIMITER: &str = "/*1^/";
+
+/// Incremental frame splitter. Bytes are buffered only until the next
+/// delimiter and never beyond `max_frame_size`; an oversized frame is reported
+/// once and the rest of it is discarded as it arrives. Every byte is searched
+/// for a delimiter once, however many chunks a frame comes in.
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// bytes of `buf` known not to start a delimiter
+    scanned: usize,
+    max_frame_size: usize,
+    discarding: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(max_frame_size: usize) -> FrameDecoder {
+        FrameDecoder {
+            buf: Vec::new(),
+            scanned: 0,
+            max_frame_size,
+            discarding: false,
+        }
+    }
+
+    /// Feed the next chunk read from the stream and get back every frame it
+    /// completed, in order. A bad frame (too large or not utf-8) comes back as
+    /// an error instead of a string.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Result<String, String>> {
+        let delimiter = DELIMITER.as_bytes();
+        // a delimiter may start in the last bytes and end in the next chunk
+        let keep = delimiter.len() - 1;
+        let mut frames = Vec::new();
+        self.buf.extend_from_slice(chunk);
+        // start of the frame not split off yet
+        let mut start = 0;
+        while let Some(found) = find(&self.buf[self.scanned..], delimiter) {
+            let end = self.scanned + found;
+            let frame = &self.buf[start..end];
+            if self.discarding {
+                self.discarding = false;
+            } else if frame.len() > self.max_frame_size {
+                frames.push(Err(format!("frame too large,size={}", frame.len())));
+            } else if !frame.is_empty() {
+                frames.push(String::from_utf8(frame.to_vec()).map_err(|err| err.to_string()));
+            }
+            start = end + delimiter.len();
+            self.scanned = start;
+        }
+        self.scanned = self.scanned.max(self.buf.len().saturating_sub(keep));
+        // keep a possible partial delimiter, drop the rest of an oversized frame
+        if self.buf.len() - start > self.max_frame_size + keep {
+            if !self.discarding {
+                frames.push(Err(format!("frame too large,size>{}", self.max_frame_size)));
+                self.discarding = true;
+            }
+            start = self.buf.len() - keep;
+        }
+        self.buf.drain(..start);
+        self.scanned -= start;
+        frames
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameDecoder;
+
+    #[test]
+    fn test_split_across_chunks() {
+        let mut decoder = FrameDecoder::new(64);
+        assert!(decoder.push(b"\"hel").is_empty());
+        let frames = decoder.push(b"lo\"/*1^/\"wor");
+        assert_eq!(vec![Ok("\"hello\"".to_string())], frames);
+        assert!(decoder.push(b"ld\"/*1").is_empty());
+        let frames = decoder.push(b"^/");
+        assert_eq!(vec![Ok("\"world\"".to_string())], frames);
+    }
+
+    #[test]
+    fn test_oversized_frame_is_discarded() {
+        let mut decoder = FrameDecoder::new(8);
+        let frames = decoder.push(&[b'x'; 100]);
+        assert_eq!(1, frames.len());
+        assert!(frames[0].is_err());
+        // still discarding: no second error for the same frame
+        assert!(decoder.push(&[b'x'; 100]).is_empty());
+        let frames = decoder.push(b"xx/*1^/\"ok\"/*1^/");
+        assert_eq!(vec![Ok("\"ok\"".to_string())], frames);
+    }
+
+    #[test]
+    fn test_large_frame_in_small_chunks() {
+        let mut decoder = FrameDecoder::new(1 << 20);
+        let body = "x".repeat(100_000);
+        let stream = format!("\"{}\"/*1^/\"a\"/*1^//*1^/\"b\"/*1^/", body);
+        let mut frames = Vec::new();
+        for chunk in stream.as_bytes().chunks(3) {
+            frames.extend(decoder.push(chunk));
+        }
+        let expected: Vec<Result<String, String>> = vec![
+            Ok(format!("\"{}\"", body)),
+            Ok("\"a\"".to_string()),
+            Ok("\"b\"".to_string()),
+        ];
+        assert_eq!(expected, frames);
+        assert!(decoder.buf.is_empty());
+        assert_eq!(0, decoder.scanned);
+    }
+}
@@ -4,7 +4,9 @@ use std::hash::Hash;
 use serde::{Deserialize, Serialize};
 use tokio::net::tcp::OwnedWriteHalf;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use crate::id::NodeId;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct BridgeMessage {
     pub from_name: Box<String>,
     pub from_group: Box<String>,
@@ -13,11 +15,176 @@ pub struct BridgeMessage {
     pub message: Box<String>,
     pub error_msg: Option<Box<String>>,
     pub sig:Option<Vec<u8>>,
+    pub relayer_sig: Option<Vec<u8>>,
+    /// fresh with every relayer signature, machines drop repeats
+    pub relayer_nonce: Option<u64>,
+    /// how the relayer fans the message out; unicast when missing
+    pub delivery: Option<Delivery>,
+    /// set on messages the relayer produces to report back to a node
+    pub control: Option<ControlEvent>,
+    /// pairs a request with its response
+    pub correlation: Option<Correlation>,
+    /// asks for, or reports, how far a message got on its target
+    pub ack: Option<Ack>,
+    /// set on at-least-once messages, the same on every resend
+    pub msg_id: Option<u64>,
+    /// when the message goes stale, or that it did
+    pub expiry: Option<Expiry>,
+    /// send lane; see `Message::get_priority` when missing
+    pub priority: Option<Priority>,
+    /// the relayer holds the message until then (unix millis)
+    pub deliver_at: Option<u64>,
+}
+
+/// Send lanes, lowest first. Send loops give higher lanes more turns.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Bulk,
+    #[default]
+    Normal,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    pub const COUNT: usize = 4;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// nobody delivers the message after `deadline_ms` (unix millis); with
+    /// `notify` the sender hears about it
+    Deadline { deadline_ms: u64, notify: bool },
+    /// notice to the sender that its message expired on the way
+    Expired,
+}
+
+/// How far a message got on its target machine.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AckLevel {
+    /// the target machine received it
+    Delivered,
+    /// the target node's handler ran on it
+    Handled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ack {
+    /// the sender wants a status event for every level up to `level`
+    Wanted { id: u64, level: AckLevel },
+    /// status event: message `id` reached `level`
+    Reached { id: u64, level: AckLevel },
+}
+
+/// Marks a message as one half of a request/response exchange.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Correlation {
+    /// the sender waits for a response carrying the same id
+    Request(u64),
+    /// answers the request with this id
+    Response(u64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum Delivery {
+    /// the single node `to_name` in `to_group`
+    #[default]
+    Unicast,
+    /// a copy to every registered member of `to_group`
+    Multicast {
+        exclude_sender: bool,
+        /// send the sender a `ControlEvent::DeliveryReport` afterwards
+        report: bool,
+    },
+    /// a copy to every registered node of every group; the sender must be
+    /// allowed to broadcast by the relayer
+    Broadcast { exclude_sender: bool, report: bool },
+    /// one healthy member of `to_group`, chosen by the relayer
+    Anycast(AnycastStrategy),
+    /// the member of `to_group` that owns `key`; every message with the same
+    /// key reaches the same member while the group doesn't change
+    Partitioned { key: String },
+    /// every node subscribed to a pattern matching `topic`
+    Publish { topic: String },
+    /// not delivered, the relayer adds `pattern` to the sender's subscriptions
+    Subscribe { pattern: String },
+    /// not delivered, the relayer removes `pattern` from the sender's subscriptions
+    Unsubscribe { pattern: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AnycastStrategy {
+    RoundRobin,
+    /// the member with the fewest messages not yet written to it
+    LeastInFlight,
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeliveryResult {
+    /// `name@group` of the member, or the id of the peer relayer it went to
+    pub target: String,
+    pub error: Option<String>,
+}
+
+/// First frames on a relayer connection to a node: the node challenges, and
+/// the relayer answers with its signature over `relayer_auth_data`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RelayerAuth {
+    Challenge { nonce: u64 },
+    Answer { sig: Vec<u8> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ControlEvent {
+    /// outcome of a fanned out message, one entry per recipient
+    DeliveryReport(Vec<DeliveryResult>),
 }
 pub trait Message {
     fn set_error_msg(&mut self, error_msg: Box<String>);
 
     fn get_signature(&self) -> Option<&Vec<u8>>;
+
+    /// Size in bytes of the user payload, checked against the relayer limit.
+    fn get_payload_len(&self) -> usize;
+
+    /// Whether the message claims to have been produced by the relayer
+    /// (error replies and control messages) rather than by a node.
+    fn is_from_relayer(&self) -> bool;
+
+    fn get_relayer_sig(&self) -> Option<&Vec<u8>>;
+
+    fn set_relayer_sig(&mut self, sig: Vec<u8>);
+
+    fn get_relayer_nonce(&self) -> Option<u64>;
+
+    fn set_relayer_nonce(&mut self, nonce: u64);
+
+    /// The content covered by the relayer signature: the target id, the
+    /// nonce and everything but the signatures.
+    fn get_relayer_sign_data(&self) -> String;
+
+    fn get_delivery(&self) -> Delivery;
+
+    /// Point a fanned out copy at one of its recipients.
+    fn set_target(&mut self, name: &str, group: &str);
+
+    fn set_control(&mut self, control: ControlEvent);
+
+    /// Past its deadline. Every hop drops expired messages.
+    fn is_expired(&self) -> bool;
+
+    fn wants_expiry_notice(&self) -> bool;
+
+    /// Turn the message into the notice its sender gets when it expired.
+    fn set_expired(&mut self);
+
+    /// Lane the message is sent in.
+    fn get_priority(&self) -> Priority;
+
+    /// When the relayer should deliver the message, unix millis. Right away
+    /// when missing or past.
+    fn get_deliver_at(&self) -> Option<u64>;
 }
 
 pub trait Router<ID>
@@ -26,6 +193,7 @@ where
 {
     fn get_source_id(&self) -> ID;
     fn get_target_id(&self) -> ID;
+    fn get_target_group(&self) -> String;
 
     fn get_source_stream<'a>(
         &self,
@@ -42,15 +210,17 @@ where
     }
 }
 
-impl Router<String> for BridgeMessage {
-    fn get_source_id(&self) -> String {
-        let ans: String = self.from_name.to_string() + &(self.from_group.to_string());
-        ans
+impl Router<NodeId> for BridgeMessage {
+    fn get_source_id(&self) -> NodeId {
+        NodeId::unchecked(&self.from_name, &self.from_group)
+    }
+
+    fn get_target_id(&self) -> NodeId {
+        NodeId::unchecked(&self.to_name, &self.to_group)
     }
 
-    fn get_target_id(&self) -> String {
-        let ans: String = self.to_name.to_string() + &(self.to_group.to_string());
-        ans
+    fn get_target_group(&self) -> String {
+        self.to_group.to_string()
     }
 }
 
@@ -62,4 +232,98 @@ impl Message for BridgeMessage {
     fn get_signature(&self) -> Option<&Vec<u8>> {
         self.sig.as_ref()
     }
+
+    fn get_payload_len(&self) -> usize {
+        self.message.len()
+    }
+
+    fn is_from_relayer(&self) -> bool {
+        self.error_msg.is_some() || self.control.is_some() || self.relayer_sig.is_some()
+    }
+
+    fn get_relayer_sig(&self) -> Option<&Vec<u8>> {
+        self.relayer_sig.as_ref()
+    }
+
+    fn set_relayer_sig(&mut self, sig: Vec<u8>) {
+        self.relayer_sig = Some(sig);
+    }
+
+    fn get_relayer_nonce(&self) -> Option<u64> {
+        self.relayer_nonce
+    }
+
+    fn set_relayer_nonce(&mut self, nonce: u64) {
+        self.relayer_nonce = Some(nonce);
+    }
+
+    fn get_relayer_sign_data(&self) -> String {
+        serde_json::to_string(&(
+            self.get_target_id().to_string(),
+            &self.relayer_nonce,
+            &self.from_name,
+            &self.from_group,
+            &self.to_name,
+            &self.to_group,
+            &self.message,
+            &self.error_msg,
+            &self.delivery,
+            &self.control,
+            &self.correlation,
+            &self.ack,
+            &self.msg_id,
+            &self.expiry,
+            &self.priority,
+            &self.deliver_at,
+        ))
+        .unwrap_or_default()
+    }
+
+    fn get_delivery(&self) -> Delivery {
+        self.delivery.clone().unwrap_or_default()
+    }
+
+    fn set_target(&mut self, name: &str, group: &str) {
+        *self.to_name = name.to_string();
+        *self.to_group = group.to_string();
+    }
+
+    fn set_control(&mut self, control: ControlEvent) {
+        self.control = Some(control);
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.expiry {
+            Some(Expiry::Deadline { deadline_ms, .. }) => crate::now_millis() > deadline_ms,
+            _ => false,
+        }
+    }
+
+    fn wants_expiry_notice(&self) -> bool {
+        matches!(self.expiry, Some(Expiry::Deadline { notify: true, .. }))
+    }
+
+    fn set_expired(&mut self) {
+        self.expiry = Some(Expiry::Expired);
+    }
+
+    /// Unless set explicitly, control events, acks and notices of the relayer
+    /// go ahead of ordinary messages.
+    fn get_priority(&self) -> Priority {
+        match self.priority {
+            Some(priority) => priority,
+            None if self.control.is_some()
+                || self.error_msg.is_some()
+                || matches!(self.ack, Some(Ack::Reached { .. }))
+                || self.expiry == Some(Expiry::Expired) =>
+            {
+                Priority::High
+            }
+            None => Priority::Normal,
+        }
+    }
+
+    fn get_deliver_at(&self) -> Option<u64> {
+        self.deliver_at
+    }
 }
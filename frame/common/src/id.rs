@@ -0,0 +1,113 @@
+//! Node ids.
+//!
+//! A node is addressed by its name within its group. Both parts are limited
+//! to letters, digits, `_` and `-`, so the text form `name@group` can't be
+//! read two ways and keys any table. Ids used to be the two parts run
+//! together, which made `A1`+`B` and `A`+`1B` the same node; `legacy` still
+//! gives that form for relayers that take it from old nodes, which they only
+//! do when told to.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Longest name or group, in bytes.
+pub const MAX_PART_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct NodeId {
+    name: String,
+    group: String,
+}
+
+impl NodeId {
+    pub fn new(name: &str, group: &str) -> Result<NodeId, String> {
+        let id = NodeId::unchecked(name, group);
+        id.validate()?;
+        Ok(id)
+    }
+
+    /// As a message claims it; `validate` tells whether a node could have it.
+    pub(crate) fn unchecked(name: &str, group: &str) -> NodeId {
+        NodeId {
+            name: name.to_string(),
+            group: group.to_string(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        check_part("name", &self.name)?;
+        check_part("group", &self.group)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    /// The id as written before it had a structure: name and group run
+    /// together.
+    pub fn legacy(&self) -> String {
+        self.name.clone() + &self.group
+    }
+}
+
+fn check_part(kind: &str, part: &str) -> Result<(), String> {
+    if part.is_empty() || part.len() > MAX_PART_LEN {
+        return Err(format!(
+            "node {} must have 1 to {} bytes,{}={:?}",
+            kind, MAX_PART_LEN, kind, part
+        ));
+    }
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if !part.chars().all(valid) {
+        return Err(format!(
+            "node {} may only hold letters, digits, '_' and '-',{}={:?}",
+            kind, kind, part
+        ));
+    }
+    Ok(())
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.name, self.group)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<NodeId, String> {
+        let (name, group) = text
+            .split_once('@')
+            .ok_or_else(|| format!("node id {:?} is not name@group", text))?;
+        NodeId::new(name, group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_id() {
+        let a1_b = NodeId::new("A1", "B").unwrap();
+        let a_1b = NodeId::new("A", "1B").unwrap();
+        assert_ne!(a1_b, a_1b);
+        assert_ne!(a1_b.to_string(), a_1b.to_string());
+        // what used to collide
+        assert_eq!(a1_b.legacy(), a_1b.legacy());
+
+        assert_eq!(a1_b, "A1@B".parse().unwrap());
+        assert!("A1B".parse::<NodeId>().is_err());
+        assert!("A@1@B".parse::<NodeId>().is_err());
+        assert!(NodeId::new("", "b").is_err());
+        assert!(NodeId::new("a b", "b").is_err());
+        assert!(NodeId::new(&"a".repeat(MAX_PART_LEN + 1), "b").is_err());
+        assert!(NodeId::new("node_1-x", "b").is_ok());
+    }
+}
@@ -0,0 +1,112 @@
+//! Priority lanes of a send loop.
+//!
+//! Every priority has its own queue. Lanes take turns by weight: while all of
+//! them are busy an urgent lane sends `weights[Urgent]` messages for every
+//! one the bulk lane sends, so urgent messages overtake bulk traffic but bulk
+//! traffic is never starved.
+
+use std::collections::VecDeque;
+
+use crate::data::Priority;
+
+/// Turns per round of each lane, indexed by `Priority as usize`.
+pub const DEFAULT_WEIGHTS: [u32; Priority::COUNT] = [1, 2, 4, 8];
+
+pub struct Lanes<T> {
+    queues: [VecDeque<T>; Priority::COUNT],
+    weights: [u32; Priority::COUNT],
+    /// turns left to each lane in the current round
+    credits: [u32; Priority::COUNT],
+    len: usize,
+}
+
+impl<T> Default for Lanes<T> {
+    fn default() -> Self {
+        Lanes::new(DEFAULT_WEIGHTS)
+    }
+}
+
+impl<T> Lanes<T> {
+    /// A weight of 0 is taken as 1, every lane gets a turn each round.
+    pub fn new(weights: [u32; Priority::COUNT]) -> Lanes<T> {
+        let weights = weights.map(|weight| weight.max(1));
+        Lanes {
+            queues: Default::default(),
+            weights,
+            credits: weights,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, priority: Priority, item: T) {
+        self.queues[priority as usize].push_back(item);
+        self.len += 1;
+    }
+
+    /// Next item to send: the highest lane with items and turns left. A new
+    /// round starts once no lane with items has turns left.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        for _ in 0..2 {
+            for lane in (0..Priority::COUNT).rev() {
+                if self.credits[lane] == 0 || self.queues[lane].is_empty() {
+                    continue;
+                }
+                self.credits[lane] -= 1;
+                self.len -= 1;
+                return self.queues[lane].pop_front();
+            }
+            self.credits = self.weights;
+        }
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_lanes() {
+        let mut lanes = Lanes::default();
+        for index in 0..20 {
+            lanes.push(Priority::Bulk, format!("bulk{}", index));
+        }
+        lanes.push(Priority::Normal, "normal".to_string());
+        for index in 0..20 {
+            lanes.push(Priority::Urgent, format!("urgent{}", index));
+        }
+        // urgent first, but every lane gets its turn within a round
+        let round: Vec<String> = (0..10).map(|_| lanes.pop().unwrap()).collect();
+        assert_eq!(
+            vec![
+                "urgent0", "urgent1", "urgent2", "urgent3", "urgent4", "urgent5", "urgent6",
+                "urgent7", "normal", "bulk0"
+            ],
+            round
+        );
+        assert_eq!("urgent8", lanes.pop().unwrap());
+        assert_eq!(30, lanes.len());
+
+        let mut left = Vec::new();
+        while let Some(item) = lanes.pop() {
+            left.push(item);
+        }
+        assert_eq!(30, left.len());
+        assert!(lanes.is_empty());
+        // within a lane the order is kept
+        let bulk: Vec<&String> = left.iter().filter(|item| item.starts_with("bulk")).collect();
+        assert_eq!("bulk1", bulk[0].as_str());
+        assert_eq!("bulk19", bulk[18].as_str());
+    }
+}
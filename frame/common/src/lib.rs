@@ -1,4 +1,7 @@
-use std::iter::repeat;
+use std::{
+    iter::repeat,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use crypto::{digest::Digest, sha2::Sha256};
 use log::error;
@@ -7,12 +10,25 @@ use rsa::{Hash, PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::runtime::Runtime;
 
+use crate::data::Message;
+
+pub mod codec;
 pub mod data;
+pub mod id;
+pub mod lane;
 
 pub fn get_runtime() -> Runtime {
     tokio::runtime::Runtime::new().unwrap()
 }
 
+/// Wall clock in unix millis, the unit of message deadlines.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 pub fn get_rsa() -> Result<(RsaPrivateKey, RsaPublicKey), String> {
     let mut rng = OsRng;
     let bits = 2048;
@@ -40,6 +56,26 @@ pub fn verify(data: &str, public_key: &RsaPublicKey, sig: &Vec<u8>) -> Result<()
         .map_err(|_| "verify failed".to_string())
 }
 
+/// What the relayer signs to prove its key to the node listening on `addr`
+/// that sent `nonce`.
+pub fn relayer_auth_data(nonce: u64, addr: &str) -> String {
+    format!("relayer:{}:{}", nonce, addr)
+}
+
+/// Sign a message the relayer produced itself, so machines can tell it apart
+/// from one a node forged. A fresh nonce lets them tell a replay apart too.
+pub fn relayer_sign<T: Message>(item: &mut T, private_key: &RsaPrivateKey) -> Result<(), String> {
+    item.set_relayer_nonce(rand::random());
+    let sig = sign(&item.get_relayer_sign_data(), private_key)?;
+    item.set_relayer_sig(sig);
+    Ok(())
+}
+
+pub fn relayer_verify<T: Message>(item: &T, public_key: &RsaPublicKey) -> Result<(), String> {
+    let sig = item.get_relayer_sig().ok_or("miss relayer signature")?;
+    verify(&item.get_relayer_sign_data(), public_key, sig)
+}
+
 pub fn get_hash(data: &str) -> Vec<u8> {
     let mut hasher = Sha256::new();
     hasher.input_str(data);
@@ -48,6 +84,18 @@ pub fn get_hash(data: &str) -> Vec<u8> {
     buf
 }
 
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parse one frame, as split by `codec::FrameDecoder`.
+pub fn parse_message<T>(json: &str) -> Result<T, String>
+where
+    T: Serialize + DeserializeOwned,
+{
+    serde_json::from_str(json).map_err(|err| format!("parse json error,error={}", err))
+}
+
 pub fn parse_message_list<T>(raw_msg: &str) -> Vec<T>
 where
     T: Serialize + DeserializeOwned,
@@ -68,7 +116,10 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::{get_rsa, sign, verify};
+    use crate::{
+        data::{BridgeMessage, Expiry, Message},
+        get_rsa, now_millis, relayer_sign, relayer_verify, sign, verify,
+    };
 
     #[test]
     fn test_rsa() {
@@ -77,4 +128,47 @@ mod tests {
         let sig = sign(data, &pr).unwrap();
         assert_eq!(true, verify(data, &pu, &sig).is_ok());
     }
+
+    #[test]
+    fn test_relayer_sign() {
+        let (pr, pu) = get_rsa().unwrap();
+        let mut bmsg = BridgeMessage {
+            from_name: Box::new("a1".to_string()),
+            from_group: Box::new("a".to_string()),
+            to_name: Box::new("b1".to_string()),
+            to_group: Box::new("b".to_string()),
+            message: Box::new("hello".to_string()),
+            error_msg: Some(Box::new("can't find target".to_string())),
+            sig: None,
+            ..Default::default()
+        };
+        assert!(relayer_verify(&bmsg, &pu).is_err());
+        relayer_sign(&mut bmsg, &pr).unwrap();
+        assert!(relayer_verify(&bmsg, &pu).is_ok());
+
+        bmsg.error_msg = Some(Box::new("forged".to_string()));
+        assert!(relayer_verify(&bmsg, &pu).is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut bmsg = BridgeMessage {
+            expiry: Some(Expiry::Deadline {
+                deadline_ms: now_millis() + 60_000,
+                notify: true,
+            }),
+            ..Default::default()
+        };
+        assert!(!bmsg.is_expired());
+        bmsg.expiry = Some(Expiry::Deadline {
+            deadline_ms: now_millis() - 1,
+            notify: true,
+        });
+        assert!(bmsg.is_expired());
+        assert!(bmsg.wants_expiry_notice());
+        // the notice itself never expires
+        bmsg.set_expired();
+        assert!(!bmsg.is_expired());
+        assert!(!bmsg.wants_expiry_notice());
+    }
 }
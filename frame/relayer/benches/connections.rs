@@ -0,0 +1,175 @@
+//! Relayer throughput with many nodes connected over TCP.
+//!
+//! Every node registers with the relayer, answers nothing but its challenge,
+//! then sends to the node after it while counting what it is delivered. All
+//! nodes share one key, so the verified cache is hot and the numbers show the
+//! cost of routing and health bookkeeping across connections. Run with
+//! `cargo bench -p frame-relayer --bench connections`.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use frame_common::{
+    codec::{FrameDecoder, DELIMITER},
+    data::{BridgeMessage, RelayerAuth},
+    get_rsa, get_runtime,
+    id::NodeId,
+    parse_message, sign,
+};
+use frame_relayer::{listen_relayer_register, RegisterInfo, RelayerState};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, TcpListener},
+    sync::mpsc,
+};
+
+const NODE_COUNTS: [usize; 3] = [16, 64, 256];
+const MESSAGES_PER_NODE: usize = 200;
+const IDLE: Duration = Duration::from_secs(1);
+
+fn frame(json: String) -> Vec<u8> {
+    let mut frame = json;
+    frame.push_str(DELIMITER);
+    frame.into_bytes()
+}
+
+/// Count delivered frames on `reader` until it goes quiet for `IDLE`.
+async fn count_delivered(
+    mut reader: OwnedReadHalf,
+    mut decoder: FrameDecoder,
+    delivered: Arc<AtomicUsize>,
+    idle: Arc<AtomicUsize>,
+) {
+    let mut buf = [0; 8192];
+    loop {
+        let size = match tokio::time::timeout(IDLE, reader.read(&mut buf)).await {
+            Ok(Ok(size)) if size > 0 => size,
+            _ => {
+                idle.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        let frames = decoder.push(&buf[..size]).len();
+        delivered.fetch_add(frames, Ordering::Relaxed);
+    }
+}
+
+fn main() {
+    let rt = get_runtime();
+    let (relayer_key, _) = get_rsa().unwrap();
+    let (node_pr, node_pu) = get_rsa().unwrap();
+
+    for nodes in NODE_COUNTS {
+        let state: RelayerState<BridgeMessage> = RelayerState::new(relayer_key.clone());
+        let names: Vec<String> = (0..nodes).map(|i| format!("n{}", i)).collect();
+        let sigs: Vec<Vec<u8>> = names
+            .iter()
+            .map(|name| sign(&format!("{}@bench", name), &node_pr).unwrap())
+            .collect();
+
+        let (elapsed, delivered) = rt.block_on(async {
+            let (clients_tx, clients_rx) = mpsc::channel(nodes);
+            tokio::spawn(listen_relayer_register(clients_rx, state.clone()));
+
+            // every node listens, challenges the relayer and keeps its link
+            let mut links = Vec::new();
+            for name in &names {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let id = NodeId::new(name, "bench").unwrap();
+                state.pub_keys.insert(id, node_pu.clone());
+                clients_tx
+                    .send(RegisterInfo {
+                        addr: Box::new(listener.local_addr().unwrap().to_string()),
+                        name: Box::new(name.clone()),
+                        group: Box::new("bench".to_string()),
+                    })
+                    .await
+                    .map_err(|_| "relayer stopped taking registrations")
+                    .unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let (mut reader, mut writer) = stream.into_split();
+                let challenge =
+                    serde_json::to_string(&RelayerAuth::Challenge { nonce: 0 }).unwrap();
+                writer.write_all(&frame(challenge)).await.unwrap();
+                let mut decoder = FrameDecoder::new(1 << 20);
+                let mut buf = [0; 4096];
+                loop {
+                    let size = reader.read(&mut buf).await.unwrap();
+                    assert!(size > 0, "relayer closed during the handshake");
+                    let mut frames = decoder.push(&buf[..size]);
+                    if !frames.is_empty() {
+                        let answer: RelayerAuth =
+                            parse_message(&frames.remove(0).unwrap()).unwrap();
+                        assert!(matches!(answer, RelayerAuth::Answer { .. }));
+                        break;
+                    }
+                }
+                links.push((reader, writer, decoder));
+            }
+            while state.route_table.len() < nodes {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            let delivered = Arc::new(AtomicUsize::new(0));
+            let idle = Arc::new(AtomicUsize::new(0));
+            let start = Instant::now();
+            let mut writers = Vec::new();
+            for (i, (reader, mut writer, decoder)) in links.into_iter().enumerate() {
+                tokio::spawn(count_delivered(
+                    reader,
+                    decoder,
+                    delivered.clone(),
+                    idle.clone(),
+                ));
+                let from = names[i].clone();
+                let to = names[(i + 1) % nodes].clone();
+                let sig = sigs[i].clone();
+                writers.push(tokio::spawn(async move {
+                    for seq in 0..MESSAGES_PER_NODE {
+                        let bmsg = BridgeMessage {
+                            from_name: Box::new(from.clone()),
+                            from_group: Box::new("bench".to_string()),
+                            to_name: Box::new(to.clone()),
+                            to_group: Box::new("bench".to_string()),
+                            message: Box::new(seq.to_string()),
+                            error_msg: None,
+                            sig: Some(sig.clone()),
+                            ..Default::default()
+                        };
+                        let json = serde_json::to_string(&bmsg).unwrap();
+                        writer.write_all(&frame(json)).await.unwrap();
+                    }
+                    writer
+                }));
+            }
+            // writers are kept open so the relayer doesn't drop their routes
+            let mut open = Vec::new();
+            for writer in writers {
+                open.push(writer.await.unwrap());
+            }
+            let total = nodes * MESSAGES_PER_NODE;
+            while delivered.load(Ordering::Relaxed) < total && idle.load(Ordering::Relaxed) < nodes
+            {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            let elapsed = start.elapsed();
+            drop(open);
+            (elapsed, delivered.load(Ordering::Relaxed))
+        });
+
+        let total = nodes * MESSAGES_PER_NODE;
+        println!(
+            "nodes={:>4} sent={} delivered={} elapsed={:?} throughput={:.0} msg/s",
+            nodes,
+            total,
+            delivered,
+            elapsed,
+            delivered as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
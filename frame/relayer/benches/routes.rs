@@ -0,0 +1,126 @@
+//! Route lookups per second with many connections, for one locked map and
+//! for the sharded route table.
+//!
+//! Every core looks routes up while one more thread keeps registering and
+//! removing connections. Run with `cargo bench -p frame-relayer --bench routes`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use frame_common::id::NodeId;
+use frame_relayer::routes::Routes;
+use tokio::sync::broadcast;
+
+const LOOKUPS_PER_THREAD: usize = 200_000;
+
+type Sender = broadcast::Sender<u64>;
+
+trait Table: Send + Sync + 'static {
+    fn get(&self, id: &NodeId) -> Option<Sender>;
+    fn insert(&self, id: NodeId, sender: Sender);
+    fn remove(&self, id: &NodeId);
+}
+
+impl Table for Mutex<HashMap<NodeId, Sender>> {
+    fn get(&self, id: &NodeId) -> Option<Sender> {
+        self.lock().unwrap().get(id).cloned()
+    }
+
+    fn insert(&self, id: NodeId, sender: Sender) {
+        self.lock().unwrap().insert(id, sender);
+    }
+
+    fn remove(&self, id: &NodeId) {
+        self.lock().unwrap().remove(id);
+    }
+}
+
+impl Table for Routes<Sender> {
+    fn get(&self, id: &NodeId) -> Option<Sender> {
+        Routes::get(self, id)
+    }
+
+    fn insert(&self, id: NodeId, sender: Sender) {
+        Routes::insert(self, id, sender);
+    }
+
+    fn remove(&self, id: &NodeId) {
+        Routes::remove(self, id);
+    }
+}
+
+fn main() {
+    let cores = thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1);
+    for connections in [100, 1_000, 10_000] {
+        let ids: Arc<Vec<NodeId>> = Arc::new(
+            (0..connections)
+                .map(|index| NodeId::new(&format!("n{}", index), "bench").unwrap())
+                .collect(),
+        );
+        run("mutex", Arc::new(Mutex::new(HashMap::new())), &ids, cores);
+        run("sharded", Arc::new(Routes::default()), &ids, cores);
+    }
+}
+
+fn run<R: Table>(name: &str, table: Arc<R>, ids: &Arc<Vec<NodeId>>, cores: usize) {
+    let (sender, _receiver) = broadcast::channel(1);
+    for id in ids.iter() {
+        table.insert(id.clone(), sender.clone());
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    // registrations and removals going on meanwhile
+    let churn = {
+        let (table, ids, stop, sender) = (table.clone(), ids.clone(), stop.clone(), sender.clone());
+        thread::spawn(move || {
+            let mut index = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let id = &ids[index % ids.len()];
+                table.remove(id);
+                table.insert(id.clone(), sender.clone());
+                index += 1;
+            }
+        })
+    };
+
+    let start = Instant::now();
+    let lookups: Vec<_> = (0..cores)
+        .map(|thread_index| {
+            let (table, ids) = (table.clone(), ids.clone());
+            thread::spawn(move || {
+                let mut found = 0;
+                for index in 0..LOOKUPS_PER_THREAD {
+                    let id = &ids[(index * 31 + thread_index) % ids.len()];
+                    if table.get(id).is_some() {
+                        found += 1;
+                    }
+                }
+                found
+            })
+        })
+        .collect();
+    for lookup in lookups {
+        lookup.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    churn.join().unwrap();
+
+    let total = cores * LOOKUPS_PER_THREAD;
+    println!(
+        "table={:<8} connections={:>6} threads={} elapsed={:?} throughput={:.0} lookups/s",
+        name,
+        ids.len(),
+        cores,
+        elapsed,
+        total as f64 / elapsed.as_secs_f64()
+    );
+}
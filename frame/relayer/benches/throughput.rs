@@ -0,0 +1,87 @@
+//! Relayer throughput with 1..=cores signature workers.
+//!
+//! Every source signs with its own key and the verified cache is off, so each
+//! message costs one full RSA check. Run with `cargo bench -p frame-relayer`.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use frame_common::{data::BridgeMessage, get_rsa, get_runtime, id::NodeId, sign};
+use frame_relayer::{dispatch_msg, verify::Verifier, RelayerState};
+use tokio::sync::broadcast;
+
+const SOURCES: usize = 32;
+const MESSAGES_PER_SOURCE: usize = 200;
+
+fn main() {
+    let rt = get_runtime();
+    let (relayer_key, _) = get_rsa().unwrap();
+    let cores = std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1);
+
+    let mut sources = Vec::new();
+    for i in 0..SOURCES {
+        let (pr, pu) = get_rsa().unwrap();
+        let name = format!("n{}", i);
+        let sig = sign(&format!("{}@bench", name), &pr).unwrap();
+        sources.push((name, pu, sig));
+    }
+
+    let mut workers = 1;
+    while workers <= cores {
+        let mut state: RelayerState<BridgeMessage> = RelayerState::new(relayer_key.clone());
+        state.verifier = Arc::new(Verifier::new(workers, 0, Duration::from_secs(0)));
+        // keep one receiver alive per route so sends succeed; lagging is fine
+        let mut receivers = Vec::new();
+        for (name, pu, _) in &sources {
+            let (tx, rx) = broadcast::channel(16);
+            let id = NodeId::new(name, "bench").unwrap();
+            state.route_table.insert(id.clone(), tx);
+            state.pub_keys.insert(id, pu.clone());
+            receivers.push(rx);
+        }
+
+        let start = Instant::now();
+        rt.block_on(async {
+            let mut tasks = Vec::new();
+            for (name, _, sig) in &sources {
+                let state = state.clone();
+                let name = name.clone();
+                let sig = sig.clone();
+                tasks.push(tokio::spawn(async move {
+                    let identity = NodeId::new(&name, "bench").unwrap();
+                    for seq in 0..MESSAGES_PER_SOURCE {
+                        let bmsg = BridgeMessage {
+                            from_name: Box::new(name.clone()),
+                            from_group: Box::new("bench".to_string()),
+                            to_name: Box::new(name.clone()),
+                            to_group: Box::new("bench".to_string()),
+                            message: Box::new(seq.to_string()),
+                            error_msg: None,
+                            sig: Some(sig.clone()),
+                            ..Default::default()
+                        };
+                        dispatch_msg(&state, &identity, bmsg).await.unwrap();
+                    }
+                }));
+            }
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+        let elapsed = start.elapsed();
+        let total = SOURCES * MESSAGES_PER_SOURCE;
+        println!(
+            "workers={:>3} messages={} elapsed={:?} throughput={:.0} msg/s",
+            workers,
+            total,
+            elapsed,
+            total as f64 / elapsed.as_secs_f64()
+        );
+        drop(receivers);
+        workers *= 2;
+    }
+}
@@ -0,0 +1,374 @@
+//! Append-only, hash-chained audit log of everything the relayer routed.
+//!
+//! Each line of the log is one JSON [`AuditEntry`]. The entry hash covers the
+//! record and the hash of the entry before it, so editing any line breaks the
+//! chain from that point on. A small head file next to the log keeps the
+//! sequence number and hash of the last entry, signed with the relayer's
+//! identity key, which is what lets [`verify_audit_log`] notice lines cut off
+//! the end or a chain computed again by someone without the key.
+//!
+//! Routing only chains entries in memory. Every `SYNC_INTERVAL` a thread of
+//! the log's own writes them out, syncs the log and signs the head once for
+//! all entries since the last time, so no file access happens while a message
+//! is routed. Entries past the signed head are cut off when the log is opened
+//! again. A log and head copied away earlier still verify
+//! together, so keep `AuditLog::head` somewhere else to rule that out.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use frame_common::{
+    data::{Message, Router},
+    get_hash,
+    id::NodeId,
+    sign, to_hex, verify,
+};
+use log::{error, info};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+
+pub type AuditTable = Arc<AuditLog>;
+
+/// How often new entries are written, synced and the head signed.
+pub const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AuditDecision {
+    /// handed to the target's send channel
+    Delivered,
+    /// target unknown, sent back to the source with an error
+    Bounced,
+    /// copied to several targets
+    Fanout { delivered: usize, total: usize },
+    /// dropped by the relayer, with the reason
+    Rejected(String),
+    /// consumed by the relayer itself, like a subscription change
+    Accepted,
+    /// queued until the offline target connects again
+    Parked,
+    /// held until its delivery time
+    Scheduled,
+    /// handed to the link of this peer relayer
+    Forwarded(String),
+}
+
+/// What the relayer knows about a message when it routes it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub timestamp: u128,
+    pub connection: String,
+    pub source: String,
+    pub target: String,
+    pub digest: String,
+    pub sig: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn from_message<T>(message: &T, connection: &str) -> AuditRecord
+    where
+        T: Serialize + Router<NodeId> + Message,
+    {
+        let digest = serde_json::to_string(message)
+            .map(|serialized| to_hex(&get_hash(&serialized)))
+            .unwrap_or_default();
+        AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis())
+                .unwrap_or_default(),
+            connection: connection.to_string(),
+            source: message.get_source_id().to_string(),
+            target: message.get_target_id().to_string(),
+            digest,
+            sig: message.get_signature().map(|sig| to_hex(sig)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub record: AuditRecord,
+    pub decision: AuditDecision,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(
+        seq: u64,
+        record: &AuditRecord,
+        decision: &AuditDecision,
+        prev_hash: &str,
+    ) -> Result<String, String> {
+        let body = serde_json::to_string(&(seq, record, decision, prev_hash))
+            .map_err(|err| err.to_string())?;
+        Ok(to_hex(&get_hash(&body)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct AuditHead {
+    count: u64,
+    hash: String,
+    /// relayer signature over `head_data`
+    sig: Vec<u8>,
+}
+
+fn head_data(count: u64, hash: &str) -> String {
+    format!("audit{}{}", count, hash)
+}
+
+/// Last entry chained, not necessarily written yet.
+struct Tail {
+    count: u64,
+    hash: String,
+    /// length of the log up to and including it
+    len: u64,
+    /// lines of the entries not written yet, in order
+    unwritten: Vec<u8>,
+}
+
+pub struct AuditLog {
+    file: File,
+    head_path: PathBuf,
+    key: RsaPrivateKey,
+    tail: Mutex<Tail>,
+    /// entries covered by the signed head
+    synced: Mutex<u64>,
+}
+
+impl AuditLog {
+    /// Open the log at `path`, creating it if needed. An existing log must
+    /// verify up to its signed head under `key`, otherwise new entries would
+    /// chain onto a broken history; what follows the head is cut off.
+    pub fn open(path: &str, key: RsaPrivateKey) -> Result<AuditLog, String> {
+        let head_path = head_path(Path::new(path));
+        let tail = if Path::new(path).exists() {
+            let (tail, extra) = verify_chain(path, &RsaPublicKey::from(&key))?;
+            if extra > 0 {
+                info!("audit log cut {} entries past the signed head", extra);
+            }
+            tail
+        } else {
+            Tail {
+                count: 0,
+                hash: GENESIS_HASH.to_string(),
+                len: 0,
+                unwritten: Vec::new(),
+            }
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| err.to_string())?;
+        file.set_len(tail.len).map_err(|err| err.to_string())?;
+        let (count, hash) = (tail.count, tail.hash.clone());
+        let log = AuditLog {
+            file,
+            head_path,
+            key,
+            synced: Mutex::new(tail.count),
+            tail: Mutex::new(tail),
+        };
+        log.write_head(count, &hash)?;
+        Ok(log)
+    }
+
+    /// Open the log and keep writing and syncing it every `SYNC_INTERVAL` for
+    /// as long as it is in use.
+    pub fn open_table(path: &str, key: RsaPrivateKey) -> Result<AuditTable, String> {
+        let log = Arc::new(AuditLog::open(path, key)?);
+        let weak = Arc::downgrade(&log);
+        thread::spawn(move || loop {
+            thread::sleep(SYNC_INTERVAL);
+            match weak.upgrade() {
+                Some(log) => {
+                    if let Err(error) = log.sync() {
+                        error!("audit log sync failed,error={}", error);
+                    }
+                }
+                None => break,
+            }
+        });
+        Ok(log)
+    }
+
+    /// Chain `record` onto the log. It is written with the next `sync`.
+    pub fn append(&self, record: AuditRecord, decision: AuditDecision) -> Result<(), String> {
+        let mut tail = self.tail.lock().map_err(|err| err.to_string())?;
+        let seq = tail.count;
+        let hash = AuditEntry::compute_hash(seq, &record, &decision, &tail.hash)?;
+        let entry = AuditEntry {
+            seq,
+            record,
+            decision,
+            prev_hash: tail.hash.clone(),
+            hash,
+        };
+        let mut line = serde_json::to_string(&entry).map_err(|err| err.to_string())?;
+        line.push('\n');
+        tail.count = seq + 1;
+        tail.hash = entry.hash;
+        tail.len += line.len() as u64;
+        tail.unwritten.extend_from_slice(line.as_bytes());
+        Ok(())
+    }
+
+    /// Write every entry chained so far, make them durable and sign the head
+    /// over them.
+    pub fn sync(&self) -> Result<(), String> {
+        let mut synced = self.synced.lock().map_err(|err| err.to_string())?;
+        let (count, hash, lines, written) = {
+            let mut tail = self.tail.lock().map_err(|err| err.to_string())?;
+            let lines = std::mem::take(&mut tail.unwritten);
+            let written = tail.len - lines.len() as u64;
+            (tail.count, tail.hash.clone(), lines, written)
+        };
+        if count == *synced {
+            return Ok(());
+        }
+        if let Err(error) = (&self.file).write_all(&lines) {
+            // cut what made it out and keep the lines for the next try, ahead
+            // of what came since
+            let _ = self.file.set_len(written);
+            if let Ok(mut tail) = self.tail.lock() {
+                tail.unwritten.splice(0..0, lines);
+            }
+            return Err(error.to_string());
+        }
+        self.file.sync_data().map_err(|err| err.to_string())?;
+        self.write_head(count, &hash)?;
+        *synced = count;
+        Ok(())
+    }
+
+    /// Entry count and last hash of the signed head, to keep outside the
+    /// relayer's reach.
+    pub fn head(&self) -> (u64, String) {
+        match self.tail.lock() {
+            Ok(tail) => (tail.count, tail.hash.clone()),
+            Err(_) => (0, GENESIS_HASH.to_string()),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.head().0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn write_head(&self, count: u64, hash: &str) -> Result<(), String> {
+        let head = AuditHead {
+            count,
+            hash: hash.to_string(),
+            sig: sign(&head_data(count, hash), &self.key)?,
+        };
+        let serialized = serde_json::to_string(&head).map_err(|err| err.to_string())?;
+        let tmp_path = self.head_path.with_extension("head.tmp");
+        fs::write(&tmp_path, serialized).map_err(|err| err.to_string())?;
+        fs::rename(&tmp_path, &self.head_path).map_err(|err| err.to_string())
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        if let Err(error) = self.sync() {
+            error!("audit log sync failed,error={}", error);
+        }
+    }
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut head = path.as_os_str().to_owned();
+    head.push(".head");
+    PathBuf::from(head)
+}
+
+/// Walk the whole log and check every link of the chain, then check that the
+/// chain ends where the head file signed by `key` says it does. Returns the
+/// number of entries and the last hash.
+pub fn verify_audit_log(path: &str, key: &RsaPublicKey) -> Result<(u64, String), String> {
+    match verify_chain(path, key)? {
+        (tail, 0) => Ok((tail.count, tail.hash)),
+        (tail, extra) => Err(format!(
+            "audit log has {} entries past the signed head,head={}",
+            extra, tail.count
+        )),
+    }
+}
+
+/// Check the chain and the signed head. Returns the entry the head names and
+/// how many entries follow it.
+fn verify_chain(path: &str, key: &RsaPublicKey) -> Result<(Tail, u64), String> {
+    let head = fs::read_to_string(head_path(Path::new(path)))
+        .map_err(|err| format!("audit head unreadable,error={}", err))?;
+    let head: AuditHead = serde_json::from_str(&head).map_err(|err| err.to_string())?;
+    verify(&head_data(head.count, &head.hash), key, &head.sig)
+        .map_err(|err| format!("audit head signature,error={}", err))?;
+
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut count: u64 = 0;
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut len: u64 = 0;
+    let mut extra: u64 = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|err| err.to_string())?;
+        if count >= head.count {
+            // never synced, possibly torn by a crash
+            extra += 1;
+            continue;
+        }
+        let hash = check_entry(&line, count, &prev_hash)?;
+        if count + 1 == head.count && hash != head.hash {
+            return Err(format!("audit entry {} differs from the signed head", count));
+        }
+        prev_hash = hash;
+        count += 1;
+        len += line.len() as u64 + 1;
+    }
+    if count != head.count || (count == 0 && head.hash != GENESIS_HASH) {
+        return Err(format!(
+            "audit log truncated or head mismatch,entries={},head={}",
+            count, head.count
+        ));
+    }
+    Ok((
+        Tail {
+            count,
+            hash: head.hash,
+            len,
+            unwritten: Vec::new(),
+        },
+        extra,
+    ))
+}
+
+/// Check the entry on `line` follows `prev_hash` as number `count`. Returns
+/// its hash.
+fn check_entry(line: &str, count: u64, prev_hash: &str) -> Result<String, String> {
+    let entry: AuditEntry = serde_json::from_str(line)
+        .map_err(|err| format!("audit entry {} unreadable,error={}", count, err))?;
+    if entry.seq != count {
+        return Err(format!("audit entry {} has seq {}", count, entry.seq));
+    }
+    if entry.prev_hash != prev_hash {
+        return Err(format!("audit entry {} not chained to previous", count));
+    }
+    let hash = AuditEntry::compute_hash(entry.seq, &entry.record, &entry.decision, prev_hash)?;
+    if entry.hash != hash {
+        return Err(format!("audit entry {} hash mismatch", count));
+    }
+    Ok(hash)
+}
@@ -0,0 +1,167 @@
+//! Per-member load and health, used to pick a member for anycast messages.
+//!
+//! Every message put on a member's channel counts as in flight until its
+//! `do_send` task has written it out. A member whose channel or socket failed
+//! recently is unhealthy for `cooldown` and skipped by anycast. Members sit
+//! in a sharded table and their counters are atomics, so recording a send
+//! only reads the table.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use frame_common::{data::AnycastStrategy, id::NodeId};
+use rand::Rng;
+
+use crate::routes::Routes;
+
+#[derive(Debug, Default, Clone)]
+pub struct MemberHealth {
+    pub in_flight: usize,
+    pub unhealthy_until: Option<Instant>,
+}
+
+impl MemberHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.unhealthy_until
+            .is_none_or(|until| Instant::now() >= until)
+    }
+}
+
+#[derive(Default)]
+struct Member {
+    in_flight: AtomicUsize,
+    /// millis past the balancer's epoch, 0 while healthy
+    unhealthy_until: AtomicU64,
+}
+
+pub struct Balancer {
+    cooldown: Duration,
+    epoch: Instant,
+    health: Routes<Arc<Member>>,
+    /// next round-robin position per group
+    cursors: Mutex<HashMap<String, usize>>,
+}
+
+impl Balancer {
+    pub fn new(cooldown: Duration) -> Balancer {
+        Balancer {
+            cooldown,
+            epoch: Instant::now(),
+            health: Routes::default(),
+            cursors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_sent(&self, id: &NodeId) {
+        self.member(id).in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_written(&self, id: &NodeId) {
+        let member = self.member(id);
+        take_in_flight(&member, 1);
+        member.unhealthy_until.store(0, Ordering::Relaxed);
+    }
+
+    /// `id` has a new connection, whatever failed on the one before.
+    pub fn record_connected(&self, id: &NodeId) {
+        self.member(id).unhealthy_until.store(0, Ordering::Relaxed);
+    }
+
+    /// The relayer itself dropped a message for `id`, like an expired one.
+    pub fn record_dropped(&self, id: &NodeId) {
+        take_in_flight(&self.member(id), 1);
+    }
+
+    /// `count` messages for `id` were lost, either never queued or dropped
+    /// from a lagging channel.
+    pub fn record_failure(&self, id: &NodeId, count: usize) {
+        let until = self.epoch.elapsed() + self.cooldown;
+        let member = self.member(id);
+        take_in_flight(&member, count);
+        member
+            .unhealthy_until
+            .store((until.as_millis() as u64).max(1), Ordering::Relaxed);
+    }
+
+    pub fn forget(&self, id: &NodeId) {
+        self.health.remove(id);
+    }
+
+    pub fn get(&self, id: &NodeId) -> MemberHealth {
+        let member = match self.health.get(id) {
+            Some(member) => member,
+            None => return MemberHealth::default(),
+        };
+        let until = member.unhealthy_until.load(Ordering::Relaxed);
+        MemberHealth {
+            in_flight: member.in_flight.load(Ordering::Relaxed),
+            unhealthy_until: (until > 0).then(|| self.epoch + Duration::from_millis(until)),
+        }
+    }
+
+    /// Order `candidates` (member ids of `group`, already filtered to the
+    /// connected ones) by preference under `strategy`, unhealthy members
+    /// dropped. The caller tries them in turn until one send succeeds.
+    pub fn rank(
+        &self,
+        group: &str,
+        candidates: &[NodeId],
+        strategy: &AnycastStrategy,
+    ) -> Vec<usize> {
+        let health: Vec<MemberHealth> = candidates.iter().map(|id| self.get(id)).collect();
+        let healthy: Vec<usize> = (0..candidates.len())
+            .filter(|&index| health[index].is_healthy())
+            .collect();
+        if healthy.is_empty() {
+            return healthy;
+        }
+        let start = match strategy {
+            AnycastStrategy::RoundRobin => {
+                let mut cursors = match self.cursors.lock() {
+                    Ok(cursors) => cursors,
+                    Err(_) => return healthy,
+                };
+                let cursor = cursors.entry(group.to_string()).or_insert(0);
+                let start = *cursor % healthy.len();
+                *cursor = cursor.wrapping_add(1);
+                start
+            }
+            AnycastStrategy::Random => rand::thread_rng().gen_range(0..healthy.len()),
+            AnycastStrategy::LeastInFlight => {
+                let mut ranked = healthy;
+                ranked.sort_by_key(|&index| health[index].in_flight);
+                return ranked;
+            }
+        };
+        let mut ranked = healthy;
+        ranked.rotate_left(start);
+        ranked
+    }
+
+    fn member(&self, id: &NodeId) -> Arc<Member> {
+        match self.health.get(id) {
+            Some(member) => member,
+            None => self.health.get_or_insert_with(id, Arc::default),
+        }
+    }
+}
+
+fn take_in_flight(member: &Member, count: usize) {
+    let _ = member
+        .in_flight
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_flight| {
+            Some(in_flight.saturating_sub(count))
+        });
+}
+
+impl Default for Balancer {
+    fn default() -> Self {
+        Balancer::new(Duration::from_secs(10))
+    }
+}
@@ -0,0 +1,31 @@
+use std::{env, fs, process};
+
+use frame_relayer::audit::verify_audit_log;
+use rsa::{pkcs8::FromPublicKey, RsaPublicKey};
+
+fn main() {
+    let (path, key_path) = match (env::args().nth(1), env::args().nth(2)) {
+        (Some(path), Some(key_path)) => (path, key_path),
+        _ => {
+            eprintln!("usage: audit_verify <audit log path> <relayer public key pem path>");
+            process::exit(2);
+        }
+    };
+    let key = match fs::read_to_string(&key_path)
+        .map_err(|err| err.to_string())
+        .and_then(|pem| RsaPublicKey::from_public_key_pem(&pem).map_err(|err| err.to_string()))
+    {
+        Ok(key) => key,
+        Err(error) => {
+            eprintln!("relayer public key unreadable, error={}", error);
+            process::exit(2);
+        }
+    };
+    match verify_audit_log(&path, &key) {
+        Ok((count, hash)) => println!("audit log ok, entries={}, last hash={}", count, hash),
+        Err(error) => {
+            eprintln!("audit log verify failed, error={}", error);
+            process::exit(1);
+        }
+    }
+}
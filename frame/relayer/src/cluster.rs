@@ -0,0 +1,327 @@
+//! Relayer instances replicating their registration and key tables.
+//!
+//! Every instance of a cluster lists the same instances in the same order.
+//! Followers link to the leader, get every registration it has and the nodes
+//! moving to another machine, then each change as it happens. When the
+//! leader's heartbeats stop, the first listed instance that is still up takes
+//! over; an instance coming back follows the current leader instead of taking
+//! over again. Only the leader connects to machines: on taking over it
+//! connects to every registered node, and the machines take the new
+//! connection in place of the lost one.
+//!
+//! A leader keeps looking for a leader listed before it, as one may lead too
+//! after missed heartbeats. Once found it steps down: it follows that one,
+//! hands it the registrations it has, and closes its own connections, while
+//! the leader connects to the nodes handed over.
+//!
+//! Instances must share one identity key, which also proves membership on the
+//! links between them. Start them in the listed order.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use frame_common::id::NodeId;
+use rsa::{
+    pkcs8::{FromPublicKey, ToPublicKey},
+    RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+
+use crate::RegisterInfo;
+
+/// A follower takes over after this many heartbeat intervals of silence.
+pub const SILENT_AFTER: u32 = 3;
+
+/// A registered node as the cluster replicates it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Registration {
+    pub name: String,
+    pub group: String,
+    pub addr: String,
+    /// public key of the node, PEM
+    pub key: String,
+}
+
+impl Registration {
+    pub fn new(register_info: &RegisterInfo, key: &RsaPublicKey) -> Result<Registration, String> {
+        Ok(Registration {
+            name: register_info.name.to_string(),
+            group: register_info.group.to_string(),
+            addr: register_info.addr.to_string(),
+            key: key.to_public_key_pem().map_err(|err| err.to_string())?,
+        })
+    }
+
+    pub fn id(&self) -> Result<NodeId, String> {
+        NodeId::new(&self.name, &self.group)
+    }
+
+    pub fn public_key(&self) -> Result<RsaPublicKey, String> {
+        RsaPublicKey::from_public_key_pem(&self.key).map_err(|err| err.to_string())
+    }
+
+    pub fn register_info(&self) -> RegisterInfo {
+        RegisterInfo {
+            addr: Box::new(self.addr.clone()),
+            name: Box::new(self.name.clone()),
+            group: Box::new(self.group.clone()),
+        }
+    }
+}
+
+/// What travels on a link between two instances.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClusterFrame {
+    /// first frame from the instance that accepted the link
+    Challenge {
+        nonce: u64,
+    },
+    /// signature over the nonce and the own instance id, with the shared key
+    Follow {
+        instance_id: String,
+        sig: Vec<u8>,
+    },
+    NotLeader,
+    /// the follow was taken, registrations come next
+    Leading,
+    Register(Registration),
+    Unregister(NodeId),
+    /// the node began moving to another machine
+    Moving(NodeId),
+    /// the node is done moving, back or not
+    Moved(NodeId),
+    Heartbeat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    /// looking for the leader
+    Candidate,
+    Leader,
+    Follower(String),
+}
+
+pub struct Cluster {
+    pub instance_id: String,
+    /// (instance id, cluster address) of every instance, in takeover order
+    pub members: Vec<(String, String)>,
+    pub heartbeat_interval: Duration,
+    role: Mutex<Role>,
+    registrations: Mutex<BTreeMap<NodeId, Registration>>,
+    /// registered nodes moving to another machine
+    moving: Mutex<BTreeSet<NodeId>>,
+    followers: Mutex<Vec<Sender<ClusterFrame>>>,
+}
+
+impl Cluster {
+    pub fn new(instance_id: &str, members: Vec<(String, String)>) -> Cluster {
+        Cluster {
+            instance_id: instance_id.to_string(),
+            members,
+            heartbeat_interval: Duration::from_secs(1),
+            role: Mutex::new(Role::Candidate),
+            registrations: Mutex::new(BTreeMap::new()),
+            moving: Mutex::new(BTreeSet::new()),
+            followers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Where this instance listens for the others.
+    pub fn own_addr(&self) -> Option<&str> {
+        self.members
+            .iter()
+            .find(|(id, _)| *id == self.instance_id)
+            .map(|(_, addr)| addr.as_str())
+    }
+
+    /// `instance_id` is listed before this instance, so it takes over first.
+    pub fn ranks_before(&self, instance_id: &str) -> bool {
+        let position = |wanted: &str| self.members.iter().position(|(id, _)| id == wanted);
+        match (position(instance_id), position(&self.instance_id)) {
+            (Some(other), Some(own)) => other < own,
+            _ => false,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+            .lock()
+            .map(|role| role.clone())
+            .unwrap_or(Role::Candidate)
+    }
+
+    pub fn set_role(&self, role: Role) {
+        if let Ok(mut current) = self.role.lock() {
+            *current = role;
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role() == Role::Leader
+    }
+
+    /// Id of the leading instance, if known.
+    pub fn leader(&self) -> Option<String> {
+        match self.role() {
+            Role::Leader => Some(self.instance_id.clone()),
+            Role::Follower(leader) => Some(leader),
+            Role::Candidate => None,
+        }
+    }
+
+    /// Keep `registration` and pass it on to the followers.
+    pub fn record(&self, registration: Registration) -> Result<(), String> {
+        let mut registrations = self.registrations.lock().map_err(|err| err.to_string())?;
+        registrations.insert(registration.id()?, registration.clone());
+        // still under the registrations lock, so followers get changes in order
+        self.send_followers(ClusterFrame::Register(registration));
+        Ok(())
+    }
+
+    /// Drop the registration of `id` here and on the followers. A move of it
+    /// ends too.
+    pub fn forget(&self, id: &NodeId) -> Result<(), String> {
+        let mut registrations = self.registrations.lock().map_err(|err| err.to_string())?;
+        registrations.remove(id);
+        self.moving
+            .lock()
+            .map_err(|err| err.to_string())?
+            .remove(id);
+        self.send_followers(ClusterFrame::Unregister(id.clone()));
+        Ok(())
+    }
+
+    /// `id` moves to another machine, here and on the followers.
+    pub fn begin_move(&self, id: &NodeId) -> Result<(), String> {
+        let _registrations = self.registrations.lock().map_err(|err| err.to_string())?;
+        self.moving
+            .lock()
+            .map_err(|err| err.to_string())?
+            .insert(id.clone());
+        self.send_followers(ClusterFrame::Moving(id.clone()));
+        Ok(())
+    }
+
+    /// `id` is done moving, here and on the followers.
+    pub fn end_move(&self, id: &NodeId) -> Result<(), String> {
+        let _registrations = self.registrations.lock().map_err(|err| err.to_string())?;
+        self.moving
+            .lock()
+            .map_err(|err| err.to_string())?
+            .remove(id);
+        self.send_followers(ClusterFrame::Moved(id.clone()));
+        Ok(())
+    }
+
+    pub fn registrations(&self) -> Vec<Registration> {
+        self.registrations
+            .lock()
+            .map(|registrations| registrations.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn is_moving(&self, id: &NodeId) -> bool {
+        self.moving
+            .lock()
+            .map(|moving| moving.contains(id))
+            .unwrap_or(false)
+    }
+
+    /// Everything an instance needs to take this one's place: the
+    /// registrations, then the nodes moving.
+    pub fn snapshot(&self) -> Result<Vec<ClusterFrame>, String> {
+        let registrations = self.registrations.lock().map_err(|err| err.to_string())?;
+        self.snapshot_of(&registrations)
+    }
+
+    /// Take a new follower. Returns what it should get first; every later
+    /// change goes to `sender`.
+    pub fn add_follower(&self, sender: Sender<ClusterFrame>) -> Result<Vec<ClusterFrame>, String> {
+        let registrations = self.registrations.lock().map_err(|err| err.to_string())?;
+        self.followers
+            .lock()
+            .map_err(|err| err.to_string())?
+            .push(sender);
+        self.snapshot_of(&registrations)
+    }
+
+    /// Let go of every follower, their links close.
+    pub fn drop_followers(&self) {
+        if let Ok(mut followers) = self.followers.lock() {
+            followers.clear();
+        }
+    }
+
+    pub fn heartbeat(&self) {
+        self.send_followers(ClusterFrame::Heartbeat);
+    }
+
+    fn snapshot_of(
+        &self,
+        registrations: &BTreeMap<NodeId, Registration>,
+    ) -> Result<Vec<ClusterFrame>, String> {
+        let moving = self.moving.lock().map_err(|err| err.to_string())?;
+        Ok(registrations
+            .values()
+            .cloned()
+            .map(ClusterFrame::Register)
+            .chain(moving.iter().cloned().map(ClusterFrame::Moving))
+            .collect())
+    }
+
+    /// A follower that can't keep up is dropped, and links up again.
+    fn send_followers(&self, frame: ClusterFrame) {
+        if let Ok(mut followers) = self.followers.lock() {
+            followers.retain(|follower| follower.try_send(frame.clone()).is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_takeover_order() {
+        let members = ["r0", "r1", "r2"]
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (id.to_string(), format!("127.0.0.1:{}", 7000 + index)))
+            .collect();
+        let cluster = Cluster::new("r1", members);
+        assert_eq!(Some("127.0.0.1:7001"), cluster.own_addr());
+        assert!(cluster.ranks_before("r0"));
+        assert!(!cluster.ranks_before("r2"));
+        assert!(!cluster.ranks_before("r9"));
+
+        assert_eq!(None, cluster.leader());
+        cluster.set_role(Role::Follower("r0".to_string()));
+        assert_eq!(Some("r0".to_string()), cluster.leader());
+        cluster.set_role(Role::Leader);
+        assert_eq!(Some("r1".to_string()), cluster.leader());
+    }
+
+    #[test]
+    fn test_moving_in_snapshot() {
+        let cluster = Cluster::new("r0", Vec::new());
+        let id = NodeId::new("b1", "b").unwrap();
+        let (sender, mut frames) = tokio::sync::mpsc::channel(8);
+        assert!(cluster.add_follower(sender).unwrap().is_empty());
+
+        cluster.begin_move(&id).unwrap();
+        assert!(cluster.is_moving(&id));
+        assert!(matches!(frames.try_recv(), Ok(ClusterFrame::Moving(moving)) if moving == id));
+        assert!(matches!(
+            cluster.snapshot().unwrap().as_slice(),
+            [ClusterFrame::Moving(moving)] if *moving == id
+        ));
+
+        cluster.end_move(&id).unwrap();
+        assert!(matches!(frames.try_recv(), Ok(ClusterFrame::Moved(moved)) if moved == id));
+        assert!(cluster.snapshot().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+//! Bounded dead-letter queue of the relayer.
+//!
+//! Messages the relayer couldn't deliver, because they failed verification,
+//! were refused, expired or had nobody to go to, are kept here with the
+//! reason. An operator can list and inspect them, purge them, or take one out
+//! to route it again. When the queue is full the oldest entry makes room.
+
+use std::{collections::VecDeque, sync::Mutex, time::SystemTime};
+
+use frame_common::{data::Router, id::NodeId};
+use log::debug;
+use serde::Serialize;
+
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct DeadLetter<M> {
+    pub id: u64,
+    pub at: SystemTime,
+    /// connection the relayer was handling when the message failed
+    pub connection: String,
+    pub reason: String,
+    /// the message as the relayer got it
+    pub message: M,
+}
+
+/// What `DeadLetters::list` shows of an entry.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DeadLetterInfo {
+    pub id: u64,
+    pub source: String,
+    pub target: String,
+    pub reason: String,
+}
+
+struct Letters<M> {
+    entries: VecDeque<DeadLetter<M>>,
+    next_id: u64,
+}
+
+pub struct DeadLetters<M> {
+    capacity: usize,
+    letters: Mutex<Letters<M>>,
+}
+
+impl<M> Default for DeadLetters<M> {
+    fn default() -> Self {
+        DeadLetters::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl<M> DeadLetters<M> {
+    /// A capacity of 0 keeps nothing.
+    pub fn new(capacity: usize) -> DeadLetters<M> {
+        DeadLetters {
+            capacity,
+            letters: Mutex::new(Letters {
+                entries: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Keep `message` with the reason it failed. Returns its id.
+    pub fn record(&self, connection: &str, reason: &str, message: M) -> Option<u64> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut letters = self.letters.lock().ok()?;
+        while letters.entries.len() >= self.capacity {
+            if let Some(oldest) = letters.entries.pop_front() {
+                debug!("dead letter {} pushed out", oldest.id);
+            }
+        }
+        let id = letters.next_id;
+        letters.next_id += 1;
+        letters.entries.push_back(DeadLetter {
+            id,
+            at: SystemTime::now(),
+            connection: connection.to_string(),
+            reason: reason.to_string(),
+            message,
+        });
+        Some(id)
+    }
+
+    /// Remove the entry `id`. False if there is none.
+    pub fn purge(&self, id: u64) -> bool {
+        self.take(id).is_some()
+    }
+
+    /// Remove every entry. Returns how many there were.
+    pub fn purge_all(&self) -> usize {
+        self.letters
+            .lock()
+            .map(|mut letters| letters.entries.drain(..).count())
+            .unwrap_or(0)
+    }
+
+    /// Remove the entry `id` and hand it out, to be routed again.
+    pub fn take(&self, id: u64) -> Option<DeadLetter<M>> {
+        let mut letters = self.letters.lock().ok()?;
+        let index = letters.entries.iter().position(|entry| entry.id == id)?;
+        letters.entries.remove(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.letters
+            .lock()
+            .map(|letters| letters.entries.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<M> DeadLetters<M>
+where
+    M: Router<NodeId> + Clone,
+{
+    /// Every entry, oldest first.
+    pub fn list(&self) -> Vec<DeadLetterInfo> {
+        let letters = match self.letters.lock() {
+            Ok(letters) => letters,
+            Err(_) => return Vec::new(),
+        };
+        letters
+            .entries
+            .iter()
+            .map(|entry| DeadLetterInfo {
+                id: entry.id,
+                source: entry.message.get_source_id().to_string(),
+                target: entry.message.get_target_id().to_string(),
+                reason: entry.reason.clone(),
+            })
+            .collect()
+    }
+
+    pub fn inspect(&self, id: u64) -> Option<DeadLetter<M>> {
+        let letters = self.letters.lock().ok()?;
+        letters.entries.iter().find(|entry| entry.id == id).cloned()
+    }
+}
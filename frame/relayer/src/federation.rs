@@ -0,0 +1,370 @@
+//! Routing between peered relayers.
+//!
+//! Every relayer of a federation has an id and pins the keys of its peers.
+//! Peers advertise the nodes they serve; an advertisement travels on to
+//! further peers with its hop count raised and the relayers it went through
+//! appended, and a relayer takes the path with the fewest hops to each
+//! origin. An advertisement that already went through a relayer, or that
+//! would exceed `max_hops`, goes no further, and so do forwarded messages.
+//! Origins not heard from for a few advertise intervals are forgotten.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use frame_common::{id::NodeId, now_millis};
+use log::debug;
+use rsa::RsaPublicKey;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+
+/// Origins are forgotten after this many advertise intervals of silence.
+const STALE_AFTER: u32 = 3;
+
+/// The nodes `origin` serves, as seen `hops` relayers away from it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Advert {
+    pub origin: String,
+    /// raised by the origin with every advertisement, newer ones win
+    pub seq: u64,
+    pub hops: usize,
+    /// relayers it went through, the origin first
+    pub path: Vec<String>,
+    pub nodes: Vec<NodeId>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Scope {
+    /// to node `id`, wherever it is; a bounce goes to its source this way
+    Node { id: NodeId },
+    /// to the members of the target group that relayer `origin` serves
+    Group { origin: String },
+}
+
+/// What travels on a relayer-to-relayer link.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PeerFrame<M> {
+    /// first frame on a link, `nonce` is the challenge for the other side
+    Hello {
+        relayer_id: String,
+        nonce: u64,
+    },
+    /// signature over the other side's nonce and both relayer ids
+    Auth {
+        sig: Vec<u8>,
+    },
+    Advert(Advert),
+    Forward {
+        /// relayers the message went through, the one that took it from the
+        /// sender first
+        path: Vec<String>,
+        scope: Scope,
+        message: M,
+    },
+    /// an error reply or report a relayer made, routed like `Forward`; the
+    /// only frames whose message a relayer signs again
+    Notice {
+        path: Vec<String>,
+        scope: Scope,
+        message: M,
+    },
+}
+
+/// What relayer `signer` signs to prove its id to the peer `recipient` that
+/// sent `nonce`. Naming the recipient keeps the signature from being replayed
+/// to any other relayer.
+pub fn auth_data(nonce: u64, signer: &str, recipient: &str) -> String {
+    format!("{}:{}:{}", nonce, signer, recipient)
+}
+
+struct Origin {
+    /// peer the best path starts with
+    via: String,
+    /// as heard, with the hops to here and this relayer added to the path
+    advert: Advert,
+    heard: Instant,
+}
+
+#[derive(Default)]
+struct Routes {
+    origins: HashMap<String, Origin>,
+    /// node id to the origin serving it
+    nodes: HashMap<NodeId, String>,
+}
+
+impl Routes {
+    fn remove(&mut self, origin: &str) {
+        if let Some(old) = self.origins.remove(origin) {
+            for node in old.advert.nodes {
+                if self.nodes.get(&node).map(|id| id.as_str()) == Some(origin) {
+                    self.nodes.remove(&node);
+                }
+            }
+        }
+    }
+}
+
+pub struct Federation<M> {
+    pub relayer_id: String,
+    pub max_hops: usize,
+    pub advertise_interval: Duration,
+    peer_keys: Mutex<HashMap<String, RsaPublicKey>>,
+    links: Mutex<HashMap<String, Sender<PeerFrame<M>>>>,
+    routes: Mutex<Routes>,
+    seq: AtomicU64,
+}
+
+impl<M> Federation<M> {
+    pub fn new(relayer_id: &str) -> Federation<M> {
+        Federation {
+            relayer_id: relayer_id.to_string(),
+            max_hops: 8,
+            advertise_interval: Duration::from_secs(5),
+            peer_keys: Mutex::new(HashMap::new()),
+            links: Mutex::new(HashMap::new()),
+            routes: Mutex::new(Routes::default()),
+            // keeps rising across restarts, so peers take the new adverts
+            seq: AtomicU64::new(now_millis()),
+        }
+    }
+
+    /// Accept links from the relayer `relayer_id` holding `key`.
+    pub fn add_peer(&self, relayer_id: &str, key: RsaPublicKey) -> Result<(), String> {
+        self.peer_keys
+            .lock()
+            .map_err(|err| err.to_string())?
+            .insert(relayer_id.to_string(), key);
+        Ok(())
+    }
+
+    pub fn peer_key(&self, relayer_id: &str) -> Option<RsaPublicKey> {
+        self.peer_keys.lock().ok()?.get(relayer_id).cloned()
+    }
+
+    pub fn add_link(&self, relayer_id: &str, sender: Sender<PeerFrame<M>>) -> Result<(), String> {
+        self.links
+            .lock()
+            .map_err(|err| err.to_string())?
+            .insert(relayer_id.to_string(), sender);
+        Ok(())
+    }
+
+    /// Forget the link `sender` to `relayer_id` and every route through it,
+    /// unless a newer link replaced it meanwhile.
+    pub fn remove_link(&self, relayer_id: &str, sender: &Sender<PeerFrame<M>>) {
+        match self.links.lock() {
+            Ok(mut links) => match links.get(relayer_id) {
+                Some(current) if current.same_channel(sender) => {
+                    links.remove(relayer_id);
+                }
+                _ => return,
+            },
+            Err(_) => return,
+        }
+        if let Ok(mut routes) = self.routes.lock() {
+            let gone: Vec<String> = routes
+                .origins
+                .iter()
+                .filter(|(_, origin)| origin.via == relayer_id)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for origin in gone {
+                routes.remove(&origin);
+            }
+        }
+    }
+
+    pub fn link(&self, relayer_id: &str) -> Option<Sender<PeerFrame<M>>> {
+        self.links.lock().ok()?.get(relayer_id).cloned()
+    }
+
+    /// Every link but those to the relayers in `skip`.
+    pub fn links_except(&self, skip: &[String]) -> Vec<(String, Sender<PeerFrame<M>>)> {
+        match self.links.lock() {
+            Ok(links) => links
+                .iter()
+                .filter(|(id, _)| !skip.contains(id))
+                .map(|(id, sender)| (id.clone(), sender.clone()))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// An advertisement of the own nodes, to send to every peer.
+    pub fn own_advert(&self, nodes: Vec<NodeId>) -> Advert {
+        Advert {
+            origin: self.relayer_id.clone(),
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            hops: 0,
+            path: vec![self.relayer_id.clone()],
+            nodes,
+        }
+    }
+
+    /// Take in `advert` heard from the peer `from`. Returns the advertisement
+    /// to pass on to the other peers, if it changed a route and may travel
+    /// further.
+    pub fn learn(&self, from: &str, advert: Advert) -> Option<Advert> {
+        if advert.origin == self.relayer_id || advert.path.contains(&self.relayer_id) {
+            debug!("drop advert of {} that looped", advert.origin);
+            return None;
+        }
+        let hops = advert.hops + 1;
+        if hops > self.max_hops {
+            return None;
+        }
+        let mut routes = self.routes.lock().ok()?;
+        let better = match routes.origins.get(&advert.origin) {
+            Some(known) => {
+                advert.seq > known.advert.seq
+                    || (advert.seq == known.advert.seq && hops < known.advert.hops)
+            }
+            None => true,
+        };
+        if !better {
+            return None;
+        }
+        routes.remove(&advert.origin);
+        for node in &advert.nodes {
+            routes.nodes.insert(node.clone(), advert.origin.clone());
+        }
+        let mut path = advert.path;
+        path.push(self.relayer_id.clone());
+        let advert = Advert {
+            hops,
+            path,
+            ..advert
+        };
+        routes.origins.insert(
+            advert.origin.clone(),
+            Origin {
+                via: from.to_string(),
+                advert: advert.clone(),
+                heard: Instant::now(),
+            },
+        );
+        if hops >= self.max_hops {
+            return None;
+        }
+        Some(advert)
+    }
+
+    /// The adverts of every known origin that may travel further, for a peer
+    /// that just linked up.
+    pub fn table(&self) -> Vec<Advert> {
+        match self.routes.lock() {
+            Ok(routes) => routes
+                .origins
+                .values()
+                .filter(|origin| origin.advert.hops < self.max_hops)
+                .map(|origin| origin.advert.clone())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Peer to send a message for node `id` to.
+    pub fn route_of(&self, id: &NodeId) -> Option<String> {
+        let origin = self.routes.lock().ok()?.nodes.get(id)?.clone();
+        self.route_to(&origin)
+    }
+
+    /// Peer to send a message for the relayer `origin` to.
+    pub fn route_to(&self, origin: &str) -> Option<String> {
+        let routes = self.routes.lock().ok()?;
+        routes.origins.get(origin).map(|origin| origin.via.clone())
+    }
+
+    /// Hops to the origin serving node `id`.
+    pub fn hops_to(&self, id: &NodeId) -> Option<usize> {
+        let routes = self.routes.lock().ok()?;
+        let origin = routes.nodes.get(id)?;
+        routes.origins.get(origin).map(|origin| origin.advert.hops)
+    }
+
+    /// Relayers serving members of `group`.
+    pub fn origins_with_group(&self, group: &str) -> Vec<String> {
+        match self.routes.lock() {
+            Ok(routes) => routes
+                .origins
+                .iter()
+                .filter(|(_, origin)| origin.advert.nodes.iter().any(|node| node.group() == group))
+                .map(|(id, _)| id.clone())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Forget origins not heard from for a while.
+    pub fn expire_stale(&self) {
+        let stale = self.advertise_interval * STALE_AFTER;
+        if let Ok(mut routes) = self.routes.lock() {
+            let gone: Vec<String> = routes
+                .origins
+                .iter()
+                .filter(|(_, origin)| origin.heard.elapsed() > stale)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for origin in gone {
+                debug!("forget stale origin {}", origin);
+                routes.remove(&origin);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c1() -> NodeId {
+        NodeId::new("c1", "c").unwrap()
+    }
+
+    fn advert(origin: &str, seq: u64, path: &[&str]) -> Advert {
+        Advert {
+            origin: origin.to_string(),
+            seq,
+            hops: path.len() - 1,
+            path: path.iter().map(|id| id.to_string()).collect(),
+            nodes: vec![c1()],
+        }
+    }
+
+    #[test]
+    fn test_learn_shortest_path() {
+        let mut federation: Federation<String> = Federation::new("a");
+        federation.max_hops = 3;
+        // the long way round first, then the direct one for the same seq
+        let passed = federation.learn("b", advert("c", 0, &["c", "b"])).unwrap();
+        assert_eq!(2, passed.hops);
+        assert_eq!(vec!["c", "b", "a"], passed.path);
+        assert_eq!(Some("b".to_string()), federation.route_of(&c1()));
+        assert!(federation.learn("c", advert("c", 0, &["c"])).is_some());
+        assert_eq!(Some("c".to_string()), federation.route_of(&c1()));
+        assert_eq!(Some(1), federation.hops_to(&c1()));
+        assert!(federation.learn("b", advert("c", 0, &["c", "b"])).is_none());
+
+        // loops and paths beyond the hop limit go nowhere
+        assert!(federation
+            .learn("b", advert("c", 1, &["c", "a", "b"]))
+            .is_none());
+        assert!(federation.learn("b", advert("a", 1, &["a", "b"])).is_none());
+        assert!(federation
+            .learn("b", advert("e", 0, &["e", "f", "g", "b"]))
+            .is_none());
+        assert_eq!(Some("c".to_string()), federation.route_of(&c1()));
+        assert_eq!(vec!["c".to_string()], federation.origins_with_group("c"));
+        assert_eq!(1, federation.table().len());
+
+        let (link, _rx) = tokio::sync::mpsc::channel(1);
+        federation.add_link("c", link.clone()).unwrap();
+        federation.remove_link("c", &link);
+        assert!(federation.route_of(&c1()).is_none());
+    }
+}
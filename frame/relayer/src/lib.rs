@@ -1,33 +1,69 @@
+pub mod audit;
+pub mod balance;
+pub mod cluster;
+pub mod deadletter;
+pub mod federation;
+pub mod link;
+pub mod migration;
+pub mod parking;
+pub mod partition;
+pub mod routes;
+pub mod schedule;
 #[cfg(test)]
 mod tests;
+pub mod topic;
+pub mod verify;
+pub mod wal;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     error::Error,
+    fmt,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
+use audit::{AuditDecision, AuditRecord, AuditTable};
+use balance::Balancer;
+use cluster::{Cluster, ClusterFrame, Registration, Role};
+use deadletter::DeadLetters;
+use federation::{auth_data, Federation, PeerFrame, Scope};
 use frame_common::{
-    data::{Message, Router}, parse_message_list, verify,
+    codec::FrameDecoder,
+    data::{AnycastStrategy, ControlEvent, Delivery, DeliveryResult, Message, RelayerAuth, Router},
+    id::NodeId,
+    lane::Lanes,
+    now_millis, parse_message, relayer_auth_data, relayer_sign, sign, verify,
 };
+use link::{write_frame, LinkReader};
 use log::{debug, error, info};
-use rsa::RsaPublicKey;
+use migration::Migrations;
+use parking::{ParkResult, Parking};
+use routes::Routes;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use schedule::Scheduler;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
+        TcpListener, TcpStream,
     },
     sync::{
-        broadcast::{self},
-        mpsc::{Receiver},
+        broadcast::{
+            self,
+            error::{RecvError, TryRecvError},
+        },
+        mpsc::{self, error::TrySendError, Receiver},
+        Notify,
     },
 };
+use topic::Topics;
+use verify::Verifier;
 
 pub struct RegisterInfo
 //where
-//    T: Send + 'static + Serialize + DeserializeOwned+ Router<String> + Message + Clone,
+//    T: Send + 'static + Serialize + DeserializeOwned+ Router<NodeId> + Message + Clone,
 {
     pub addr: Box<String>,
     pub name: Box<String>,
@@ -36,40 +72,144 @@ pub struct RegisterInfo
 }
 
 impl RegisterInfo
-//where T: Send + 'static + Serialize + DeserializeOwned+ Router<String> + Message + Clone,
+//where T: Send + 'static + Serialize + DeserializeOwned+ Router<NodeId> + Message + Clone,
 {
-    pub fn get_source_id(&self) -> Box<String> {
-        let ans: String = self.name.to_string() + &(self.group.to_string());
-        Box::new(ans)
+    /// Fails for a name or group no node may have.
+    pub fn get_source_id(&self) -> Result<NodeId, String> {
+        NodeId::new(&self.name, &self.group)
     }
 }
 
-pub type RouteTable<M> = Arc<Mutex<HashMap<String, BcMsgSender<M>>>>;
-pub type PubKeyTable = Arc<Mutex<HashMap<String, RsaPublicKey>>>;
+pub type RouteTable<M> = Arc<Routes<BcMsgSender<M>>>;
+pub type PubKeyTable = Arc<Routes<RsaPublicKey>>;
+/// group -> every connected member
+pub type GroupTable = Arc<Mutex<HashMap<String, BTreeSet<NodeId>>>>;
+/// source ids allowed to send `Delivery::Broadcast`
+pub type BroadcastAcl = Arc<Mutex<HashSet<NodeId>>>;
+/// node id -> closes the reading side of its current connection
+pub type ConnectionTable = Arc<Mutex<HashMap<NodeId, Arc<Notify>>>>;
 type BcMsgSender<M> = tokio::sync::broadcast::Sender<M>;
 type BcMsgReceiver<M> = tokio::sync::broadcast::Receiver<M>;
 
+/// Limits on what a single connection may send to the relayer.
+#[derive(Debug, Clone, Copy)]
+pub struct RelayerLimits {
+    /// bytes between two delimiters, i.e. one serialized message
+    pub max_frame_size: usize,
+    /// bytes of user payload inside one message
+    pub max_message_size: usize,
+    /// malformed or oversized frames tolerated before the connection is dropped
+    pub max_bad_frames: u32,
+}
+
+impl Default for RelayerLimits {
+    fn default() -> Self {
+        RelayerLimits {
+            max_frame_size: 64 * 1024,
+            max_message_size: 32 * 1024,
+            max_bad_frames: 8,
+        }
+    }
+}
+
+/// Everything the relayer tasks share. Cloning only clones the handles.
+pub struct RelayerState<M> {
+    pub route_table: RouteTable<M>,
+    pub pub_keys: PubKeyTable,
+    pub groups: GroupTable,
+    pub broadcasters: BroadcastAcl,
+    pub connections: ConnectionTable,
+    pub audit: Option<AuditTable>,
+    /// signs every message the relayer produces itself
+    pub identity_key: Arc<RsaPrivateKey>,
+    pub limits: RelayerLimits,
+    /// also take signatures over the run-together id nodes signed before
+    /// structured ids, off by default. Compatibility only, to be removed once
+    /// no deployed node signs that form.
+    pub legacy_id_signatures: bool,
+    pub verifier: Arc<Verifier>,
+    pub balancer: Arc<Balancer>,
+    pub topics: Arc<Topics>,
+    /// store-and-forward for offline nodes, off when `None`
+    pub parking: Option<Arc<Parking<M>>>,
+    /// nodes moving to another machine
+    pub migrations: Arc<Migrations<M>>,
+    pub dead_letters: Arc<DeadLetters<M>>,
+    /// messages held until their delivery time
+    pub scheduler: Arc<Scheduler<M>>,
+    /// links to peer relayers, off when `None`
+    pub federation: Option<Arc<Federation<M>>>,
+    /// instances replicating this one's tables, off when `None`
+    pub cluster: Option<Arc<Cluster>>,
+}
+
+impl<M> RelayerState<M> {
+    pub fn new(identity_key: RsaPrivateKey) -> RelayerState<M> {
+        RelayerState {
+            route_table: Arc::new(Routes::default()),
+            pub_keys: Arc::new(Routes::default()),
+            groups: Arc::new(Mutex::new(HashMap::new())),
+            broadcasters: Arc::new(Mutex::new(HashSet::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            audit: None,
+            identity_key: Arc::new(identity_key),
+            limits: RelayerLimits::default(),
+            legacy_id_signatures: false,
+            verifier: Arc::new(Verifier::default()),
+            balancer: Arc::new(Balancer::default()),
+            topics: Arc::new(Topics::default()),
+            parking: None,
+            migrations: Arc::new(Migrations::default()),
+            dead_letters: Arc::new(DeadLetters::default()),
+            scheduler: Arc::new(Scheduler::default()),
+            federation: None,
+            cluster: None,
+        }
+    }
+}
+
+impl<M> Clone for RelayerState<M> {
+    fn clone(&self) -> Self {
+        RelayerState {
+            route_table: self.route_table.clone(),
+            pub_keys: self.pub_keys.clone(),
+            groups: self.groups.clone(),
+            broadcasters: self.broadcasters.clone(),
+            connections: self.connections.clone(),
+            audit: self.audit.clone(),
+            identity_key: self.identity_key.clone(),
+            limits: self.limits,
+            legacy_id_signatures: self.legacy_id_signatures,
+            verifier: self.verifier.clone(),
+            balancer: self.balancer.clone(),
+            topics: self.topics.clone(),
+            parking: self.parking.clone(),
+            migrations: self.migrations.clone(),
+            dead_letters: self.dead_letters.clone(),
+            scheduler: self.scheduler.clone(),
+            federation: self.federation.clone(),
+            cluster: self.cluster.clone(),
+        }
+    }
+}
+
 pub async fn listen_relayer_register<T>(
     mut clients_rx: Receiver<RegisterInfo>,
-    route_table: RouteTable<T>,
-    pub_keys: PubKeyTable,
+    state: RelayerState<T>,
 ) -> Result<(), String>
 where
-    T: Send + 'static + Serialize + DeserializeOwned + Router<String> + Message + Clone,
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
 {
+    tokio::spawn(run_scheduler(state.clone()));
+    tokio::spawn(run_migrations(state.clone()));
+    tokio::spawn(run_federation(state.clone()));
+    tokio::spawn(run_cluster(state.clone()));
     let future = tokio::spawn(async move {
         while let Some(register_info) = clients_rx.recv().await {
-            let route_table = route_table.clone();
-            let pub_keys = pub_keys.clone();
+            let state = state.clone();
             info!("relayer have receive new register={}", register_info.addr);
             tokio::spawn(async move {
-                let res = relayer_connect(
-                    &register_info.addr,
-                    route_table,
-                    pub_keys,
-                    *register_info.get_source_id(),
-                )
-                .await;
+                let res = relayer_connect(&register_info, state).await;
                 if let Err(error) = res {
                     error!(
                         "relayer error to listen to addr: {},error = {}",
@@ -96,113 +236,1929 @@ where
 }
 
 async fn relayer_connect<T>(
-    addr: &str,
-    route_table: RouteTable<T>,
-    pub_keys: PubKeyTable,
-    identity: String,
+    register_info: &RegisterInfo,
+    state: RelayerState<T>,
 ) -> Result<(), Box<dyn Error>>
 where
-    T: Send + 'static + Serialize + DeserializeOwned + Router<String> + Message + Clone,
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
 {
+    let addr = register_info.addr.as_str();
+    let identity = register_info.get_source_id()?;
     let stream = TcpStream::connect(addr).await?;
-    let (reader, writer) = stream.into_split();
+    let (reader, mut writer) = stream.into_split();
 
     debug!("has connect to addr={}", addr);
+    let reader = tokio::time::timeout(
+        NODE_HANDSHAKE_TIMEOUT,
+        answer_node(&state, reader, &mut writer, addr),
+    )
+    .await
+    .map_err(|_| "node didn't challenge in time".to_string())??;
 
     let (send_tx, send_rx): (BcMsgSender<T>, BcMsgReceiver<T>) = broadcast::channel(16);
 
-    {
-        let mut lock_table = route_table.lock().map_err(|err| err.to_string())?;
-        lock_table.insert(identity, send_tx);
-    }
+    add_route(&state, &identity, send_tx)?;
+    let closer = Arc::new(Notify::new());
+    state
+        .connections
+        .lock()
+        .map_err(|err| err.to_string())?
+        .insert(identity.clone(), closer.clone());
 
+    let send_state = state.clone();
+    let send_identity = identity.clone();
     tokio::spawn(async move {
-        do_send(send_rx, writer).await;
+        do_send(send_rx, writer, send_state, send_identity).await;
     });
+    if parking_of(&state, &identity).is_some() {
+        let flush_state = state.clone();
+        let flush_identity = identity.clone();
+        tokio::spawn(async move {
+            flush_parked(flush_state, flush_identity).await;
+        });
+    }
     tokio::spawn(async move {
-        do_receive(route_table, pub_keys, reader).await;
+        do_receive(state, reader, identity, closer).await;
     });
     Ok(())
 }
 
-async fn do_receive<T>(route_table: RouteTable<T>, pub_keys: PubKeyTable, mut reader: OwnedReadHalf)
+/// Nodes that don't challenge the relayer in time are dropped.
+const NODE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prove to the node listening on `addr` that this is its relayer, by signing
+/// the nonce it challenges with.
+async fn answer_node<T>(
+    state: &RelayerState<T>,
+    reader: OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+    addr: &str,
+) -> Result<OwnedReadHalf, String> {
+    let mut reader = LinkReader::new(reader, state.limits.max_frame_size);
+    let nonce = match reader.next::<RelayerAuth>().await? {
+        Some(RelayerAuth::Challenge { nonce }) => nonce,
+        _ => return Err("node didn't challenge".to_string()),
+    };
+    let sig = sign(&relayer_auth_data(nonce, addr), &state.identity_key)?;
+    write_frame(writer, RelayerAuth::Answer { sig }).await?;
+    Ok(reader.into_inner())
+}
+
+/// Queues where messages for `id` wait while it has no connection: the
+/// store-and-forward ones, or while it moves the migration ones.
+fn parking_of<T>(state: &RelayerState<T>, id: &NodeId) -> Option<Arc<Parking<T>>> {
+    match &state.parking {
+        Some(parking) => Some(parking.clone()),
+        None if state.migrations.is_moving(id) => Some(state.migrations.parking.clone()),
+        None => None,
+    }
+}
+
+/// Hand the messages parked for `identity` to its new connection, oldest
+/// first, keeping at most `FLUSH_WINDOW` of them unwritten at a time.
+/// Messages are only done once the connection's writer drained them, so a
+/// message may be sent twice if the connection drops meanwhile. A move of
+/// `identity` ends once everything is flushed.
+pub async fn flush_parked<T>(state: RelayerState<T>, identity: NodeId)
 where
-    T: Send + 'static + Serialize + DeserializeOwned + Router<String> + Message + Clone,
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
 {
-    let mut buf = [0; 4096];
-    while let Ok(size) = reader.read(&mut buf).await {
-        if size == 0 {
-            continue;
+    const FLUSH_WINDOW: usize = 8;
+    let parking = match parking_of(&state, &identity) {
+        Some(parking) => parking,
+        None => return,
+    };
+    let mut unconfirmed = 0;
+    let mut flushed = 0;
+    let mut finished = false;
+    loop {
+        let drained = wait_drained(&state, &identity, FLUSH_WINDOW).await;
+        if !drained {
+            // disconnected again, the rest waits for the next connection
+            parking.rewind(&identity);
+            break;
+        }
+        let sender = match state.route_table.get(&identity) {
+            Some(sender) => sender,
+            None => {
+                parking.rewind(&identity);
+                break;
+            }
+        };
+        let popped = parking.pop(&identity);
+        dead_letter_expired(&state, parking.as_ref());
+        match popped {
+            Some(item) if item.is_expired() => {
+                if let Err(error) = expire(&state, item) {
+                    error!(
+                        "relayer expire message for {} failed,error={}",
+                        identity, error
+                    );
+                }
+                unconfirmed += 1;
+            }
+            Some(item) => {
+                if let Err((error, _)) = channel_send(&state, &identity, &sender, item) {
+                    error!("relayer flush to {} failed,error={}", identity, error);
+                    parking.unpop(&identity);
+                    parking.rewind(&identity);
+                    break;
+                }
+                unconfirmed += 1;
+                flushed += 1;
+            }
+            None => {
+                if !wait_drained(&state, &identity, 1).await {
+                    parking.rewind(&identity);
+                    break;
+                }
+                parking.done(&identity, unconfirmed);
+                unconfirmed = 0;
+                if parking.finish(&identity) {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+    }
+    if flushed > 0 {
+        info!(
+            "relayer flushed {} parked messages to {}",
+            flushed, identity
+        );
+    }
+    if finished && end_migration(&state, &identity) {
+        info!("relayer finish moving {}", identity);
+    }
+}
+
+/// End the move of `id` here and on the cluster's followers. False if it
+/// wasn't moving.
+fn end_migration<T>(state: &RelayerState<T>, id: &NodeId) -> bool {
+    if !state.migrations.end(id) {
+        return false;
+    }
+    if let Some(cluster) = &state.cluster {
+        if let Err(error) = cluster.end_move(id) {
+            error!("relayer replicate end of move failed,error={}", error);
+        }
+    }
+    true
+}
+
+/// End the moves of nodes that didn't register again in time, for as long
+/// as the relayer runs.
+pub async fn run_migrations<T>(state: RelayerState<T>)
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    loop {
+        let next = state
+            .migrations
+            .next_deadline()
+            .unwrap_or_else(|| Instant::now() + state.migrations.timeout);
+        tokio::time::sleep_until(next.into()).await;
+        for id in state.migrations.take_overdue() {
+            expire_migration(&state, &id);
+        }
+    }
+}
+
+/// Give up on `id` coming back from its move and bounce what the migration
+/// queues parked for it. With store-and-forward on, its messages wait there
+/// as for any node offline. A node that is back already gets its queue
+/// flushed.
+fn expire_migration<T>(state: &RelayerState<T>, id: &NodeId)
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    if let Some(cluster) = &state.cluster {
+        if let Err(error) = cluster.end_move(id) {
+            error!("relayer replicate end of move failed,error={}", error);
+        }
+    }
+    if state.parking.is_some() || state.route_table.contains(id) {
+        info!("relayer stop moving {}", id);
+        return;
+    }
+    let parked = match state.migrations.parking.remove(id) {
+        Ok(parked) => parked,
+        Err(error) => {
+            error!("relayer take parked messages failed,error={}", error);
+            return;
+        }
+    };
+    let reason = format!("node {} didn't finish moving in time", id);
+    for item in &parked {
+        if let Err(error) = bounce(state, item.clone(), &reason) {
+            error!("relayer bounce parked message failed,error={}", error);
         }
-        let serialized = String::from_utf8_lossy(&mut buf[0..size]);
-        debug!("relayer receive message={}", serialized);
-        let mut item_list = parse_message_list::<T>(&serialized);
-        while !item_list.is_empty() {
-            let parsed = item_list.remove(0);
-            if let Err(error) = transfer_msg(route_table.clone(), pub_keys.clone(), parsed) {
-                error!("transfer msg failed,msg={},error={}", serialized, error);
+    }
+    info!("relayer stop moving {},bounced={}", id, parked.len());
+}
+
+/// Route scheduled messages when they are due, for as long as the relayer
+/// runs.
+pub async fn run_scheduler<T>(state: RelayerState<T>)
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    loop {
+        release_due(&state);
+        let next_due = state.scheduler.next_due();
+        state.scheduler.sleep_until(next_due).await;
+    }
+}
+
+/// Route every scheduled message that is due. Their check was done when
+/// they arrived. Returns how many there were.
+pub fn release_due<T>(state: &RelayerState<T>) -> usize
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let due = state.scheduler.take_due(now_millis());
+    let seqs: Vec<u64> = due.iter().map(|(seq, _)| *seq).collect();
+    for (_, item) in due {
+        let source_id = item.get_source_id();
+        if let Err(error) = finish_transfer(state, &source_id, item, Ok(())) {
+            error!("relayer deliver scheduled message failed,error={}", error);
+        }
+    }
+    state.scheduler.done(&seqs);
+    seqs.len()
+}
+
+/// Wait until fewer than `window` messages for `identity` are unwritten.
+/// False if its connection is gone or failed meanwhile.
+async fn wait_drained<T>(state: &RelayerState<T>, identity: &NodeId, window: usize) -> bool {
+    loop {
+        let connected = state.route_table.contains(identity);
+        let health = state.balancer.get(identity);
+        if !connected || !health.is_healthy() {
+            return false;
+        }
+        if health.in_flight < window {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Read the messages `identity` sends until the connection closes, or until
+/// `closer` is notified because the node was unregistered.
+async fn do_receive<T>(
+    state: RelayerState<T>,
+    mut reader: OwnedReadHalf,
+    identity: NodeId,
+    closer: Arc<Notify>,
+) where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let mut buf = [0; 4096];
+    let mut decoder = FrameDecoder::new(state.limits.max_frame_size);
+    let mut bad_frames: u32 = 0;
+    'connection: loop {
+        let size = tokio::select! {
+            res = reader.read(&mut buf) => match res {
+                Ok(size) if size > 0 => size,
+                _ => break,
+            },
+            _ = closer.notified() => break,
+        };
+        for frame in decoder.push(&buf[0..size]) {
+            if let Err(error) = receive_frame(&state, &identity, frame).await {
+                bad_frames += 1;
+                error!(
+                    "relayer receive bad frame,from={},count={},error={}",
+                    identity, bad_frames, error
+                );
+                if bad_frames > state.limits.max_bad_frames {
+                    break 'connection;
+                }
             }
         }
     }
+    info!("relayer drop connection of {}", identity);
+    drop_connection(&state, &identity, &closer);
+}
+
+/// Handle one frame split off the stream. Only a malformed frame is an error
+/// here; a message that parses but can't be routed is logged and counted as
+/// handled.
+async fn receive_frame<T>(
+    state: &RelayerState<T>,
+    identity: &NodeId,
+    frame: Result<String, String>,
+) -> Result<(), String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let frame = frame?;
+    debug!("relayer receive message={}", frame);
+    let parsed = parse_message::<T>(&frame)?;
+    if parsed.get_payload_len() > state.limits.max_message_size {
+        let error = format!("message too large,size={}", parsed.get_payload_len());
+        dead_letter(state, identity, &error, parsed);
+        return Err(error);
+    }
+    if let Err(error) = dispatch_msg(state, identity, parsed).await {
+        error!("transfer msg failed,msg={},error={}", frame, error);
+    }
+    Ok(())
+}
+
+/// Make `identity` reachable, both directly and as a member of its group.
+pub fn add_route<T>(
+    state: &RelayerState<T>,
+    identity: &NodeId,
+    sender: BcMsgSender<T>,
+) -> Result<(), String> {
+    {
+        // the route changes under the groups lock, so members and routes agree
+        let mut groups = state.groups.lock().map_err(|err| err.to_string())?;
+        state.route_table.insert(identity.clone(), sender);
+        groups
+            .entry(identity.group().to_string())
+            .or_default()
+            .insert(identity.clone());
+    }
+    state.balancer.record_connected(identity);
+    advertise(state);
+    Ok(())
+}
+
+/// Forget the route of a closed connection, unless a newer connection of
+/// `identity` replaced it meanwhile.
+fn drop_connection<T>(state: &RelayerState<T>, identity: &NodeId, closer: &Arc<Notify>) {
+    let current = match state.connections.lock() {
+        Ok(mut connections) => match connections.get(identity) {
+            Some(current) if Arc::ptr_eq(current, closer) => connections.remove(identity),
+            _ => None,
+        },
+        Err(_) => None,
+    };
+    if current.is_some() {
+        remove_route(state, identity);
+    }
+}
+
+/// Forget the route of `identity`. Dropping the sender also ends its
+/// `do_send` task, which closes the write half.
+fn remove_route<T>(state: &RelayerState<T>, identity: &NodeId) {
+    let res = state
+        .groups
+        .lock()
+        .map_err(|err| err.to_string())
+        .map(|mut groups| {
+            state.route_table.remove(identity);
+            groups.values_mut().for_each(|members| {
+                members.remove(identity);
+            });
+            groups.retain(|_, members| !members.is_empty());
+        });
+    if let Err(error) = res {
+        error!("relayer remove route failed,error={}", error);
+    }
+    // a moving node keeps its subscriptions
+    if !state.migrations.is_moving(identity) {
+        state.topics.forget(identity);
+    }
+    advertise(state);
+}
+
+/// Forget the node `id`: close its connection, drop its route, key and
+/// broadcast right, and bounce what was parked for it. Returns how many
+/// parked messages were bounced. Only the leader of a cluster unregisters.
+pub fn unregister_node<T>(state: &RelayerState<T>, id: &NodeId) -> Result<usize, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    check_leader(state)?;
+    if let Some(cluster) = &state.cluster {
+        cluster.forget(id)?;
+    }
+    let keyed = state.pub_keys.remove(id).is_some();
+    if !keyed && !state.route_table.contains(id) {
+        return Err(format!("node {} is not registered", id));
+    }
+    let parking = parking_of(state, id);
+    state.migrations.end(id);
+    state
+        .broadcasters
+        .lock()
+        .map_err(|err| err.to_string())?
+        .remove(id);
+    let closer = state
+        .connections
+        .lock()
+        .map_err(|err| err.to_string())?
+        .remove(id);
+    if let Some(closer) = closer {
+        closer.notify_one();
+    }
+    remove_route(state, id);
+    state.balancer.forget(id);
+
+    let parked = match parking {
+        Some(parking) => parking.remove(id)?,
+        None => Vec::new(),
+    };
+    let reason = format!("node {} unregistered", id);
+    for item in &parked {
+        if let Err(error) = bounce(state, item.clone(), &reason) {
+            error!("relayer bounce parked message failed,error={}", error);
+        }
+    }
+    info!("relayer unregister {},bounced={}", id, parked.len());
+    Ok(parked.len())
 }
 
-async fn do_send<T>(mut input: BcMsgReceiver<T>, mut writer: OwnedWriteHalf)
+/// Start moving the node `id` to another machine: its route is dropped and
+/// what is sent to it parks until it registers again under the same key. It
+/// keeps its subscriptions. A node still moving starts over. It has
+/// `Migrations::timeout` to come back, or what was parked is bounced.
+/// The old connection stays open until its machine closes it. Only the
+/// leader of a cluster moves nodes, and its followers learn of the move.
+pub fn begin_migration<T>(state: &RelayerState<T>, id: &NodeId) -> Result<(), String>
 where
-    T: Send + 'static + Serialize + DeserializeOwned + Clone,
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    check_leader(state)?;
+    if !state.pub_keys.contains(id) {
+        return Err(format!("node {} is not registered", id));
+    }
+    state.migrations.begin(id)?;
+    if let Some(cluster) = &state.cluster {
+        cluster.begin_move(id)?;
+    }
+    remove_route(state, id);
+    info!("relayer begin moving {}", id);
+    Ok(())
+}
+
+/// Write the messages routed to `identity`. Messages waiting for the stream
+/// are sorted into priority lanes, at most `LANE_BUFFER` of them; the rest
+/// stay in the route's channel.
+async fn do_send<T>(
+    mut input: BcMsgReceiver<T>,
+    mut writer: OwnedWriteHalf,
+    state: RelayerState<T>,
+    identity: NodeId,
+) where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
 {
-    while let Ok(raw_msg) = input.recv().await {
+    const LANE_BUFFER: usize = 64;
+    let balancer = state.balancer.clone();
+    let lagged = |count: u64| {
+        error!(
+            "relayer sender of {} lagged, {} messages lost",
+            identity, count
+        );
+        balancer.record_failure(&identity, count as usize);
+    };
+    let mut lanes = Lanes::default();
+    let mut closed = false;
+    loop {
+        if lanes.is_empty() {
+            if closed {
+                break;
+            }
+            match input.recv().await {
+                Ok(raw_msg) => lanes.push(raw_msg.get_priority(), raw_msg),
+                Err(RecvError::Lagged(count)) => {
+                    lagged(count);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+        // take what else is waiting, so urgent messages can overtake
+        while !closed && lanes.len() < LANE_BUFFER {
+            match input.try_recv() {
+                Ok(raw_msg) => lanes.push(raw_msg.get_priority(), raw_msg),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Lagged(count)) => lagged(count),
+                Err(TryRecvError::Closed) => closed = true,
+            }
+        }
+        let raw_msg = match lanes.pop() {
+            Some(raw_msg) => raw_msg,
+            None => continue,
+        };
+        // it may have waited behind a slow consumer for a while
+        if raw_msg.is_expired() {
+            balancer.record_dropped(&identity);
+            if let Err(error) = expire(&state, raw_msg) {
+                error!(
+                    "relayer expire message for {} failed,error={}",
+                    identity, error
+                );
+            }
+            continue;
+        }
         let res = serde_json::to_string(&raw_msg);
         match res {
             Ok(mut serialized) => {
                 serialized.push_str("/*1^/");
                 if let Err(error) = writer.write_all(serialized.as_bytes()).await {
-                    error!("relayer sender error to write to stream; error = {}", error);
+                    balancer.record_failure(&identity, 1);
+                    dead_letter(&state, &identity, &error.to_string(), raw_msg);
+                } else {
+                    balancer.record_written(&identity);
                 }
             }
             Err(error) => error!("relayer sender serialize message error,error={}", error),
         }
     }
+    // a newer connection of the node keeps its health
+    if !state.route_table.contains(&identity) {
+        balancer.forget(&identity);
+    }
 }
 
-fn transfer_msg<T>(
-    route_table: RouteTable<T>,
-    pub_keys: PubKeyTable,
-    mut parsed: T,
+/// Route one parsed message as if it arrived on the connection of `identity`.
+///
+/// The signature is checked on a blocking worker before any table is locked,
+/// so verification on one connection never holds up routing on another.
+/// Messages of one connection still go through one at a time, in order.
+pub async fn dispatch_msg<T>(
+    state: &RelayerState<T>,
+    identity: &NodeId,
+    parsed: T,
+) -> Result<(), String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let (parsed, checked) = {
+        let _permit = state.verifier.acquire().await?;
+        let state = state.clone();
+        let identity = identity.clone();
+        tokio::task::spawn_blocking(move || {
+            let checked = check_msg(&state, &identity, &parsed);
+            (parsed, checked)
+        })
+        .await
+        .map_err(|err| err.to_string())?
+    };
+    finish_transfer(state, identity, parsed, checked)
+}
+
+/// Route a message whose check is done. When an audit log is configured every
+/// call leaves exactly one entry in it, whatever the outcome.
+fn finish_transfer<T>(
+    state: &RelayerState<T>,
+    identity: &NodeId,
+    parsed: T,
+    checked: Result<(), String>,
 ) -> Result<(), String>
 where
-    T: Send + 'static + Serialize + DeserializeOwned + Router<String> + Message + Clone,
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let record = state
+        .audit
+        .as_ref()
+        .map(|_| AuditRecord::from_message(&parsed, &identity.to_string()));
+    let res = match checked {
+        Ok(()) => route_msg(state, parsed),
+        Err(error) => {
+            dead_letter(state, identity, &error, parsed);
+            Err(error)
+        }
+    };
+    if let Some(record) = record {
+        let decision = match &res {
+            Ok(decision) => decision.clone(),
+            Err(error) => AuditDecision::Rejected(error.clone()),
+        };
+        write_audit(state, record, decision);
+    }
+    res.map(|_| ())
+}
+
+fn write_audit<T>(state: &RelayerState<T>, record: AuditRecord, decision: AuditDecision) {
+    let audit = match &state.audit {
+        Some(audit) => audit,
+        None => return,
+    };
+    if let Err(error) = audit.append(record, decision) {
+        error!("relayer write audit log failed,error={}", error);
+    }
+}
+
+/// A message claiming any source other than the connection's own identity is
+/// rejected before its signature is even looked at. The signature must cover
+/// the `name@group` id, or the run-together one while `legacy_id_signatures`
+/// is on.
+fn check_msg<T>(state: &RelayerState<T>, identity: &NodeId, parsed: &T) -> Result<(), String>
+where
+    T: Message + Router<NodeId>,
+{
+    let source_id = parsed.get_source_id();
+    check_connection_identity(&source_id, identity)?;
+    // error replies, reports and relayer signatures only come from relayers
+    if parsed.is_from_relayer() || parsed.get_relayer_nonce().is_some() {
+        return Err("node message carries relayer fields".to_string());
+    }
+    let public_key = state.pub_keys.get(&source_id).ok_or("miss public key")?;
+    let sign = parsed.get_signature().ok_or("miss signature")?;
+    let error = match state
+        .verifier
+        .verify(&source_id.to_string(), &public_key, sign)
+    {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+    };
+    // nodes from before structured ids sign the run-together form
+    if !state.legacy_id_signatures
+        || state
+            .verifier
+            .verify(&source_id.legacy(), &public_key, sign)
+            .is_err()
+    {
+        return Err(error);
+    }
+    debug!("relayer accept legacy id signature of {}", source_id);
+    Ok(())
+}
+
+fn route_msg<T>(state: &RelayerState<T>, parsed: T) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    if parsed.is_expired() {
+        return expire(state, parsed);
+    }
+    if let Some(deliver_at) = parsed.get_deliver_at() {
+        if deliver_at > now_millis() {
+            let owner = parsed.get_source_id().to_string();
+            state.scheduler.schedule(&owner, parsed, deliver_at)?;
+            return Ok(AuditDecision::Scheduled);
+        }
+    }
+    match parsed.get_delivery() {
+        Delivery::Unicast => route_unicast(state, parsed),
+        Delivery::Multicast {
+            exclude_sender,
+            report,
+        } => route_multicast(state, parsed, exclude_sender, report),
+        Delivery::Broadcast {
+            exclude_sender,
+            report,
+        } => route_broadcast(state, parsed, exclude_sender, report),
+        Delivery::Anycast(strategy) => route_anycast(state, parsed, &strategy),
+        Delivery::Partitioned { key } => route_partitioned(state, parsed, &key),
+        Delivery::Publish { topic } => route_publish(state, parsed, &topic),
+        Delivery::Subscribe { pattern } => {
+            match state.topics.subscribe(&parsed.get_source_id(), &pattern) {
+                Ok(()) => Ok(AuditDecision::Accepted),
+                Err(error) => bounce(state, parsed, &error),
+            }
+        }
+        Delivery::Unsubscribe { pattern } => {
+            state
+                .topics
+                .unsubscribe(&parsed.get_source_id(), &pattern)?;
+            Ok(AuditDecision::Accepted)
+        }
+    }
+}
+
+/// Only looks up and clones the sender under the lock; signing and sending
+/// happen after it is released.
+fn route_unicast<T>(state: &RelayerState<T>, mut parsed: T) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
 {
     let id = parsed.get_target_id();
+    let target = state.route_table.get(&id);
+    if let (None, Some(federation)) = (&target, &state.federation) {
+        if let Some(via) = federation.route_of(&id) {
+            let path = vec![federation.relayer_id.clone()];
+            return forward(state, &via, path, Scope::Node { id }, parsed);
+        }
+    }
+    if let Some(parking) = parking_of(state, &id) {
+        let known = target.is_some() || state.pub_keys.contains(&id);
+        if known {
+            let parked = parking.park(&id, parsed, target.is_some());
+            dead_letter_expired(state, &parking);
+            match parked {
+                Ok(ParkResult::Parked) => return Ok(AuditDecision::Parked),
+                Ok(ParkResult::Pass(item)) => parsed = item,
+                Ok(ParkResult::Full(item)) => return bounce(state, item, "offline queue full"),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+    if let Some(sender) = target {
+        log_send(state, &id, &sender, parsed);
+        Ok(AuditDecision::Delivered)
+    } else {
+        bounce(state, parsed, "can't find target")
+    }
+}
+
+/// Send a copy to every member of the target group, renamed to that member,
+/// and one to every peer relayer serving members of it. An empty group
+/// bounces like an unknown unicast target.
+fn route_multicast<T>(
+    state: &RelayerState<T>,
+    parsed: T,
+    exclude_sender: bool,
+    report: bool,
+) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let group = parsed.get_target_group();
+    let members = collect_members(state, Some(&group))?;
+    let remote = forward_to_group(state, &parsed, &group);
+    route_fanout(
+        state,
+        parsed,
+        members,
+        remote,
+        exclude_sender,
+        report,
+        "can't find target group",
+    )
+}
+
+/// Deliver to one connected, healthy member of the target group. If the
+/// chosen member's channel refuses the message the next one in line gets it.
+fn route_anycast<T>(
+    state: &RelayerState<T>,
+    parsed: T,
+    strategy: &AnycastStrategy,
+) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let group = parsed.get_target_group();
+    let members: Vec<Member<T>> = collect_members(state, Some(&group))?
+        .into_iter()
+        .filter(|member| member.is_connected())
+        .collect();
+    let ids: Vec<NodeId> = members.iter().map(|member| member.id.clone()).collect();
+    for index in state.balancer.rank(&group, &ids, strategy) {
+        let member = &members[index];
+        let mut copy = parsed.clone();
+        copy.set_target(member.id.name(), member.id.group());
+        if member.send(state, copy).is_ok() {
+            return Ok(AuditDecision::Delivered);
+        }
+    }
+    bounce(state, parsed, "no healthy member in target group")
+}
+
+/// Deliver to the connected member of the target group that owns `key`.
+fn route_partitioned<T>(
+    state: &RelayerState<T>,
+    mut parsed: T,
+    key: &str,
+) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let group = parsed.get_target_group();
+    let members: Vec<Member<T>> = collect_members(state, Some(&group))?
+        .into_iter()
+        .filter(|member| member.is_connected())
+        .collect();
+    let ids: Vec<String> = members.iter().map(|member| member.id.to_string()).collect();
+    match partition::pick_member(&ids, key) {
+        Some(index) => {
+            let member = &members[index];
+            parsed.set_target(member.id.name(), member.id.group());
+            if let Err((error, parsed)) = member.send(state, parsed) {
+                dead_letter(state, &member.id, &error, parsed);
+                return Err(error);
+            }
+            Ok(AuditDecision::Delivered)
+        }
+        None => bounce(state, parsed, "can't find target group"),
+    }
+}
+
+/// Send a copy to every node subscribed to `topic`. Nobody subscribing isn't
+/// an error, the message is just dropped.
+fn route_publish<T>(
+    state: &RelayerState<T>,
+    parsed: T,
+    topic: &str,
+) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    if let Err(error) = topic::check_topic(topic) {
+        return bounce(state, parsed, &error);
+    }
+    let subscribers: HashSet<NodeId> = state.topics.subscribers(topic)?.into_iter().collect();
+    let members: Vec<Member<T>> = collect_members(state, None)?
+        .into_iter()
+        .filter(|member| subscribers.contains(&member.id))
+        .collect();
+    if members.is_empty() {
+        debug!("relayer publish to {} without subscribers", topic);
+        return Ok(AuditDecision::Fanout {
+            delivered: 0,
+            total: 0,
+        });
+    }
+    route_fanout(
+        state,
+        parsed,
+        members,
+        Vec::new(),
+        false,
+        false,
+        "no subscriber",
+    )
+}
+
+/// Send a copy to every registered node, if the sender may broadcast at all.
+fn route_broadcast<T>(
+    state: &RelayerState<T>,
+    parsed: T,
+    exclude_sender: bool,
+    report: bool,
+) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let source_id = parsed.get_source_id();
+    let permitted = state
+        .broadcasters
+        .lock()
+        .map_err(|err| err.to_string())?
+        .contains(&source_id);
+    if !permitted {
+        return bounce(state, parsed, "broadcast not permitted");
+    }
+    let members = collect_members(state, None)?;
+    let decision = route_fanout(
+        state,
+        parsed,
+        members,
+        Vec::new(),
+        exclude_sender,
+        report,
+        "no node registered",
+    )?;
+    info!("relayer broadcast from {}, {:?}", source_id, decision);
+    Ok(decision)
+}
+
+/// Broadcast a notice on behalf of the relayer operator. Every copy is signed
+/// with the relayer key, so machines can tell it from a node's broadcast.
+/// Returns how many nodes it reached.
+pub fn relayer_broadcast<T>(state: &RelayerState<T>, notice: T) -> Result<usize, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let record = state
+        .audit
+        .as_ref()
+        .map(|_| AuditRecord::from_message(&notice, "relayer"));
+    let members = collect_members(state, None)?;
+    let total = members.len();
+    let mut delivered = 0;
+    for member in members {
+        let mut copy = notice.clone();
+        copy.set_target(member.id.name(), member.id.group());
+        relayer_sign(&mut copy, &state.identity_key)?;
+        if member.send(state, copy).is_ok() {
+            delivered += 1;
+        }
+    }
+    info!(
+        "relayer broadcast notice reach {} of {} nodes",
+        delivered, total
+    );
+    if let Some(record) = record {
+        write_audit(state, record, AuditDecision::Fanout { delivered, total });
+    }
+    Ok(delivered)
+}
+
+/// A registered node as seen when fanning out.
+struct Member<T> {
+    id: NodeId,
+    sender: Option<BcMsgSender<T>>,
+}
+
+impl<T> Member<T> {
+    fn send(&self, state: &RelayerState<T>, item: T) -> Result<(), (String, T)> {
+        match &self.sender {
+            Some(sender) => channel_send(state, &self.id, sender, item),
+            None => Err(("route gone".to_string(), item)),
+        }
+    }
+
+    /// Still has a live `do_send` task behind its channel.
+    fn is_connected(&self) -> bool {
+        self.sender
+            .as_ref()
+            .is_some_and(|sender| sender.receiver_count() > 0)
+    }
+}
+
+/// Members of `group`, or of every group, with their senders cloned so the
+/// locks are released before anything is sent.
+fn collect_members<T>(
+    state: &RelayerState<T>,
+    group: Option<&str>,
+) -> Result<Vec<Member<T>>, String> {
+    let groups = state.groups.lock().map_err(|err| err.to_string())?;
+    let members = groups
+        .iter()
+        .filter(|(name, _)| group.is_none_or(|group| group == name.as_str()))
+        .flat_map(|(_, members)| members)
+        .map(|id| Member {
+            id: id.clone(),
+            sender: state.route_table.get(id),
+        })
+        .collect();
+    Ok(members)
+}
+
+/// Copy `parsed` to each member, then optionally report back to the sender.
+/// `remote` holds the copies already forwarded to peer relayers, counted one
+/// per relayer. Nobody to send to bounces the message with `empty_error`.
+fn route_fanout<T>(
+    state: &RelayerState<T>,
+    mut parsed: T,
+    members: Vec<Member<T>>,
+    remote: Vec<DeliveryResult>,
+    exclude_sender: bool,
+    report: bool,
+    empty_error: &str,
+) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let source_id = parsed.get_source_id();
+    let members: Vec<Member<T>> = members
+        .into_iter()
+        .filter(|member| !(exclude_sender && member.id == source_id))
+        .collect();
+    if members.is_empty() && remote.is_empty() {
+        return bounce(state, parsed, empty_error);
+    }
+
+    let total = members.len() + remote.len();
+    let mut results = Vec::with_capacity(total);
+    for member in members {
+        let mut copy = parsed.clone();
+        copy.set_target(member.id.name(), member.id.group());
+        let error = match member.send(state, copy) {
+            Ok(()) => None,
+            Err((error, copy)) => {
+                dead_letter(state, &member.id, &error, copy);
+                Some(error)
+            }
+        };
+        results.push(DeliveryResult {
+            error,
+            target: member.id.to_string(),
+        });
+    }
+    results.extend(remote);
+    let delivered = results.iter().filter(|res| res.error.is_none()).count();
+    if report {
+        parsed.set_control(ControlEvent::DeliveryReport(results));
+        relayer_sign(&mut parsed, &state.identity_key)?;
+        send_to_source(state, &source_id, parsed)?;
+    }
+    Ok(AuditDecision::Fanout { delivered, total })
+}
+
+/// Send a message of the relayer to the node `source_id`, connected here or
+/// to a peer relayer.
+fn send_to_source<T>(state: &RelayerState<T>, source_id: &NodeId, item: T) -> Result<(), String>
+where
+    T: Router<NodeId> + Message,
+{
+    if let Some(sender) = state.route_table.get(source_id) {
+        log_send(state, source_id, &sender, item);
+        return Ok(());
+    }
+    if let Some(federation) = &state.federation {
+        if let Some(via) = federation.route_of(source_id) {
+            let path = vec![federation.relayer_id.clone()];
+            let scope = Scope::Node {
+                id: source_id.clone(),
+            };
+            return forward(state, &via, path, scope, item).map(|_| ());
+        }
+    }
+    Err("relayer can't find source".to_string())
+}
+
+/// Drop an expired message, sending its notice back if the sender asked.
+fn expire<T>(state: &RelayerState<T>, mut parsed: T) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let source_id = parsed.get_source_id();
+    dead_letter(state, &source_id, "message expired", parsed.clone());
+    if !parsed.wants_expiry_notice() {
+        return Ok(AuditDecision::Rejected("message expired".to_string()));
+    }
+    parsed.set_expired();
+    send_back(state, parsed, "message expired")
+}
+
+/// Keep the message as a dead letter and send it back to its source with
+/// `reason`, signed by the relayer.
+fn bounce<T>(state: &RelayerState<T>, parsed: T, reason: &str) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    dead_letter(state, parsed.get_source_id(), reason, parsed.clone());
+    send_back(state, parsed, reason)
+}
+
+fn send_back<T>(
+    state: &RelayerState<T>,
+    mut parsed: T,
+    reason: &str,
+) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
     let source_id = parsed.get_source_id();
-    let mut route_table = route_table.lock().map_err(|err| err.to_string())?;
-    let pub_keys = pub_keys.lock().map_err(|err| err.to_string())?;
-    verify_signature(&parsed, pub_keys.get(&source_id))?;
-    if let Some(sender) = route_table.get_mut(&id) {
-        channel_send(sender, parsed)
+    parsed.set_error_msg(Box::new(reason.to_string()));
+    relayer_sign(&mut parsed, &state.identity_key)?;
+    send_to_source(state, &source_id, parsed)?;
+    Ok(AuditDecision::Bounced)
+}
+
+/// Keep `parsed` in the dead-letter queue, with why it wasn't delivered.
+fn dead_letter<T>(state: &RelayerState<T>, connection: impl fmt::Display, reason: &str, parsed: T)
+where
+    T: Router<NodeId>,
+{
+    error!(
+        "relayer dead letter from {} to {},error={}",
+        parsed.get_source_id(),
+        parsed.get_target_id(),
+        reason
+    );
+    state
+        .dead_letters
+        .record(&connection.to_string(), reason, parsed);
+}
+
+/// Move what the store-and-forward queues dropped for their age to the
+/// dead-letter queue.
+fn dead_letter_expired<T>(state: &RelayerState<T>, parking: &Parking<T>)
+where
+    T: Serialize + DeserializeOwned + Router<NodeId> + Clone,
+{
+    for (id, item) in parking.take_expired() {
+        dead_letter(state, &id, "offline queue ttl passed", item);
+    }
+}
+
+/// Take dead letter `id` out of the queue and route it again as if its source
+/// sent it now, signature check included. If it fails again it comes back
+/// under a new id.
+pub async fn redeliver_dead_letter<T>(state: &RelayerState<T>, id: u64) -> Result<(), String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let letter = state
+        .dead_letters
+        .inspect(id)
+        .ok_or_else(|| format!("no dead letter {}", id))?;
+    if letter.message.is_from_relayer() {
+        return Err("a message of the relayer can't be redelivered".to_string());
+    }
+    state.dead_letters.purge(id);
+    let source_id = letter.message.get_source_id();
+    dispatch_msg(state, &source_id, letter.message).await
+}
+
+fn check_connection_identity(source_id: &NodeId, identity: &NodeId) -> Result<(), String> {
+    if source_id != identity {
+        return Err(format!(
+            "source id not match connection,source={},connection={}",
+            source_id, identity
+        ));
+    }
+    Ok(())
+}
+
+/// Queue `item` on the channel of `id`, keeping its in-flight count. A
+/// refused item comes back with the error.
+fn channel_send<T>(
+    state: &RelayerState<T>,
+    id: &NodeId,
+    sender: &BcMsgSender<T>,
+    item: T,
+) -> Result<(), (String, T)> {
+    state.balancer.record_sent(id);
+    sender.send(item).map(|_| ()).map_err(|error| {
+        state.balancer.record_failure(id, 1);
+        (
+            format!("relayer channel transfer failed,error={}", error),
+            error.0,
+        )
+    })
+}
+
+fn log_send<T>(state: &RelayerState<T>, id: &NodeId, sender: &BcMsgSender<T>, item: T)
+where
+    T: Router<NodeId>,
+{
+    if let Err((error, item)) = channel_send(state, id, sender, item) {
+        dead_letter(state, id, &error, item);
+    }
+}
+
+/// Peer relayers that don't finish the handshake in time are dropped.
+const PEER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Frames waiting for a peer link before it counts as failed.
+const PEER_BUFFER: usize = 1024;
+
+/// Accept links of peer relayers on `listener`.
+pub async fn listen_peers<T>(state: RelayerState<T>, listener: TcpListener) -> Result<(), String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    loop {
+        let (stream, addr) = listener.accept().await.map_err(|err| err.to_string())?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            match peer_handshake(&state, stream, false).await {
+                Ok((peer, reader, link)) => receive_peer(state, peer, reader, link).await,
+                Err(error) => error!("relayer peer link from {} failed,error={}", addr, error),
+            }
+        });
+    }
+}
+
+/// Link to the peer relayer listening on `addr`. Returns the peer's id once
+/// both sides proved theirs.
+pub async fn connect_peer<T>(state: RelayerState<T>, addr: &str) -> Result<String, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|err| err.to_string())?;
+    let (peer, reader, link) = peer_handshake(&state, stream, true).await?;
+    tokio::spawn(receive_peer(state, peer.clone(), reader, link));
+    Ok(peer)
+}
+
+/// Both sides send a nonce and sign the other's with their relayer key; a
+/// peer whose key isn't pinned is refused. The `dialing` side proves its id
+/// first, so the listening side only signs for a peer it verified. Then the
+/// link gets its writer and the routes known so far.
+async fn peer_handshake<T>(
+    state: &RelayerState<T>,
+    stream: TcpStream,
+    dialing: bool,
+) -> Result<(String, LinkReader, mpsc::Sender<PeerFrame<T>>), String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let federation = state.federation.as_ref().ok_or("federation not enabled")?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = LinkReader::new(reader, state.limits.max_frame_size);
+    let peer = tokio::time::timeout(PEER_HANDSHAKE_TIMEOUT, async {
+        let nonce: u64 = rand::random();
+        let hello = PeerFrame::<T>::Hello {
+            relayer_id: federation.relayer_id.clone(),
+            nonce,
+        };
+        write_frame(&mut writer, hello).await?;
+        let (peer, peer_nonce) = match reader.next::<PeerFrame<T>>().await? {
+            Some(PeerFrame::Hello { relayer_id, nonce }) => (relayer_id, nonce),
+            _ => return Err("peer didn't say hello".to_string()),
+        };
+        let key = federation
+            .peer_key(&peer)
+            .ok_or_else(|| format!("unknown peer {}", peer))?;
+        let own_id = &federation.relayer_id;
+        let sig = sign(&auth_data(peer_nonce, own_id, &peer), &state.identity_key)?;
+        if dialing {
+            write_frame(&mut writer, PeerFrame::<T>::Auth { sig: sig.clone() }).await?;
+        }
+        match reader.next::<PeerFrame<T>>().await? {
+            Some(PeerFrame::Auth { sig }) => verify(&auth_data(nonce, &peer, own_id), &key, &sig)?,
+            _ => return Err(format!("peer {} didn't authenticate", peer)),
+        }
+        if !dialing {
+            write_frame(&mut writer, PeerFrame::<T>::Auth { sig }).await?;
+        }
+        Ok(peer)
+    })
+    .await
+    .map_err(|_| "peer handshake timed out".to_string())??;
+
+    let (link, mut frames) = mpsc::channel::<PeerFrame<T>>(PEER_BUFFER);
+    let write_peer = peer.clone();
+    tokio::spawn(async move {
+        while let Some(frame) = frames.recv().await {
+            if let Err(error) = write_frame(&mut writer, frame).await {
+                error!(
+                    "relayer write to peer {} failed,error={}",
+                    write_peer, error
+                );
+                break;
+            }
+        }
+    });
+    federation.add_link(&peer, link.clone())?;
+    info!("relayer linked with peer {}", peer);
+    for advert in federation.table() {
+        if !advert.path.contains(&peer) {
+            let _ = link.try_send(PeerFrame::Advert(advert));
+        }
+    }
+    advertise(state);
+    Ok((peer, reader, link))
+}
+
+/// Handle what the peer sends until the link closes, then forget the routes
+/// through it.
+async fn receive_peer<T>(
+    state: RelayerState<T>,
+    peer: String,
+    mut reader: LinkReader,
+    link: mpsc::Sender<PeerFrame<T>>,
+) where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let mut bad_frames: u32 = 0;
+    loop {
+        match reader.next::<PeerFrame<T>>().await {
+            Ok(Some(frame)) => receive_peer_frame(&state, &peer, frame),
+            Ok(None) => break,
+            Err(error) => {
+                bad_frames += 1;
+                error!(
+                    "relayer receive bad frame,from peer={},count={},error={}",
+                    peer, bad_frames, error
+                );
+                if bad_frames > state.limits.max_bad_frames {
+                    break;
+                }
+            }
+        }
+    }
+    info!("relayer drop link of peer {}", peer);
+    if let Some(federation) = &state.federation {
+        federation.remove_link(&peer, &link);
+    }
+}
+
+fn receive_peer_frame<T>(state: &RelayerState<T>, peer: &str, frame: PeerFrame<T>)
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let federation = match &state.federation {
+        Some(federation) => federation,
+        None => return,
+    };
+    match frame {
+        PeerFrame::Advert(advert) => {
+            if let Some(advert) = federation.learn(peer, advert) {
+                for (_, link) in federation.links_except(&advert.path) {
+                    let _ = link.try_send(PeerFrame::Advert(advert.clone()));
+                }
+            }
+        }
+        PeerFrame::Forward {
+            path,
+            scope,
+            message,
+        } => receive_forwarded(state, peer, path, scope, message, false),
+        PeerFrame::Notice {
+            path,
+            scope,
+            message,
+        } => receive_forwarded(state, peer, path, scope, message, true),
+        PeerFrame::Hello { .. } | PeerFrame::Auth { .. } => {
+            error!("relayer unexpected handshake from peer {}", peer)
+        }
+    }
+}
+
+fn receive_forwarded<T>(
+    state: &RelayerState<T>,
+    peer: &str,
+    path: Vec<String>,
+    scope: Scope,
+    message: T,
+    notice: bool,
+) where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let record = state
+        .audit
+        .as_ref()
+        .map(|_| AuditRecord::from_message(&message, peer));
+    let res = route_forwarded(state, peer, path, scope, message, notice);
+    if let Err(error) = &res {
+        error!("relayer forwarded msg from {} failed,error={}", peer, error);
+    }
+    if let Some(record) = record {
+        let decision = match res {
+            Ok(decision) => decision,
+            Err(error) => AuditDecision::Rejected(error),
+        };
+        write_audit(state, record, decision);
+    }
+}
+
+/// Route a message a peer forwarded along `path`: deliver it here, or pass it
+/// on towards its target. A message that went through this relayer before, or
+/// through more than `max_hops` relayers, is dropped, and so is one that
+/// carries relayer fields unless it came as a `notice`.
+fn route_forwarded<T>(
+    state: &RelayerState<T>,
+    peer: &str,
+    mut path: Vec<String>,
+    scope: Scope,
+    mut parsed: T,
+    notice: bool,
+) -> Result<AuditDecision, String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let federation = state.federation.as_ref().ok_or("federation not enabled")?;
+    let refused = if path.contains(&federation.relayer_id) {
+        Some("routing loop")
+    } else if path.len() > federation.max_hops {
+        Some("hop limit reached")
+    } else if parsed.is_from_relayer() != notice {
+        Some("relayer fields don't match the frame")
+    } else {
+        None
+    };
+    if let Some(reason) = refused {
+        dead_letter(state, peer, reason, parsed);
+        return Err(reason.to_string());
+    }
+    if parsed.is_expired() {
+        return expire(state, parsed);
+    }
+    // machines here only know the key of this relayer
+    if notice {
+        relayer_sign(&mut parsed, &state.identity_key)?;
+    }
+    path.push(federation.relayer_id.clone());
+    match scope {
+        Scope::Node { id } => {
+            if let Some(sender) = state.route_table.get(&id) {
+                log_send(state, &id, &sender, parsed);
+                return Ok(AuditDecision::Delivered);
+            }
+            if let Some(via) = federation.route_of(&id) {
+                return forward(state, &via, path, Scope::Node { id }, parsed);
+            }
+            if notice {
+                dead_letter(state, peer, "can't find target", parsed);
+                return Err("can't find target".to_string());
+            }
+            route_unicast(state, parsed)
+        }
+        Scope::Group { origin } if origin == federation.relayer_id => {
+            let group = parsed.get_target_group();
+            let members = collect_members(state, Some(&group))?;
+            if members.is_empty() {
+                dead_letter(state, peer, "can't find target group", parsed);
+                return Err("can't find target group".to_string());
+            }
+            route_fanout(state, parsed, members, Vec::new(), false, false, "")
+        }
+        Scope::Group { origin } => match federation.route_to(&origin) {
+            Some(via) => forward(state, &via, path, Scope::Group { origin }, parsed),
+            None => {
+                dead_letter(state, peer, "can't find target relayer", parsed);
+                Err("can't find target relayer".to_string())
+            }
+        },
+    }
+}
+
+/// Hand `parsed` to the link of the peer `via`, as a notice if this or
+/// another relayer made it. A message the link can't take becomes a dead
+/// letter.
+fn forward<T>(
+    state: &RelayerState<T>,
+    via: &str,
+    path: Vec<String>,
+    scope: Scope,
+    parsed: T,
+) -> Result<AuditDecision, String>
+where
+    T: Router<NodeId> + Message,
+{
+    let link = state
+        .federation
+        .as_ref()
+        .and_then(|federation| federation.link(via));
+    let link = match link {
+        Some(link) => link,
+        None => {
+            dead_letter(state, via, "peer link gone", parsed);
+            return Err("peer link gone".to_string());
+        }
+    };
+    let frame = if parsed.is_from_relayer() {
+        PeerFrame::Notice {
+            path,
+            scope,
+            message: parsed,
+        }
     } else {
-        let sender = route_table
-            .get_mut(&source_id)
-            .ok_or("relayer can't find both source and target")?;
-        parsed.set_error_msg(Box::new("can't find target".to_string()));
-        channel_send(sender, parsed)
+        PeerFrame::Forward {
+            path,
+            scope,
+            message: parsed,
+        }
+    };
+    match link.try_send(frame) {
+        Ok(()) => Ok(AuditDecision::Forwarded(via.to_string())),
+        Err(error) => {
+            let reason = format!("relayer peer link failed,error={}", error);
+            let frame = match error {
+                TrySendError::Full(frame) | TrySendError::Closed(frame) => frame,
+            };
+            if let PeerFrame::Forward { message, .. } | PeerFrame::Notice { message, .. } = frame {
+                dead_letter(state, via, &reason, message);
+            }
+            Err(reason)
+        }
+    }
+}
+
+/// Forward one copy to every peer relayer serving members of `group`, to fan
+/// out there. One result per relayer.
+fn forward_to_group<T>(state: &RelayerState<T>, parsed: &T, group: &str) -> Vec<DeliveryResult>
+where
+    T: Router<NodeId> + Message + Clone,
+{
+    let federation = match &state.federation {
+        Some(federation) => federation,
+        None => return Vec::new(),
+    };
+    federation
+        .origins_with_group(group)
+        .into_iter()
+        .filter_map(|origin| {
+            let via = federation.route_to(&origin)?;
+            let path = vec![federation.relayer_id.clone()];
+            let scope = Scope::Group {
+                origin: origin.clone(),
+            };
+            let error = forward(state, &via, path, scope, parsed.clone()).err();
+            Some(DeliveryResult {
+                error,
+                target: origin,
+            })
+        })
+        .collect()
+}
+
+/// Tell every peer which nodes are connected here.
+fn advertise<T>(state: &RelayerState<T>) {
+    let federation = match &state.federation {
+        Some(federation) => federation,
+        None => return,
+    };
+    let nodes = match collect_members(state, None) {
+        Ok(members) => members
+            .into_iter()
+            .filter(|member| member.sender.is_some())
+            .map(|member| member.id)
+            .collect(),
+        Err(error) => {
+            error!("relayer advertise failed,error={}", error);
+            return;
+        }
+    };
+    let advert = federation.own_advert(nodes);
+    for (peer, link) in federation.links_except(&[]) {
+        if let Err(error) = link.try_send(PeerFrame::Advert(advert.clone())) {
+            error!("relayer advertise to {} failed,error={}", peer, error);
+        }
+    }
+}
+
+/// Advertise on every interval, and forget origins that went silent.
+pub async fn run_federation<T>(state: RelayerState<T>) {
+    let federation = match &state.federation {
+        Some(federation) => federation.clone(),
+        None => return,
+    };
+    loop {
+        tokio::time::sleep(federation.advertise_interval).await;
+        advertise(&state);
+        federation.expire_stale();
+    }
+}
+
+/// Frames waiting for a follower before it counts as lagging.
+const CLUSTER_BUFFER: usize = 1024;
+/// An instance that doesn't finish the handshake in time counts as down.
+const CLUSTER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Recipient named in what a follower signs, apart from any peer relayer id.
+const CLUSTER_AUTH: &str = "cluster";
+
+/// Keep the registration of a node in the cluster's tables before it is
+/// connected. Only the leader takes registrations.
+pub fn record_registration<T>(
+    state: &RelayerState<T>,
+    register_info: &RegisterInfo,
+    key: &RsaPublicKey,
+) -> Result<(), String> {
+    check_leader(state)?;
+    match &state.cluster {
+        Some(cluster) => cluster.record(Registration::new(register_info, key)?),
+        None => Ok(()),
+    }
+}
+
+/// Take the registration of a node and keep its key. A moving node must
+/// come back with the key it had.
+pub fn register_key<T>(
+    state: &RelayerState<T>,
+    register_info: &RegisterInfo,
+    key: RsaPublicKey,
+) -> Result<(), String> {
+    let id = register_info.get_source_id()?;
+    if state.migrations.is_moving(&id) && state.pub_keys.get(&id).as_ref() != Some(&key) {
+        return Err(format!("node {} moves under another key", id));
     }
+    record_registration(state, register_info, &key)?;
+    state.pub_keys.insert(id, key);
     Ok(())
 }
 
-fn verify_signature<T>(item: &T, public_key: Option<&RsaPublicKey>) -> Result<(), String>
+/// Fails on a cluster instance that doesn't lead.
+fn check_leader<T>(state: &RelayerState<T>) -> Result<(), String> {
+    match &state.cluster {
+        Some(cluster) if !cluster.is_leader() => Err(format!(
+            "relayer instance {} doesn't lead,leader={:?}",
+            cluster.instance_id,
+            cluster.leader()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Play this instance's part in the cluster: serve followers, follow the
+/// leader while there is one, and take over once every instance listed
+/// before this one is gone.
+pub async fn run_cluster<T>(state: RelayerState<T>)
 where
-    T: Message + Router<String>,
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
 {
-    let public_key = public_key.ok_or("miss public key")?;
-    let sign = item.get_signature().ok_or("miss signature")?;
-    verify(&item.get_source_id(), public_key, sign)?;
+    let cluster = match &state.cluster {
+        Some(cluster) => cluster.clone(),
+        None => return,
+    };
+    let listener = match TcpListener::bind(cluster.own_addr().unwrap_or_default()).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("relayer cluster listen failed,error={}", error);
+            return;
+        }
+    };
+    tokio::spawn(serve_followers(state.clone(), listener));
+    'search: loop {
+        let mut outranked = false;
+        for (id, addr) in &cluster.members {
+            if *id == cluster.instance_id {
+                continue;
+            }
+            match join_leader(&state, addr).await {
+                Ok(Some(link)) => {
+                    info!("relayer instance {} follows {}", cluster.instance_id, id);
+                    cluster.set_role(Role::Follower(id.clone()));
+                    follow_leader(&state, link).await;
+                    cluster.set_role(Role::Candidate);
+                    info!(
+                        "relayer instance {} lost leader {}",
+                        cluster.instance_id, id
+                    );
+                    // ask everyone again before deciding anything
+                    continue 'search;
+                }
+                Ok(None) => outranked |= cluster.ranks_before(id),
+                Err(error) => debug!("relayer instance {} unreachable,error={}", id, error),
+            }
+        }
+        if !outranked {
+            take_over(&state);
+            tokio::spawn(send_heartbeats(cluster.clone()));
+            // after a split an instance listed before this one may lead too
+            let (id, mut link) = loop {
+                tokio::time::sleep(cluster.heartbeat_interval).await;
+                if let Some(found) = find_leader_before(&state).await {
+                    break found;
+                }
+            };
+            info!(
+                "relayer instance {} steps down for {}",
+                cluster.instance_id, id
+            );
+            step_down(&state);
+            cluster.set_role(Role::Follower(id.clone()));
+            for frame in cluster.snapshot().unwrap_or_default() {
+                if let Err(error) = write_frame(&mut link.1, frame).await {
+                    error!(
+                        "relayer hand registrations to {} failed,error={}",
+                        id, error
+                    );
+                    break;
+                }
+            }
+            follow_leader(&state, link).await;
+            cluster.set_role(Role::Candidate);
+            continue 'search;
+        }
+        tokio::time::sleep(cluster.heartbeat_interval).await;
+    }
+}
+
+async fn send_heartbeats(cluster: Arc<Cluster>) {
+    while cluster.is_leader() {
+        tokio::time::sleep(cluster.heartbeat_interval).await;
+        cluster.heartbeat();
+    }
+}
+
+/// An instance listed before this one that leads, linked to as its follower.
+async fn find_leader_before<T>(
+    state: &RelayerState<T>,
+) -> Option<(String, (LinkReader, OwnedWriteHalf))> {
+    let cluster = state.cluster.as_ref()?;
+    for (id, addr) in &cluster.members {
+        if !cluster.ranks_before(id) {
+            continue;
+        }
+        if let Ok(Some(link)) = join_leader(state, addr).await {
+            return Some((id.clone(), link));
+        }
+    }
+    None
+}
+
+/// Stop leading: the followers go look for the leader, and the connections
+/// to nodes close, as the leader connects to them.
+fn step_down<T>(state: &RelayerState<T>) {
+    if let Some(cluster) = &state.cluster {
+        cluster.drop_followers();
+    }
+    if let Ok(connections) = state.connections.lock() {
+        for closer in connections.values() {
+            closer.notify_one();
+        }
+    }
+}
+
+/// Link to the instance at `addr`, as its follower if it leads.
+async fn join_leader<T>(
+    state: &RelayerState<T>,
+    addr: &str,
+) -> Result<Option<(LinkReader, OwnedWriteHalf)>, String> {
+    let cluster = state.cluster.as_ref().ok_or("cluster not enabled")?;
+    tokio::time::timeout(CLUSTER_HANDSHAKE_TIMEOUT, async {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|err| err.to_string())?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = LinkReader::new(reader, state.limits.max_frame_size);
+        let nonce = match reader.next::<ClusterFrame>().await? {
+            Some(ClusterFrame::Challenge { nonce }) => nonce,
+            _ => return Err("instance didn't challenge".to_string()),
+        };
+        // instances share the relayer key, so the cluster stands in for the
+        // recipient
+        let data = auth_data(nonce, &cluster.instance_id, CLUSTER_AUTH);
+        let sig = sign(&data, &state.identity_key)?;
+        let follow = ClusterFrame::Follow {
+            instance_id: cluster.instance_id.clone(),
+            sig,
+        };
+        write_frame(&mut writer, follow).await?;
+        match reader.next::<ClusterFrame>().await? {
+            Some(ClusterFrame::Leading) => Ok(Some((reader, writer))),
+            Some(ClusterFrame::NotLeader) => Ok(None),
+            _ => Err("instance refused to be followed".to_string()),
+        }
+    })
+    .await
+    .map_err(|_| "instance didn't answer in time".to_string())?
+}
+
+/// Take in the leader's registrations until it goes silent or the link drops.
+async fn follow_leader<T>(state: &RelayerState<T>, link: (LinkReader, OwnedWriteHalf)) {
+    let cluster = match &state.cluster {
+        Some(cluster) => cluster,
+        None => return,
+    };
+    // the write half stays open for as long as this instance follows
+    let (mut reader, _writer) = link;
+    let silence = cluster.heartbeat_interval * cluster::SILENT_AFTER;
+    loop {
+        match tokio::time::timeout(silence, reader.next::<ClusterFrame>()).await {
+            Ok(Ok(Some(ClusterFrame::Register(registration)))) => {
+                if let Err(error) = apply_registration(state, registration) {
+                    error!("relayer apply registration failed,error={}", error);
+                }
+            }
+            Ok(Ok(Some(ClusterFrame::Unregister(id)))) => {
+                if let Err(error) = apply_unregistration(state, &id) {
+                    error!("relayer apply unregistration failed,error={}", error);
+                }
+            }
+            Ok(Ok(Some(ClusterFrame::Moving(id)))) => {
+                if let Err(error) = apply_move(state, &id, true) {
+                    error!("relayer apply move failed,error={}", error);
+                }
+            }
+            Ok(Ok(Some(ClusterFrame::Moved(id)))) => {
+                if let Err(error) = apply_move(state, &id, false) {
+                    error!("relayer apply move failed,error={}", error);
+                }
+            }
+            Ok(Ok(Some(_))) => (),
+            Ok(Ok(None)) => break,
+            Ok(Err(error)) => {
+                error!("relayer bad frame from leader,error={}", error);
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+fn apply_registration<T>(
+    state: &RelayerState<T>,
+    registration: Registration,
+) -> Result<(), String> {
+    let key = registration.public_key()?;
+    state.pub_keys.insert(registration.id()?, key);
+    if let Some(cluster) = &state.cluster {
+        cluster.record(registration)?;
+    }
     Ok(())
 }
 
-fn channel_send<T>(sender: &BcMsgSender<T>, item: T) {
-    if let Err(error) = sender.send(item) {
-        error!("relayer channel transfer failed,error={}", error);
+fn apply_unregistration<T>(state: &RelayerState<T>, id: &NodeId) -> Result<(), String> {
+    state.pub_keys.remove(id);
+    if let Some(cluster) = &state.cluster {
+        cluster.forget(id)?;
+    }
+    Ok(())
+}
+
+/// Mark `id` as moving, or done moving, as the leader did. The deadline of
+/// the move runs from here.
+fn apply_move<T>(state: &RelayerState<T>, id: &NodeId, moving: bool) -> Result<(), String> {
+    let cluster = state.cluster.as_ref().ok_or("cluster not enabled")?;
+    if moving {
+        state.migrations.begin(id)?;
+        cluster.begin_move(id)
+    } else {
+        state.migrations.end(id);
+        cluster.end_move(id)
+    }
+}
+
+/// Lead from now on, and connect to every node registered so far.
+fn take_over<T>(state: &RelayerState<T>)
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let cluster = match &state.cluster {
+        Some(cluster) => cluster,
+        None => return,
+    };
+    cluster.set_role(Role::Leader);
+    let registrations = cluster.registrations();
+    info!(
+        "relayer instance {} leads,nodes={}",
+        cluster.instance_id,
+        registrations.len()
+    );
+    for registration in registrations {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let register_info = registration.register_info();
+            if let Err(error) = relayer_connect(&register_info, state).await {
+                error!(
+                    "relayer reconnect to {} failed,error={}",
+                    register_info.addr, error
+                );
+            }
+        });
     }
 }
 
+/// Take the registrations and moves a follower that led before hands over,
+/// and connect to their nodes.
+async fn take_handed<T>(state: RelayerState<T>, mut reader: LinkReader, follower: String)
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    while let Ok(Some(frame)) = reader.next::<ClusterFrame>().await {
+        let registration = match frame {
+            ClusterFrame::Register(registration) => registration,
+            ClusterFrame::Moving(id) => {
+                if let Err(error) = apply_move(&state, &id, true) {
+                    error!("relayer take move of {} failed,error={}", follower, error);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        let register_info = registration.register_info();
+        if let Err(error) = apply_registration(&state, registration) {
+            error!(
+                "relayer take registration of {} failed,error={}",
+                follower, error
+            );
+            continue;
+        }
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(error) = relayer_connect(&register_info, state).await {
+                error!(
+                    "relayer connect to {} failed,error={}",
+                    register_info.addr, error
+                );
+            }
+        });
+    }
+}
 
+/// Accept links of other instances. Those proving they hold the shared key
+/// follow this one while it leads.
+async fn serve_followers<T>(state: RelayerState<T>, listener: TcpListener)
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                error!("relayer cluster accept failed,error={}", error);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(error) = serve_follower(state, stream).await {
+                debug!("relayer cluster link from {} ended,error={}", addr, error);
+            }
+        });
+    }
+}
+
+async fn serve_follower<T>(state: RelayerState<T>, stream: TcpStream) -> Result<(), String>
+where
+    T: Send + 'static + Serialize + DeserializeOwned + Router<NodeId> + Message + Clone,
+{
+    let cluster = state.cluster.as_ref().ok_or("cluster not enabled")?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = LinkReader::new(reader, state.limits.max_frame_size);
+    let nonce: u64 = rand::random();
+    write_frame(&mut writer, ClusterFrame::Challenge { nonce }).await?;
+    let answer = tokio::time::timeout(CLUSTER_HANDSHAKE_TIMEOUT, reader.next::<ClusterFrame>())
+        .await
+        .map_err(|_| "follower didn't answer in time".to_string())??;
+    let follower = match answer {
+        Some(ClusterFrame::Follow { instance_id, sig }) => {
+            let key = RsaPublicKey::from(state.identity_key.as_ref());
+            verify(&auth_data(nonce, &instance_id, CLUSTER_AUTH), &key, &sig)?;
+            instance_id
+        }
+        _ => return Err("instance didn't follow".to_string()),
+    };
+    if !cluster.is_leader() {
+        return write_frame(&mut writer, ClusterFrame::NotLeader).await;
+    }
+    write_frame(&mut writer, ClusterFrame::Leading).await?;
+    tokio::spawn(take_handed(state.clone(), reader, follower.clone()));
+    let (sender, mut frames) = mpsc::channel(CLUSTER_BUFFER);
+    for frame in cluster.add_follower(sender)? {
+        write_frame(&mut writer, frame).await?;
+    }
+    info!(
+        "relayer instance {} followed by {}",
+        cluster.instance_id, follower
+    );
+    while let Some(frame) = frames.recv().await {
+        write_frame(&mut writer, frame).await?;
+    }
+    Ok(())
+}
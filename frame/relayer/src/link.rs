@@ -0,0 +1,64 @@
+//! Framing of the links between relayers, for peers and cluster instances
+//! alike: one JSON value per frame, split by the message delimiter.
+
+use std::collections::VecDeque;
+
+use frame_common::{
+    codec::{FrameDecoder, DELIMITER},
+    parse_message,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+};
+
+/// Reads frames off the read half of a link.
+pub struct LinkReader {
+    reader: OwnedReadHalf,
+    decoder: FrameDecoder,
+    frames: VecDeque<Result<String, String>>,
+}
+
+impl LinkReader {
+    pub fn new(reader: OwnedReadHalf, max_frame_size: usize) -> LinkReader {
+        LinkReader {
+            reader,
+            decoder: FrameDecoder::new(max_frame_size),
+            frames: VecDeque::new(),
+        }
+    }
+
+    /// The next frame, `None` once the link closed or broke.
+    pub async fn next<F: Serialize + DeserializeOwned>(&mut self) -> Result<Option<F>, String> {
+        let mut buf = [0; 4096];
+        while self.frames.is_empty() {
+            let size = match self.reader.read(&mut buf).await {
+                Ok(size) if size > 0 => size,
+                _ => return Ok(None),
+            };
+            self.frames.extend(self.decoder.push(&buf[..size]));
+        }
+        let frame = self.frames.pop_front().unwrap()?;
+        parse_message(&frame).map(Some)
+    }
+
+    /// The read half back, once the other side waits for an answer, so
+    /// nothing beyond the frames taken was read.
+    pub fn into_inner(self) -> OwnedReadHalf {
+        self.reader
+    }
+}
+
+/// Write one frame to a link.
+pub async fn write_frame<F: Serialize>(
+    writer: &mut OwnedWriteHalf,
+    frame: F,
+) -> Result<(), String> {
+    let mut serialized = serde_json::to_string(&frame).map_err(|err| err.to_string())?;
+    serialized.push_str(DELIMITER);
+    writer
+        .write_all(serialized.as_bytes())
+        .await
+        .map_err(|err| err.to_string())
+}
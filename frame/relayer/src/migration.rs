@@ -0,0 +1,114 @@
+//! Nodes moving from one machine to another.
+//!
+//! While a node moves the relayer keeps its key and parks what is sent to
+//! it: in the store-and-forward queues if those are on, in queues of its own
+//! if not. The node registers again at its new address under the same key,
+//! the parked messages are flushed to the new connection, and the move ends.
+//! A node that isn't back within `timeout` stops moving, and what its own
+//! queues parked is bounced.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use frame_common::id::NodeId;
+
+use crate::parking::{Parking, ParkingLimits};
+
+/// How long a node may take to register again at its new address.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Migrations<M> {
+    pub timeout: Duration,
+    /// deadline of every moving node
+    moving: Mutex<HashMap<NodeId, Instant>>,
+    /// holds the messages of moving nodes when store-and-forward is off
+    pub parking: Arc<Parking<M>>,
+}
+
+impl<M> Default for Migrations<M> {
+    fn default() -> Self {
+        Migrations::new(DEFAULT_TIMEOUT)
+    }
+}
+
+impl<M> Migrations<M> {
+    pub fn new(timeout: Duration) -> Migrations<M> {
+        Migrations {
+            timeout,
+            moving: Mutex::new(HashMap::new()),
+            parking: Arc::new(Parking::new(ParkingLimits::default())),
+        }
+    }
+
+    /// A node moving already gets a new deadline.
+    pub fn begin(&self, id: &NodeId) -> Result<(), String> {
+        let mut moving = self.moving.lock().map_err(|err| err.to_string())?;
+        moving.insert(id.clone(), Instant::now() + self.timeout);
+        Ok(())
+    }
+
+    pub fn is_moving(&self, id: &NodeId) -> bool {
+        self.moving
+            .lock()
+            .map(|moving| moving.contains_key(id))
+            .unwrap_or(false)
+    }
+
+    /// The earliest deadline of a moving node.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.moving
+            .lock()
+            .ok()
+            .and_then(|moving| moving.values().min().copied())
+    }
+
+    /// End the moves past their deadline, returning their nodes.
+    pub fn take_overdue(&self) -> Vec<NodeId> {
+        let now = Instant::now();
+        let mut overdue = Vec::new();
+        if let Ok(mut moving) = self.moving.lock() {
+            moving.retain(|id, deadline| {
+                let late = *deadline <= now;
+                if late {
+                    overdue.push(id.clone());
+                }
+                !late
+            });
+        }
+        overdue
+    }
+
+    /// False if `id` wasn't moving.
+    pub fn end(&self, id: &NodeId) -> bool {
+        self.moving
+            .lock()
+            .map(|mut moving| moving.remove(id).is_some())
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overdue_moves_end() {
+        let migrations: Migrations<String> = Migrations::new(Duration::from_millis(100));
+        let id = |name: &str| NodeId::new(name, "b").unwrap();
+        migrations.begin(&id("b1")).unwrap();
+        assert!(migrations.take_overdue().is_empty());
+        std::thread::sleep(Duration::from_millis(50));
+        migrations.begin(&id("b2")).unwrap();
+        std::thread::sleep(Duration::from_millis(70));
+
+        assert_eq!(vec![id("b1")], migrations.take_overdue());
+        assert!(!migrations.is_moving(&id("b1")));
+        assert!(migrations.is_moving(&id("b2")));
+        assert!(migrations.next_deadline().unwrap() > Instant::now());
+        assert!(migrations.end(&id("b2")));
+        assert_eq!(None, migrations.next_deadline());
+    }
+}
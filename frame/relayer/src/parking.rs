@@ -0,0 +1,296 @@
+//! Store-and-forward queues for nodes that are known but offline.
+//!
+//! A unicast message for a node whose key the relayer knows, but that has no
+//! connection, is parked instead of bounced and handed over in order once the
+//! node connects again. Messages older than `ttl` are dropped, and a node's
+//! queue holds at most `max_per_node` messages. Opened on a directory, the
+//! queues are kept in a write-ahead log and survive a restart.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use frame_common::id::NodeId;
+use log::{debug, error, info};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::wal::{Wal, WalEntry};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParkingLimits {
+    pub ttl: Duration,
+    pub max_per_node: usize,
+}
+
+impl Default for ParkingLimits {
+    fn default() -> Self {
+        ParkingLimits {
+            ttl: Duration::from_secs(3600),
+            max_per_node: 1024,
+        }
+    }
+}
+
+pub enum ParkResult<M> {
+    Parked,
+    /// the node is connected and nothing is queued for it, send it directly
+    Pass(M),
+    /// the node's queue is at `max_per_node`
+    Full(M),
+}
+
+struct Queue<M> {
+    items: VecDeque<WalEntry<M>>,
+    /// items at the front handed out by `pop` but not `done` yet
+    sent: usize,
+}
+
+impl<M> Default for Queue<M> {
+    fn default() -> Self {
+        Queue {
+            items: VecDeque::new(),
+            sent: 0,
+        }
+    }
+}
+
+struct Queues<M> {
+    /// a queue stays in the map, maybe empty, until its flush finishes
+    by_id: HashMap<NodeId, Queue<M>>,
+    next_seq: u64,
+    wal: Option<Wal>,
+    /// dropped for their age, until `take_expired` collects them
+    expired: Vec<(NodeId, M)>,
+}
+
+pub struct Parking<M> {
+    limits: ParkingLimits,
+    queues: Mutex<Queues<M>>,
+}
+
+impl<M> Parking<M> {
+    /// Queues kept in memory only.
+    pub fn new(limits: ParkingLimits) -> Parking<M> {
+        Parking {
+            limits,
+            queues: Mutex::new(Queues {
+                by_id: HashMap::new(),
+                next_seq: 0,
+                wal: None,
+                expired: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl<M> Parking<M>
+where
+    M: Serialize + DeserializeOwned + Clone,
+{
+    /// Queues kept in a write-ahead log in `dir`, with whatever a previous
+    /// run left undelivered there. Queues written under the old run-together
+    /// ids are moved to the id `target_of` gives their messages.
+    pub fn open(
+        limits: ParkingLimits,
+        dir: &str,
+        target_of: impl Fn(&M) -> NodeId,
+    ) -> Result<Parking<M>, String> {
+        let (mut wal, replayed) = Wal::open::<M>(dir)?;
+        let next_seq = replayed
+            .values()
+            .flatten()
+            .map(|entry| entry.seq + 1)
+            .max()
+            .unwrap_or(0);
+        let mut by_id: HashMap<NodeId, Queue<M>> = HashMap::new();
+        for (wal_id, entries) in replayed {
+            if let Ok(id) = wal_id.parse::<NodeId>() {
+                by_id.entry(id).or_default().items.extend(entries);
+                continue;
+            }
+            info!(
+                "relayer migrate {} parked messages of {}",
+                entries.len(),
+                wal_id
+            );
+            for entry in entries {
+                let id = target_of(&entry.item);
+                wal.push(&id.to_string(), &entry)?;
+                by_id.entry(id).or_default().items.push_back(entry);
+            }
+            wal.compact::<M>(&wal_id, std::iter::empty())?;
+        }
+        for queue in by_id.values_mut() {
+            queue.items.make_contiguous().sort_by_key(|entry| entry.seq);
+        }
+        Ok(Parking {
+            limits,
+            queues: Mutex::new(Queues {
+                by_id,
+                next_seq,
+                wal: Some(wal),
+                expired: Vec::new(),
+            }),
+        })
+    }
+
+    /// Queue `item` for `id` if it is offline, or if older messages for it
+    /// are still queued so they keep their order.
+    pub fn park(&self, id: &NodeId, item: M, connected: bool) -> Result<ParkResult<M>, String> {
+        let mut lock = self.queues.lock().map_err(|err| err.to_string())?;
+        let queues = &mut *lock;
+        if connected && !queues.by_id.contains_key(id) {
+            return Ok(ParkResult::Pass(item));
+        }
+        let queue = queues.by_id.entry(id.clone()).or_default();
+        self.drop_expired(id, queue, &mut queues.wal, &mut queues.expired);
+        if queue.items.len() >= self.limits.max_per_node {
+            return Ok(ParkResult::Full(item));
+        }
+        let entry = WalEntry {
+            seq: queues.next_seq,
+            at: SystemTime::now(),
+            item,
+        };
+        if let Some(wal) = &mut queues.wal {
+            wal.push(&id.to_string(), &entry)?;
+        }
+        queues.next_seq += 1;
+        queue.items.push_back(entry);
+        Ok(ParkResult::Parked)
+    }
+
+    /// Next message for `id` still within its TTL. It stays queued until
+    /// `done` confirms it.
+    pub fn pop(&self, id: &NodeId) -> Option<M> {
+        let mut lock = self.queues.lock().ok()?;
+        let queues = &mut *lock;
+        let queue = queues.by_id.get_mut(id)?;
+        self.drop_expired(id, queue, &mut queues.wal, &mut queues.expired);
+        let item = queue.items.get(queue.sent)?.item.clone();
+        queue.sent += 1;
+        Some(item)
+    }
+
+    /// Take back the message `pop` returned last, it couldn't be sent.
+    pub fn unpop(&self, id: &NodeId) {
+        self.update(id, |queue| queue.sent = queue.sent.saturating_sub(1));
+    }
+
+    /// Take back everything popped since the last `done`, the connection
+    /// went away before it was known to be written.
+    pub fn rewind(&self, id: &NodeId) {
+        self.update(id, |queue| queue.sent = 0);
+    }
+
+    /// The first `count` popped messages of `id` were delivered.
+    pub fn done(&self, id: &NodeId, count: usize) {
+        let mut lock = match self.queues.lock() {
+            Ok(lock) => lock,
+            Err(_) => return,
+        };
+        let queues = &mut *lock;
+        let queue = match queues.by_id.get_mut(id) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let count = count.min(queue.sent);
+        let seqs: Vec<u64> = queue.items.drain(..count).map(|entry| entry.seq).collect();
+        queue.sent -= count;
+        if let Some(wal) = &mut queues.wal {
+            if let Err(error) = wal.done(&id.to_string(), &seqs, queue.items.iter()) {
+                error!("relayer wal of {} failed,error={}", id, error);
+            }
+        }
+    }
+
+    /// End a flush of `id`. False if messages arrived meanwhile, then the
+    /// flush has to go on.
+    pub fn finish(&self, id: &NodeId) -> bool {
+        let mut queues = match self.queues.lock() {
+            Ok(queues) => queues,
+            Err(_) => return true,
+        };
+        match queues.by_id.get(id) {
+            Some(queue) if !queue.items.is_empty() => false,
+            _ => {
+                queues.by_id.remove(id);
+                true
+            }
+        }
+    }
+
+    /// Drop the queue of `id` for good. Returns what was still queued.
+    pub fn remove(&self, id: &NodeId) -> Result<Vec<M>, String> {
+        let mut lock = self.queues.lock().map_err(|err| err.to_string())?;
+        let queues = &mut *lock;
+        let queue = match queues.by_id.remove(id) {
+            Some(queue) => queue,
+            None => return Ok(Vec::new()),
+        };
+        if let Some(wal) = &mut queues.wal {
+            wal.compact::<M>(&id.to_string(), std::iter::empty())?;
+        }
+        Ok(queue.items.into_iter().map(|entry| entry.item).collect())
+    }
+
+    /// Messages queued for `id`, popped ones included until they are done.
+    pub fn len(&self, id: &NodeId) -> usize {
+        self.queues
+            .lock()
+            .ok()
+            .and_then(|queues| queues.by_id.get(id).map(|queue| queue.items.len()))
+            .unwrap_or(0)
+    }
+
+    /// Messages dropped because they outlived the TTL since the last call,
+    /// with the node they were parked for.
+    pub fn take_expired(&self) -> Vec<(NodeId, M)> {
+        self.queues
+            .lock()
+            .map(|mut queues| std::mem::take(&mut queues.expired))
+            .unwrap_or_default()
+    }
+
+    fn update<F: FnOnce(&mut Queue<M>)>(&self, id: &NodeId, f: F) {
+        if let Ok(mut queues) = self.queues.lock() {
+            if let Some(queue) = queues.by_id.get_mut(id) {
+                f(queue);
+            }
+        }
+    }
+
+    fn drop_expired(
+        &self,
+        id: &NodeId,
+        queue: &mut Queue<M>,
+        wal: &mut Option<Wal>,
+        dropped: &mut Vec<(NodeId, M)>,
+    ) {
+        let ttl = self.limits.ttl;
+        let mut expired = Vec::new();
+        let mut index = queue.sent;
+        while index < queue.items.len() {
+            let age = queue.items[index].at.elapsed().unwrap_or_default();
+            if age < ttl {
+                index += 1;
+                continue;
+            }
+            if let Some(entry) = queue.items.remove(index) {
+                expired.push(entry.seq);
+                dropped.push((id.clone(), entry.item));
+            }
+        }
+        if expired.is_empty() {
+            return;
+        }
+        debug!("drop {} expired messages for {}", expired.len(), id);
+        if let Some(wal) = wal {
+            if let Err(error) = wal.done(&id.to_string(), &expired, queue.items.iter()) {
+                error!("relayer wal of {} failed,error={}", id, error);
+            }
+        }
+    }
+}
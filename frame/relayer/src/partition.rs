@@ -0,0 +1,67 @@
+//! Choosing a group member for a partition key.
+//!
+//! Uses rendezvous (highest random weight) hashing: every member scores
+//! `hash(member, key)` and the highest score wins. A key only moves when its
+//! winner leaves, or when a newly joined member outscores it, so membership
+//! changes reassign about `1 / members` of the keys.
+
+use frame_common::get_hash;
+
+/// Index of the member of `members` that owns `key`, `None` if there are none.
+pub fn pick_member(members: &[String], key: &str) -> Option<usize> {
+    members
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, member)| (score(member, key), member.as_str()))
+        .map(|(index, _)| index)
+}
+
+fn score(member: &str, key: &str) -> u64 {
+    // length prefix keeps ("ab", "c") and ("a", "bc") apart
+    let hash = get_hash(&format!("{}:{}{}", member.len(), member, key));
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pick_member;
+
+    #[test]
+    fn test_small_reassignment() {
+        let members: Vec<String> = (1..=4).map(|i| format!("b{}b", i)).collect();
+        let keys: Vec<String> = (0..1000).map(|i| format!("order-{}", i)).collect();
+        let before: Vec<String> = keys
+            .iter()
+            .map(|key| members[pick_member(&members, key).unwrap()].clone())
+            .collect();
+        // every member gets a share
+        for member in &members {
+            assert!(before.iter().filter(|owner| *owner == member).count() > 100);
+        }
+
+        // removing b2b only moves the keys b2b owned
+        let remaining: Vec<String> = members.iter().filter(|m| *m != "b2b").cloned().collect();
+        for (key, owner) in keys.iter().zip(&before) {
+            let now = &remaining[pick_member(&remaining, key).unwrap()];
+            if owner != "b2b" {
+                assert_eq!(owner, now);
+            }
+        }
+
+        // adding b5b only takes keys, never shuffles them between old members
+        let mut grown = members.clone();
+        grown.push("b5b".to_string());
+        let moved = keys
+            .iter()
+            .zip(&before)
+            .filter(|(key, owner)| {
+                let now = &grown[pick_member(&grown, key).unwrap()];
+                assert!(now == *owner || now == "b5b");
+                now != *owner
+            })
+            .count();
+        assert!(moved < 350);
+    }
+}
@@ -0,0 +1,159 @@
+//! Route table of the relayer, split into shards. The node key table is one
+//! as well.
+//!
+//! Every routed message looks a route and a key up, while registrations and
+//! removals are rare. Each node id hashes to one shard behind its own read-write lock, so
+//! lookups never wait for each other and only wait for a change to the same
+//! shard. A change holds the lock of one shard only, so a reader sees a route
+//! either before or after it, never half of it.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use frame_common::id::NodeId;
+
+pub const DEFAULT_SHARDS: usize = 16;
+
+type Shard<S> = RwLock<HashMap<NodeId, S>>;
+
+pub struct Routes<S> {
+    shards: Vec<Shard<S>>,
+    hasher: RandomState,
+}
+
+impl<S> Default for Routes<S> {
+    fn default() -> Self {
+        Routes::new(DEFAULT_SHARDS)
+    }
+}
+
+impl<S> Routes<S> {
+    /// At least one shard is used.
+    pub fn new(shards: usize) -> Routes<S> {
+        Routes {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, id: &NodeId) -> &Shard<S> {
+        let index = self.hasher.hash_one(id) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        read(self.shard(id)).contains_key(id)
+    }
+
+    /// Returns the route `id` had before.
+    pub fn insert(&self, id: NodeId, sender: S) -> Option<S> {
+        write(self.shard(&id)).insert(id, sender)
+    }
+
+    pub fn remove(&self, id: &NodeId) -> Option<S> {
+        write(self.shard(id)).remove(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| read(shard).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every routed id, one shard at a time.
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.shards
+            .iter()
+            .flat_map(|shard| read(shard).keys().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+impl<S: Clone> Routes<S> {
+    pub fn get(&self, id: &NodeId) -> Option<S> {
+        read(self.shard(id)).get(id).cloned()
+    }
+
+    /// What `id` has, or what `make` gives it if it has nothing yet.
+    pub fn get_or_insert_with(&self, id: &NodeId, make: impl FnOnce() -> S) -> S {
+        write(self.shard(id))
+            .entry(id.clone())
+            .or_insert_with(make)
+            .clone()
+    }
+}
+
+// a shard is only changed by single map calls, so one left behind by a
+// panicking thread is still whole
+fn read<S>(shard: &Shard<S>) -> RwLockReadGuard<'_, HashMap<NodeId, S>> {
+    shard
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write<S>(shard: &Shard<S>) -> RwLockWriteGuard<'_, HashMap<NodeId, S>> {
+    shard
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    fn id(index: usize) -> NodeId {
+        NodeId::new(&format!("n{}", index), "g").unwrap()
+    }
+
+    #[test]
+    fn test_sharded_routes() {
+        let routes: Arc<Routes<usize>> = Arc::new(Routes::new(4));
+        for index in 0..1000 {
+            assert!(routes.insert(id(index), index).is_none());
+        }
+        // readers run while a writer moves the upper half around
+        let writer = {
+            let routes = routes.clone();
+            thread::spawn(move || {
+                for round in 0..50 {
+                    for index in 500..1000 {
+                        routes.remove(&id(index));
+                        routes.insert(id(index), index + round);
+                    }
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let routes = routes.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        for index in 0..500 {
+                            assert_eq!(Some(index), routes.get(&id(index)));
+                        }
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(1000, routes.len());
+        assert_eq!(1000, routes.ids().len());
+        assert_eq!(Some(549), routes.get(&id(500)));
+        assert_eq!(Some(549), routes.remove(&id(500)));
+        assert!(!routes.contains(&id(500)));
+        assert!(Routes::<usize>::new(0).insert(id(0), 0).is_none());
+    }
+}
@@ -0,0 +1,292 @@
+//! Messages held back until their delivery time.
+//!
+//! Pending messages sit in a map ordered by due time, so adding one and
+//! taking the next due ones stay cheap with millions pending. A single timer
+//! task sleeps until the earliest due time and is woken early when a message
+//! due sooner arrives. Opened on a directory, pending messages are kept in a
+//! write-ahead log and survive a restart; the log entry of a message is done
+//! only after it was routed, so a crash in between sends it again. Entries
+//! are written under the lock but synced outside it, and a sync covers every
+//! entry written before it, so concurrent senders share one fsync.
+//!
+//! A node can hold at most `MAX_PENDING_PER_NODE` messages, all nodes
+//! together `MAX_PENDING`, and none due more than `MAX_DELAY_MS` ahead.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use log::error;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Notify;
+
+use crate::wal::{Wal, WalEntry};
+
+/// All scheduled messages share one log file under this id.
+const WAL_ID: &str = "scheduled";
+
+/// Scheduled messages held for all nodes together.
+pub const MAX_PENDING: usize = 1_000_000;
+/// Scheduled messages held for one node.
+pub const MAX_PENDING_PER_NODE: usize = 10_000;
+/// How far ahead a message may be scheduled, a week.
+pub const MAX_DELAY_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+struct Pending<M> {
+    /// by (due time in unix millis, seq); an entry's `at` is its due time
+    /// and its item the owning node with the message
+    due: BTreeMap<(u64, u64), WalEntry<(String, M)>>,
+    /// pending messages of each node
+    owned: HashMap<String, usize>,
+    next_seq: u64,
+    wal: Option<Wal>,
+    /// entries written to the wal so far
+    written: u64,
+}
+
+pub struct Scheduler<M> {
+    pending: Mutex<Pending<M>>,
+    /// entries known to be on disk
+    synced: Mutex<u64>,
+    wake: Notify,
+}
+
+impl<M> Default for Scheduler<M> {
+    fn default() -> Self {
+        Scheduler {
+            pending: Mutex::new(Pending {
+                due: BTreeMap::new(),
+                owned: HashMap::new(),
+                next_seq: 0,
+                wal: None,
+                written: 0,
+            }),
+            synced: Mutex::new(0),
+            wake: Notify::new(),
+        }
+    }
+}
+
+impl<M> Scheduler<M>
+where
+    M: Serialize + DeserializeOwned,
+{
+    /// Pending messages kept in a write-ahead log in `dir`, with whatever a
+    /// previous run left there.
+    pub fn open(dir: &str) -> Result<Scheduler<M>, String> {
+        let (wal, mut replayed) = Wal::open::<(String, M)>(dir)?;
+        let entries = replayed.remove(WAL_ID).unwrap_or_default();
+        let next_seq = entries.iter().map(|entry| entry.seq + 1).max().unwrap_or(0);
+        let mut owned = HashMap::new();
+        for entry in &entries {
+            *owned.entry(entry.item.0.clone()).or_insert(0) += 1;
+        }
+        let due = entries
+            .into_iter()
+            .map(|entry| ((to_millis(&entry), entry.seq), entry))
+            .collect();
+        Ok(Scheduler {
+            pending: Mutex::new(Pending {
+                due,
+                owned,
+                next_seq,
+                wal: Some(wal),
+                written: 0,
+            }),
+            synced: Mutex::new(0),
+            wake: Notify::new(),
+        })
+    }
+
+    /// Hold `item` of node `owner` until `due_ms` (unix millis). Returns
+    /// once it is on disk, if the scheduler has a log.
+    pub fn schedule(&self, owner: &str, item: M, due_ms: u64) -> Result<(), String> {
+        if due_ms > frame_common::now_millis().saturating_add(MAX_DELAY_MS) {
+            return Err(format!("deliver_at {} is too far ahead", due_ms));
+        }
+        let mut lock = self.pending.lock().map_err(|err| err.to_string())?;
+        let pending = &mut *lock;
+        if pending.due.len() >= MAX_PENDING {
+            return Err("relayer has too many scheduled messages".to_string());
+        }
+        let owned = pending.owned.get(owner).copied().unwrap_or(0);
+        if owned >= MAX_PENDING_PER_NODE {
+            return Err(format!("{} has too many scheduled messages", owner));
+        }
+        let entry = WalEntry {
+            seq: pending.next_seq,
+            at: UNIX_EPOCH + Duration::from_millis(due_ms),
+            item: (owner.to_string(), item),
+        };
+        let mut written = None;
+        if let Some(wal) = &mut pending.wal {
+            wal.append(WAL_ID, &entry)?;
+            pending.written += 1;
+            written = Some(pending.written);
+        }
+        pending.next_seq += 1;
+        pending.owned.insert(owner.to_string(), owned + 1);
+        let earliest = pending
+            .due
+            .keys()
+            .next()
+            .is_none_or(|&(first, _)| due_ms < first);
+        pending.due.insert((due_ms, entry.seq), entry);
+        drop(lock);
+        if earliest {
+            self.wake.notify_one();
+        }
+        match written {
+            Some(written) => self.sync(written),
+            None => Ok(()),
+        }
+    }
+
+    /// Make sure the first `written` wal entries are on disk. Whoever syncs
+    /// covers everything written so far, so the callers waiting meanwhile
+    /// find their entry synced already.
+    fn sync(&self, written: u64) -> Result<(), String> {
+        let mut synced = self.synced.lock().map_err(|err| err.to_string())?;
+        if *synced >= written {
+            return Ok(());
+        }
+        // the file of now, as a compaction before has synced the older one
+        let (upto, file) = {
+            let pending = self.pending.lock().map_err(|err| err.to_string())?;
+            let file = match &pending.wal {
+                Some(wal) => wal.handle(WAL_ID)?,
+                None => None,
+            };
+            (pending.written, file)
+        };
+        if let Some(file) = file {
+            file.sync_data().map_err(|err| err.to_string())?;
+        }
+        *synced = upto;
+        Ok(())
+    }
+
+    /// Take every message due at `now_ms`, earliest first, with the seq to
+    /// pass to `done` once it was routed.
+    pub fn take_due(&self, now_ms: u64) -> Vec<(u64, M)> {
+        let mut pending = match self.pending.lock() {
+            Ok(pending) => pending,
+            Err(_) => return Vec::new(),
+        };
+        let pending = &mut *pending;
+        let later = pending.due.split_off(&(now_ms.saturating_add(1), 0));
+        let due = std::mem::replace(&mut pending.due, later);
+        due.into_values()
+            .map(|entry| {
+                let (owner, item) = entry.item;
+                if let Some(owned) = pending.owned.get_mut(&owner) {
+                    *owned -= 1;
+                    if *owned == 0 {
+                        pending.owned.remove(&owner);
+                    }
+                }
+                (entry.seq, item)
+            })
+            .collect()
+    }
+
+    /// The messages `seqs` were routed.
+    pub fn done(&self, seqs: &[u64]) {
+        let mut lock = match self.pending.lock() {
+            Ok(lock) => lock,
+            Err(_) => return,
+        };
+        let pending = &mut *lock;
+        if let Some(wal) = &mut pending.wal {
+            if let Err(error) = wal.done(WAL_ID, seqs, pending.due.values()) {
+                error!("relayer schedule wal failed,error={}", error);
+            }
+        }
+    }
+
+    /// Due time of the earliest pending message.
+    pub fn next_due(&self) -> Option<u64> {
+        let pending = self.pending.lock().ok()?;
+        pending.due.keys().next().map(|&(due_ms, _)| due_ms)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending
+            .lock()
+            .map(|pending| pending.due.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait until `due_ms`, or until a message due sooner than the ones
+    /// before is scheduled.
+    pub async fn sleep_until(&self, due_ms: Option<u64>) {
+        match due_ms {
+            Some(due_ms) => {
+                let wait = due_ms.saturating_sub(frame_common::now_millis());
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_millis(wait)) => (),
+                    _ = self.wake.notified() => (),
+                }
+            }
+            None => self.wake.notified().await,
+        }
+    }
+}
+
+fn to_millis<M>(entry: &WalEntry<M>) -> u64 {
+    entry
+        .at
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_due_in_order() {
+        let scheduler: Scheduler<u64> = Scheduler::default();
+        // many pending, added in no particular order
+        let count = 200_000;
+        for index in 0..count {
+            let due_ms = (index * 7919) % count;
+            let owner = (index % 100).to_string();
+            scheduler.schedule(&owner, due_ms, due_ms).unwrap();
+        }
+        assert_eq!(Some(0), scheduler.next_due());
+        let due: Vec<u64> = scheduler
+            .take_due(9)
+            .into_iter()
+            .map(|(_, item)| item)
+            .collect();
+        assert_eq!((0..10).collect::<Vec<u64>>(), due);
+        assert_eq!(Some(10), scheduler.next_due());
+        assert_eq!(count as usize - 10, scheduler.len());
+    }
+
+    #[test]
+    fn test_schedule_limits() {
+        let scheduler: Scheduler<u64> = Scheduler::default();
+        let now = frame_common::now_millis();
+        assert!(scheduler
+            .schedule("a1@a", 0, now + MAX_DELAY_MS + 60_000)
+            .is_err());
+        for index in 0..MAX_PENDING_PER_NODE as u64 {
+            scheduler.schedule("a1@a", index, now + index).unwrap();
+        }
+        assert!(scheduler.schedule("a1@a", 0, now).is_err());
+        // the others still have room
+        scheduler.schedule("b1@b", 0, now).unwrap();
+        // and the node again once its messages were taken
+        scheduler.take_due(now);
+        scheduler.schedule("a1@a", 0, now).unwrap();
+    }
+}
@@ -1,9 +1,46 @@
-
 use super::*;
 
-use frame_common::{data::BridgeMessage, get_rsa, sign, get_runtime};
+use frame_common::{
+    data::{BridgeMessage, ControlEvent, Delivery, Expiry, Priority},
+    get_rsa, get_runtime, relayer_verify, sign,
+};
+
+use crate::{
+    audit::{verify_audit_log, AuditLog},
+    cluster::Cluster,
+    deadletter::DeadLetters,
+    federation::Federation,
+    schedule::Scheduler,
+};
+
+fn node_id(text: &str) -> NodeId {
+    text.parse().unwrap()
+}
+
+fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(format!("{}.head", path.display()));
+    path.display().to_string()
+}
 
-use crate::RouteTable;
+/// Stand in for `do_send`: take `count` messages off the channel of `id`
+/// and record them written.
+async fn drain_writer(
+    state: RelayerState<BridgeMessage>,
+    id: &'static str,
+    mut rx: BcMsgReceiver<BridgeMessage>,
+    count: usize,
+) -> (Vec<String>, BcMsgReceiver<BridgeMessage>) {
+    let mut written = Vec::new();
+    while written.len() < count {
+        if let Ok(msg) = rx.recv().await {
+            state.balancer.record_written(&node_id(id));
+            written.push(*msg.message);
+        }
+    }
+    (written, rx)
+}
 
 #[test]
 fn it_works() {
@@ -13,15 +50,14 @@ fn it_works() {
 
 #[test]
 fn test_transfer() {
-    let route_table: RouteTable<BridgeMessage> = Arc::new(Mutex::new(HashMap::new()));
-    let pub_keys: PubKeyTable = Arc::new(Mutex::new(HashMap::new()));
     let (send_tx, send_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
         broadcast::channel(16);
 
     let rt = get_runtime();
     let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
     let a1 = "a1";
-    let sig = sign("a1a", &pr).unwrap();
+    let sig = sign("a1@a", &pr).unwrap();
     let bmsg = BridgeMessage {
         from_name: Box::new(a1.to_string()),
         from_group: Box::new("a".to_string()),
@@ -30,14 +66,1834 @@ fn test_transfer() {
         message: Box::new("erwrew hihi".to_string()),
         error_msg: None,
         sig: Some(sig),
+        ..Default::default()
+    };
+
+    state.route_table.insert(bmsg.get_source_id(), send_tx);
+
+    state.pub_keys.insert(bmsg.get_source_id(), pu);
+
+    assert_eq!(
+        true,
+        rt.block_on(dispatch_msg(&state, &node_id("a1@a"), bmsg))
+            .is_ok()
+    );
+}
+
+#[test]
+fn test_transfer_reject_other_connection() {
+    let (send_tx, _send_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+
+    let (pr, pu) = get_rsa().unwrap();
+    let path = temp_path("relayer_audit_reject");
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.audit = Some(AuditLog::open_table(&path, pr.clone()).unwrap());
+    let sig = sign("a1@a", &pr).unwrap();
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("a1".to_string()),
+        to_group: Box::new("a".to_string()),
+        message: Box::new("relayed for someone else".to_string()),
+        error_msg: None,
+        sig: Some(sig),
+        ..Default::default()
+    };
+
+    state.route_table.insert(bmsg.get_source_id(), send_tx);
+    state.pub_keys.insert(bmsg.get_source_id(), pu.clone());
+
+    // a valid signature does not let connection b1b speak for a1a
+    let rt = get_runtime();
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("b1@b"), bmsg.clone()))
+        .is_err());
+    // nor can a node pass its message off as an error reply of the relayer
+    let mut forged = bmsg.clone();
+    forged.error_msg = Some(Box::new("can't find target".to_string()));
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("a1@a"), forged))
+        .is_err());
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("a1@a"), bmsg))
+        .is_ok());
+
+    state.audit.as_ref().unwrap().sync().unwrap();
+    let (count, _) = verify_audit_log(&path, &pu).unwrap();
+    assert_eq!(3, count);
+}
+
+#[test]
+fn test_legacy_id_signature() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    let (tx, mut rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("a1@b"), tx).unwrap();
+    state.pub_keys.insert(node_id("a1@b"), pu);
+    let message = |from_name: &str, from_group: &str, signed: &str| BridgeMessage {
+        from_name: Box::new(from_name.to_string()),
+        from_group: Box::new(from_group.to_string()),
+        to_name: Box::new("a1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("hello".to_string()),
+        sig: Some(sign(signed, &pr).unwrap()),
+        ..Default::default()
+    };
+
+    // the run-together id only signs for a node while that is turned on
+    let refused = rt.block_on(dispatch_msg(
+        &state,
+        &node_id("a1@b"),
+        message("a1", "b", "a1b"),
+    ));
+    assert!(refused.unwrap_err().contains("verify failed"));
+    assert!(rx.try_recv().is_err());
+    state.legacy_id_signatures = true;
+
+    // a node that still signs the run-together id gets through
+    assert!(rt
+        .block_on(dispatch_msg(
+            &state,
+            &node_id("a1@b"),
+            message("a1", "b", "a1b")
+        ))
+        .is_ok());
+    assert!(rx.try_recv().is_ok());
+    // but a of group 1b is someone else, whatever it signs
+    assert!(rt
+        .block_on(dispatch_msg(
+            &state,
+            &node_id("a1@b"),
+            message("a", "1b", "a1b")
+        ))
+        .is_err());
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_audit_log_detects_tampering() {
+    let path = temp_path("relayer_audit_tamper");
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("audited".to_string()),
+        error_msg: None,
+        sig: Some(vec![1, 2, 3]),
+        ..Default::default()
+    };
+    let (pr, pu) = get_rsa().unwrap();
+    {
+        let log = AuditLog::open(&path, pr.clone()).unwrap();
+        for _ in 0..3 {
+            log.append(
+                AuditRecord::from_message(&bmsg, "a1@a"),
+                AuditDecision::Delivered,
+            )
+            .unwrap();
+        }
+    }
+    // reopening continues the same chain
+    let log = AuditLog::open(&path, pr.clone()).unwrap();
+    log.append(
+        AuditRecord::from_message(&bmsg, "a1@a"),
+        AuditDecision::Bounced,
+    )
+    .unwrap();
+    // not written before the sync, the log still ends at the signed head
+    assert_eq!(3, verify_audit_log(&path, &pu).unwrap().0);
+    assert_eq!(4, log.len());
+    log.sync().unwrap();
+    assert_eq!(4, verify_audit_log(&path, &pu).unwrap().0);
+    drop(log);
+
+    let original = std::fs::read_to_string(&path).unwrap();
+    let head = std::fs::read_to_string(format!("{}.head", path)).unwrap();
+
+    let edited = original.replacen("\"Delivered\"", "\"Bounced\"", 1);
+    std::fs::write(&path, edited).unwrap();
+    assert!(verify_audit_log(&path, &pu).is_err());
+
+    let mut lines: Vec<&str> = original.lines().collect();
+    lines.pop();
+    std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+    assert!(verify_audit_log(&path, &pu).is_err());
+
+    // a chain computed again, with a head signed by another key
+    let (other, _) = get_rsa().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let forged = AuditLog::open(&path, other).unwrap();
+    forged
+        .append(
+            AuditRecord::from_message(&bmsg, "a1@a"),
+            AuditDecision::Delivered,
+        )
+        .unwrap();
+    drop(forged);
+    assert!(verify_audit_log(&path, &pu).is_err());
+
+    // entries never synced are cut when the log opens again
+    std::fs::write(&path, &original).unwrap();
+    std::fs::write(format!("{}.head", path), &head).unwrap();
+    let mut torn = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    std::io::Write::write_all(&mut torn, b"{\"seq\":4,").unwrap();
+    assert!(verify_audit_log(&path, &pu).is_err());
+    assert_eq!(4, AuditLog::open(&path, pr).unwrap().len());
+    assert_eq!(4, verify_audit_log(&path, &pu).unwrap().0);
+}
+
+#[test]
+fn test_bounce_signed_by_relayer() {
+    let (send_tx, mut send_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+
+    let (pr, pu) = get_rsa().unwrap();
+    let (relayer_pr, relayer_pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(relayer_pr);
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("nobody".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("lost".to_string()),
+        error_msg: None,
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        ..Default::default()
+    };
+    state.route_table.insert(bmsg.get_source_id(), send_tx);
+    state.pub_keys.insert(bmsg.get_source_id(), pu.clone());
+
+    let rt = get_runtime();
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("a1@a"), bmsg))
+        .is_ok());
+    let bounced = send_rx.try_recv().unwrap();
+    assert!(bounced.error_msg.is_some());
+    assert!(relayer_verify(&bounced, &relayer_pu).is_ok());
+    // the node's own key can't pass for the relayer's
+    assert!(relayer_verify(&bounced, &pu).is_err());
+    // nor does the signature hold for another target or nonce
+    let mut retargeted = bounced.clone();
+    retargeted.set_target("b1", "b");
+    assert!(relayer_verify(&retargeted, &relayer_pu).is_err());
+    let mut renonced = bounced.clone();
+    renonced.relayer_nonce = bounced.relayer_nonce.map(|nonce| nonce + 1);
+    assert!(relayer_verify(&renonced, &relayer_pu).is_err());
+}
+
+#[test]
+fn test_receive_frame_limits() {
+    let rt = get_runtime();
+    let (pr, _) = get_rsa().unwrap();
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr);
+    state.limits.max_message_size = 4;
+
+    rt.block_on(async {
+        assert!(
+            receive_frame(&state, &node_id("a1@a"), Err("frame too large".to_string()))
+                .await
+                .is_err()
+        );
+        assert!(
+            receive_frame(&state, &node_id("a1@a"), Ok("{not json".to_string()))
+                .await
+                .is_err()
+        );
+    });
+
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("more than four bytes".to_string()),
+        error_msg: None,
+        sig: None,
+        ..Default::default()
+    };
+    let frame = serde_json::to_string(&bmsg).unwrap();
+    assert!(rt
+        .block_on(receive_frame(&state, &node_id("a1@a"), Ok(frame)))
+        .is_err());
+
+    // a well formed message that fails routing is not a bad frame
+    let mut bmsg = bmsg;
+    *bmsg.message = "hi".to_string();
+    let frame = serde_json::to_string(&bmsg).unwrap();
+    assert!(rt
+        .block_on(receive_frame(&state, &node_id("a1@a"), Ok(frame)))
+        .is_ok());
+}
+
+#[test]
+fn test_dispatch_on_verify_pool() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let (_, other_pu) = get_rsa().unwrap();
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.verifier = Arc::new(Verifier::new(2, 16, std::time::Duration::from_secs(60)));
+    let (send_tx, mut send_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("a1".to_string()),
+        to_group: Box::new("a".to_string()),
+        message: Box::new("verified off the lock".to_string()),
+        error_msg: None,
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        ..Default::default()
+    };
+    state.route_table.insert(bmsg.get_source_id(), send_tx);
+    state.pub_keys.insert(bmsg.get_source_id(), pu);
+
+    rt.block_on(async {
+        // second call is answered from the cache
+        for _ in 0..2 {
+            assert!(dispatch_msg(&state, &node_id("a1@a"), bmsg.clone())
+                .await
+                .is_ok());
+            assert!(send_rx.try_recv().is_ok());
+        }
+        // a new key for the same id must not reuse the cached proof
+        state.pub_keys.insert(bmsg.get_source_id(), other_pu);
+        assert!(dispatch_msg(&state, &node_id("a1@a"), bmsg.clone())
+            .await
+            .is_err());
+    });
+}
+
+#[test]
+fn test_multicast_to_group() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let (relayer_pr, relayer_pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(relayer_pr);
+
+    let mut receivers = HashMap::new();
+    for (name, group) in [("a1", "a"), ("b1", "b"), ("b2", "b")] {
+        let (tx, rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+            broadcast::channel(16);
+        let id = format!("{}@{}", name, group);
+        add_route(&state, &node_id(&id), tx).unwrap();
+        receivers.insert(id, rx);
+    }
+    state.pub_keys.insert(node_id("a1@a"), pu);
+
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("to every b".to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        delivery: Some(Delivery::Multicast {
+            exclude_sender: true,
+            report: true,
+        }),
+        ..Default::default()
+    };
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("a1@a"), bmsg))
+        .is_ok());
+
+    for name in ["b1", "b2"] {
+        let copy = receivers
+            .get_mut(&format!("{}@b", name))
+            .unwrap()
+            .try_recv()
+            .unwrap();
+        assert_eq!(name, copy.to_name.as_str());
+        assert_eq!("to every b", copy.message.as_str());
+    }
+    let report = receivers.get_mut("a1@a").unwrap().try_recv().unwrap();
+    assert!(relayer_verify(&report, &relayer_pu).is_ok());
+    match report.control {
+        Some(ControlEvent::DeliveryReport(results)) => {
+            assert_eq!(2, results.len());
+            assert!(results.iter().all(|res| res.error.is_none()));
+        }
+        _ => panic!("expect a delivery report"),
+    }
+}
+
+#[test]
+fn test_multicast_exclude_sender() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+
+    let (b1_tx, mut b1_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("b1@b"), b1_tx).unwrap();
+    state.pub_keys.insert(node_id("b1@b"), pu);
+
+    let mut bmsg = BridgeMessage {
+        from_name: Box::new("b1".to_string()),
+        from_group: Box::new("b".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("to my group".to_string()),
+        sig: Some(sign("b1@b", &pr).unwrap()),
+        delivery: Some(Delivery::Multicast {
+            exclude_sender: false,
+            report: false,
+        }),
+        ..Default::default()
+    };
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("b1@b"), bmsg.clone()))
+        .is_ok());
+    assert!(b1_rx.try_recv().unwrap().error_msg.is_none());
+
+    // b1 is the only member, so excluding it leaves nobody
+    bmsg.delivery = Some(Delivery::Multicast {
+        exclude_sender: true,
+        report: false,
+    });
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("b1@b"), bmsg))
+        .is_ok());
+    assert!(b1_rx.try_recv().unwrap().error_msg.is_some());
+}
+
+#[test]
+fn test_broadcast_needs_permission() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+
+    let mut receivers = HashMap::new();
+    for (name, group) in [("a1", "a"), ("b1", "b"), ("c1", "c")] {
+        let (tx, rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+            broadcast::channel(16);
+        let id = format!("{}@{}", name, group);
+        add_route(&state, &node_id(&id), tx).unwrap();
+        receivers.insert(id, rx);
+    }
+    state.pub_keys.insert(node_id("a1@a"), pu);
+
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        message: Box::new("to everyone".to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        delivery: Some(Delivery::Broadcast {
+            exclude_sender: true,
+            report: false,
+        }),
+        ..Default::default()
+    };
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("a1@a"), bmsg.clone()))
+        .is_ok());
+    let bounced = receivers.get_mut("a1@a").unwrap().try_recv().unwrap();
+    assert!(bounced.error_msg.is_some());
+    assert!(receivers.get_mut("b1@b").unwrap().try_recv().is_err());
+
+    state.broadcasters.lock().unwrap().insert(node_id("a1@a"));
+    assert!(rt
+        .block_on(dispatch_msg(&state, &node_id("a1@a"), bmsg))
+        .is_ok());
+    for (id, group) in [("b1@b", "b"), ("c1@c", "c")] {
+        let copy = receivers.get_mut(id).unwrap().try_recv().unwrap();
+        assert_eq!(group, copy.to_group.as_str());
+    }
+    assert!(receivers.get_mut("a1@a").unwrap().try_recv().is_err());
+
+    let notice = BridgeMessage {
+        from_name: Box::new("relayer".to_string()),
+        message: Box::new("maintenance".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(3, relayer_broadcast(&state, notice).unwrap());
+    let notice = receivers.get_mut("c1@c").unwrap().try_recv().unwrap();
+    assert!(relayer_verify(&notice, &RsaPublicKey::from(&pr)).is_ok());
+}
+
+#[test]
+fn test_anycast_strategies() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.pub_keys.insert(node_id("a1@a"), pu);
+
+    let mut receivers = HashMap::new();
+    for (name, group) in [("a1", "a"), ("b1", "b"), ("b2", "b"), ("b3", "b")] {
+        let (tx, rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+            broadcast::channel(16);
+        let id = format!("{}@{}", name, group);
+        add_route(&state, &node_id(&id), tx).unwrap();
+        receivers.insert(id, rx);
+    }
+    let anycast = |strategy: AnycastStrategy| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("any b".to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        delivery: Some(Delivery::Anycast(strategy)),
+        ..Default::default()
+    };
+    let receive = |receivers: &mut HashMap<String, BcMsgReceiver<BridgeMessage>>| {
+        let got: Vec<String> = ["b1@b", "b2@b", "b3@b"]
+            .iter()
+            .filter(|id| receivers.get_mut(**id).unwrap().try_recv().is_ok())
+            .map(|id| id.to_string())
+            .collect();
+        assert_eq!(1, got.len());
+        got[0].clone()
+    };
+
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+        rt.block_on(dispatch_msg(
+            &state,
+            &node_id("a1@a"),
+            anycast(AnycastStrategy::RoundRobin),
+        ))
+        .unwrap();
+        seen.push(receive(&mut receivers));
+    }
+    seen.sort();
+    assert_eq!(vec!["b1@b", "b2@b", "b3@b"], seen);
+
+    // there is no do_send here, so each member now has one message in flight
+    state.balancer.record_sent(&node_id("b1@b"));
+    state.balancer.record_sent(&node_id("b3@b"));
+    state.balancer.record_written(&node_id("b2@b"));
+    rt.block_on(dispatch_msg(
+        &state,
+        &node_id("a1@a"),
+        anycast(AnycastStrategy::LeastInFlight),
+    ))
+    .unwrap();
+    assert_eq!("b2@b", receive(&mut receivers));
+
+    // unhealthy and disconnected members are skipped
+    state.balancer.record_failure(&node_id("b1@b"), 0);
+    receivers.remove("b2@b");
+    for _ in 0..3 {
+        rt.block_on(dispatch_msg(
+            &state,
+            &node_id("a1@a"),
+            anycast(AnycastStrategy::Random),
+        ))
+        .unwrap();
+        assert!(receivers.get_mut("b3@b").unwrap().try_recv().is_ok());
+        assert!(receivers.get_mut("b1@b").unwrap().try_recv().is_err());
+    }
+}
+
+#[test]
+fn test_partitioned_sticks_to_one_member() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.pub_keys.insert(node_id("a1@a"), pu);
+
+    let mut receivers = HashMap::new();
+    for (name, group) in [("a1", "a"), ("b1", "b"), ("b2", "b"), ("b3", "b")] {
+        let (tx, rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+            broadcast::channel(16);
+        let id = format!("{}@{}", name, group);
+        add_route(&state, &node_id(&id), tx).unwrap();
+        receivers.insert(id, rx);
+    }
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("about order 42".to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        delivery: Some(Delivery::Partitioned {
+            key: "order-42".to_string(),
+        }),
+        ..Default::default()
+    };
+
+    let mut owners = Vec::new();
+    for _ in 0..4 {
+        rt.block_on(dispatch_msg(&state, &node_id("a1@a"), bmsg.clone()))
+            .unwrap();
+        for id in ["b1@b", "b2@b", "b3@b"] {
+            if let Ok(copy) = receivers.get_mut(id).unwrap().try_recv() {
+                assert_eq!(node_id(id), copy.get_target_id());
+                owners.push(id);
+            }
+        }
+    }
+    assert_eq!(4, owners.len());
+    assert!(owners.iter().all(|owner| *owner == owners[0]));
+}
+
+#[test]
+fn test_publish_to_subscribers() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+
+    let mut receivers = HashMap::new();
+    for (name, group) in [("a1", "a"), ("b1", "b"), ("b2", "b"), ("c1", "c")] {
+        let (tx, rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+            broadcast::channel(16);
+        let id = format!("{}@{}", name, group);
+        add_route(&state, &node_id(&id), tx).unwrap();
+        state.pub_keys.insert(node_id(&id), pu.clone());
+        receivers.insert(id, rx);
+    }
+    let message = |name: &str, group: &str, delivery: Delivery| BridgeMessage {
+        from_name: Box::new(name.to_string()),
+        from_group: Box::new(group.to_string()),
+        message: Box::new("order 42".to_string()),
+        sig: Some(sign(&format!("{}@{}", name, group), &pr).unwrap()),
+        delivery: Some(delivery),
+        ..Default::default()
+    };
+    for (name, group, pattern) in [
+        ("b1", "b", "orders.*"),
+        ("b2", "b", "orders.#"),
+        ("c1", "c", "billing.*"),
+    ] {
+        let subscribe = message(
+            name,
+            group,
+            Delivery::Subscribe {
+                pattern: pattern.to_string(),
+            },
+        );
+        rt.block_on(dispatch_msg(
+            &state,
+            &node_id(&format!("{}@{}", name, group)),
+            subscribe,
+        ))
+        .unwrap();
+    }
+    let mut published = |topic: &str| {
+        let publish = message(
+            "a1",
+            "a",
+            Delivery::Publish {
+                topic: topic.to_string(),
+            },
+        );
+        rt.block_on(dispatch_msg(&state, &node_id("a1@a"), publish))
+            .unwrap();
+        let mut got: Vec<String> = receivers
+            .iter_mut()
+            .filter_map(|(id, rx)| rx.try_recv().ok().map(|_| id.clone()))
+            .collect();
+        got.sort();
+        got
+    };
+
+    assert_eq!(vec!["b1@b", "b2@b"], published("orders.created"));
+    assert_eq!(vec!["b2@b"], published("orders.eu.created"));
+    assert!(published("shipping.created").is_empty());
+
+    remove_route(&state, &node_id("b2@b"));
+    assert!(state.topics.patterns(&node_id("b2@b")).is_empty());
+    assert_eq!(vec!["b1@b"], published("orders.created"));
+}
+
+#[test]
+fn test_urgent_overtakes_bulk() {
+    let rt = get_runtime();
+    let (pr, _) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr);
+    let (tx, rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(64);
+    let message = |content: &str, priority: Priority| BridgeMessage {
+        message: Box::new(content.to_string()),
+        priority: Some(priority),
+        ..Default::default()
+    };
+    for index in 0..10 {
+        tx.send(message(&format!("bulk{}", index), Priority::Bulk))
+            .unwrap();
+    }
+    tx.send(message("urgent", Priority::Urgent)).unwrap();
+    tx.send(message("notice", Priority::High)).unwrap();
+    drop(tx);
+
+    let written = rt.block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (_, writer) = stream.into_split();
+        do_send(rx, writer, state, node_id("b1@b")).await;
+
+        let mut decoder = FrameDecoder::new(4096);
+        let mut written = Vec::new();
+        let mut buf = [0; 4096];
+        while written.len() < 12 {
+            let size = client.read(&mut buf).await.unwrap();
+            for frame in decoder.push(&buf[..size]) {
+                let msg: BridgeMessage = serde_json::from_str(&frame.unwrap()).unwrap();
+                written.push(*msg.message);
+            }
+        }
+        written
+    });
+    // everything was waiting before the first write, so the lanes sort it all
+    assert_eq!(vec!["urgent", "notice", "bulk0", "bulk1"], written[..4]);
+    assert_eq!("bulk9", written[11]);
+}
+
+#[test]
+fn test_expired_messages_dropped() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    let (a_tx, mut a_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    let (b_tx, mut b_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("a1@a"), a_tx).unwrap();
+    add_route(&state, &node_id("b1@b"), b_tx).unwrap();
+    state.pub_keys.insert(node_id("a1@a"), pu);
+    let message = |deadline_ms: u64, notify: bool| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("flash sale".to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        expiry: Some(Expiry::Deadline {
+            deadline_ms,
+            notify,
+        }),
+        ..Default::default()
+    };
+
+    let later = frame_common::now_millis() + 60_000;
+    rt.block_on(dispatch_msg(&state, &node_id("a1@a"), message(later, true)))
+        .unwrap();
+    assert_eq!("flash sale", *b_rx.try_recv().unwrap().message);
+
+    rt.block_on(dispatch_msg(&state, &node_id("a1@a"), message(1, false)))
+        .unwrap();
+    assert!(b_rx.try_recv().is_err());
+    assert!(a_rx.try_recv().is_err());
+
+    rt.block_on(dispatch_msg(&state, &node_id("a1@a"), message(1, true)))
+        .unwrap();
+    assert!(b_rx.try_recv().is_err());
+    let notice = a_rx.try_recv().unwrap();
+    assert_eq!(Some(Expiry::Expired), notice.expiry);
+    assert_eq!("message expired", notice.error_msg.unwrap().as_str());
+}
+
+#[test]
+fn test_dead_letters() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let (other_pr, _) = get_rsa().unwrap();
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.dead_letters = Arc::new(DeadLetters::new(2));
+    let (a_tx, mut a_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("a1@a"), a_tx).unwrap();
+    state.pub_keys.insert(node_id("a1@a"), pu);
+    let message = |content: &str, key: &RsaPrivateKey| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new(content.to_string()),
+        sig: Some(sign("a1@a", key).unwrap()),
+        ..Default::default()
+    };
+
+    rt.block_on(dispatch_msg(
+        &state,
+        &node_id("a1@a"),
+        message("first", &pr),
+    ))
+    .unwrap();
+    assert!(a_rx.try_recv().unwrap().error_msg.is_some());
+    rt.block_on(dispatch_msg(
+        &state,
+        &node_id("a1@a"),
+        message("second", &pr),
+    ))
+    .unwrap();
+    assert!(rt
+        .block_on(dispatch_msg(
+            &state,
+            &node_id("a1@a"),
+            message("forged", &other_pr)
+        ))
+        .is_err());
+
+    // the oldest entry made room
+    let letters = state.dead_letters.list();
+    assert_eq!(
+        vec![1, 2],
+        letters.iter().map(|l| l.id).collect::<Vec<u64>>()
+    );
+    assert_eq!("can't find target", letters[0].reason);
+    assert_eq!("b1@b", letters[0].target);
+    let forged = state.dead_letters.inspect(2).unwrap();
+    assert_eq!("forged", *forged.message.message);
+    assert!(forged.message.error_msg.is_none());
+
+    // b1 is there now, so the redelivered message arrives
+    let (b_tx, mut b_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("b1@b"), b_tx).unwrap();
+    rt.block_on(redeliver_dead_letter(&state, 1)).unwrap();
+    assert_eq!("second", *b_rx.try_recv().unwrap().message);
+    assert!(rt.block_on(redeliver_dead_letter(&state, 1)).is_err());
+
+    // a forged message fails the check again and comes back under a new id
+    assert!(rt.block_on(redeliver_dead_letter(&state, 2)).is_err());
+    assert!(b_rx.try_recv().is_err());
+    assert_eq!(
+        vec![3],
+        state
+            .dead_letters
+            .list()
+            .iter()
+            .map(|l| l.id)
+            .collect::<Vec<u64>>()
+    );
+    assert!(state.dead_letters.purge(3));
+    assert!(state.dead_letters.is_empty());
+}
+
+#[test]
+fn test_scheduled_delivery() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    let (b_tx, mut b_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("b1@b"), b_tx).unwrap();
+    state.pub_keys.insert(node_id("a1@a"), pu);
+    let message = |content: &str, delay_ms: u64| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new(content.to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        deliver_at: Some(frame_common::now_millis() + delay_ms),
+        ..Default::default()
+    };
+
+    rt.block_on(async {
+        tokio::spawn(run_scheduler(state.clone()));
+        dispatch_msg(&state, &node_id("a1@a"), message("later", 60_000))
+            .await
+            .unwrap();
+        // due sooner than the one the timer sleeps for, so it wakes early
+        dispatch_msg(&state, &node_id("a1@a"), message("soon", 100))
+            .await
+            .unwrap();
+        assert!(b_rx.try_recv().is_err());
+        tokio::time::sleep(Duration::from_millis(400)).await;
+    });
+    assert_eq!("soon", *b_rx.try_recv().unwrap().message);
+    assert!(b_rx.try_recv().is_err());
+    assert_eq!(1, state.scheduler.len());
+}
+
+#[test]
+fn test_durable_schedule_survives_restart() {
+    let dir = temp_dir("relayer_schedule_restart");
+    let message = |content: &str| BridgeMessage {
+        message: Box::new(content.to_string()),
+        ..Default::default()
+    };
+    {
+        let scheduler: Scheduler<BridgeMessage> = Scheduler::open(&dir).unwrap();
+        scheduler.schedule("a1@a", message("first"), 1_000).unwrap();
+        scheduler
+            .schedule("a1@a", message("second"), 2_000)
+            .unwrap();
+        scheduler.schedule("a1@a", message("third"), 3_000).unwrap();
+        let due = scheduler.take_due(1_500);
+        assert_eq!(1, due.len());
+        scheduler.done(&[due[0].0]);
+        // taken but not done when the relayer stops, so it comes back
+        assert_eq!(1, scheduler.take_due(2_500).len());
+    }
+    let scheduler: Scheduler<BridgeMessage> = Scheduler::open(&dir).unwrap();
+    assert_eq!(Some(2_000), scheduler.next_due());
+    let due: Vec<String> = scheduler
+        .take_due(u64::MAX)
+        .into_iter()
+        .map(|(_, msg)| *msg.message)
+        .collect();
+    assert_eq!(vec!["second", "third"], due);
+    // seqs keep counting after a restart
+    scheduler
+        .schedule("a1@a", message("fourth"), 4_000)
+        .unwrap();
+    assert_eq!(3, scheduler.take_due(u64::MAX)[0].0);
+}
+
+#[test]
+fn test_park_for_offline_node() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.parking = Some(Arc::new(Parking::new(parking::ParkingLimits {
+        ttl: Duration::from_secs(60),
+        max_per_node: 3,
+    })));
+    let (a_tx, mut a_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("a1@a"), a_tx).unwrap();
+    // b1b registered before, but is offline now
+    for id in ["a1@a", "b1@b"] {
+        state.pub_keys.insert(node_id(id), pu.clone());
+    }
+    let message = |to_name: &str, content: &str| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new(to_name.to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new(content.to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        ..Default::default()
+    };
+
+    for content in ["one", "two", "three", "four"] {
+        rt.block_on(dispatch_msg(
+            &state,
+            &node_id("a1@a"),
+            message("b1", content),
+        ))
+        .unwrap();
+    }
+    // never registered, still bounced
+    rt.block_on(dispatch_msg(
+        &state,
+        &node_id("a1@a"),
+        message("b2", "five"),
+    ))
+    .unwrap();
+    let bounced: Vec<String> = std::iter::from_fn(|| a_rx.try_recv().ok())
+        .map(|bounce| *bounce.error_msg.unwrap())
+        .collect();
+    assert_eq!(vec!["offline queue full", "can't find target"], bounced);
+    assert_eq!(3, state.parking.as_ref().unwrap().len(&node_id("b1@b")));
+
+    let (b_tx, b_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("b1@b"), b_tx).unwrap();
+    let drain = rt.spawn(drain_writer(state.clone(), "b1@b", b_rx, 3));
+    rt.block_on(flush_parked(state.clone(), node_id("b1@b")));
+    let (flushed, mut b_rx) = rt.block_on(drain).unwrap();
+    assert_eq!(vec!["one", "two", "three"], flushed);
+
+    // connected with an empty queue, messages go straight through
+    rt.block_on(dispatch_msg(&state, &node_id("a1@a"), message("b1", "six")))
+        .unwrap();
+    assert_eq!("six", b_rx.try_recv().unwrap().message.as_str());
+    assert_eq!(0, state.parking.as_ref().unwrap().len(&node_id("b1@b")));
+}
+
+/// Accept the relayer on `listener` the way a node does, checking that it
+/// holds `key`.
+async fn accept_relayer(listener: &TcpListener, key: &RsaPublicKey) -> TcpStream {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let nonce: u64 = rand::random();
+    let mut challenge = serde_json::to_string(&RelayerAuth::Challenge { nonce }).unwrap();
+    challenge.push_str(frame_common::codec::DELIMITER);
+    stream.write_all(challenge.as_bytes()).await.unwrap();
+    let mut decoder = FrameDecoder::new(4096);
+    let mut buf = [0; 4096];
+    loop {
+        let size = stream.read(&mut buf).await.unwrap();
+        assert!(size > 0);
+        if let Some(frame) = decoder.push(&buf[..size]).pop() {
+            match parse_message(&frame.unwrap()).unwrap() {
+                RelayerAuth::Answer { sig } => {
+                    verify(&relayer_auth_data(nonce, &addr), key, &sig).unwrap();
+                    return stream;
+                }
+                RelayerAuth::Challenge { .. } => panic!("expect an answer"),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_unregister_node() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.parking = Some(Arc::new(Parking::new(parking::ParkingLimits::default())));
+    let (a_tx, mut a_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("a1@a"), a_tx).unwrap();
+    for id in ["a1@a", "b1@b", "c1@c"] {
+        state.pub_keys.insert(node_id(id), pu.clone());
+    }
+    let to_b1 = |content: &str| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new(content.to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        ..Default::default()
+    };
+
+    // b1b is offline, so its message waits until it is unregistered
+    rt.block_on(dispatch_msg(&state, &node_id("a1@a"), to_b1("parked")))
+        .unwrap();
+    assert_eq!(1, unregister_node(&state, &node_id("b1@b")).unwrap());
+    let bounce = a_rx.try_recv().unwrap();
+    assert_eq!("parked", bounce.message.as_str());
+    assert_eq!("node b1@b unregistered", bounce.error_msg.unwrap().as_str());
+    assert!(!state.pub_keys.contains(&node_id("b1@b")));
+
+    // unknown from now on, not parked again
+    rt.block_on(dispatch_msg(&state, &node_id("a1@a"), to_b1("later")))
+        .unwrap();
+    assert_eq!(
+        "can't find target",
+        a_rx.try_recv().unwrap().error_msg.unwrap().as_str()
+    );
+    assert_eq!(0, state.parking.as_ref().unwrap().len(&node_id("b1@b")));
+    assert!(unregister_node(&state, &node_id("b1@b")).is_err());
+
+    // c1c is connected: its connection closes and its route goes
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let register_info = RegisterInfo {
+            addr: Box::new(listener.local_addr().unwrap().to_string()),
+            name: Box::new("c1".to_string()),
+            group: Box::new("c".to_string()),
+        };
+        let (connected, mut stream) = tokio::join!(
+            relayer_connect(&register_info, state.clone()),
+            accept_relayer(&listener, &pu)
+        );
+        connected.unwrap();
+        assert!(state.route_table.contains(&node_id("c1@c")));
+
+        assert_eq!(0, unregister_node(&state, &node_id("c1@c")).unwrap());
+        let mut buf = [0; 16];
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+        assert_eq!(0, closed.unwrap().unwrap());
+    });
+    assert!(!state.route_table.contains(&node_id("c1@c")));
+    assert!(state.groups.lock().unwrap().get("c").is_none());
+    assert!(state.connections.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_flush_after_reconnect_within_cooldown() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.parking = Some(Arc::new(Parking::new(parking::ParkingLimits::default())));
+    let (a_tx, _a_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("a1@a"), a_tx).unwrap();
+    for id in ["a1@a", "b1@b"] {
+        state.pub_keys.insert(node_id(id), pu.clone());
+    }
+    for content in ["one", "two"] {
+        let bmsg = BridgeMessage {
+            from_name: Box::new("a1".to_string()),
+            from_group: Box::new("a".to_string()),
+            to_name: Box::new("b1".to_string()),
+            to_group: Box::new("b".to_string()),
+            message: Box::new(content.to_string()),
+            sig: Some(sign("a1@a", &pr).unwrap()),
+            ..Default::default()
+        };
+        rt.block_on(dispatch_msg(&state, &node_id("a1@a"), bmsg))
+            .unwrap();
+    }
+    assert_eq!(2, state.parking.as_ref().unwrap().len(&node_id("b1@b")));
+
+    // the connection before failed, the new one still gets everything
+    state.balancer.record_failure(&node_id("b1@b"), 0);
+    let (b_tx, b_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("b1@b"), b_tx).unwrap();
+    assert!(state.balancer.get(&node_id("b1@b")).is_healthy());
+    let drain = rt.spawn(drain_writer(state.clone(), "b1@b", b_rx, 2));
+    rt.block_on(flush_parked(state.clone(), node_id("b1@b")));
+    let (flushed, _) = rt.block_on(drain).unwrap();
+    assert_eq!(vec!["one", "two"], flushed);
+    assert_eq!(0, state.parking.as_ref().unwrap().len(&node_id("b1@b")));
+}
+
+#[test]
+fn test_stale_connection_keeps_route() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr);
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let register_info = RegisterInfo {
+            addr: Box::new(listener.local_addr().unwrap().to_string()),
+            name: Box::new("c1".to_string()),
+            group: Box::new("c".to_string()),
+        };
+        let (connected, stale) = tokio::join!(
+            relayer_connect(&register_info, state.clone()),
+            accept_relayer(&listener, &pu)
+        );
+        connected.unwrap();
+        state.balancer.record_sent(&node_id("c1@c"));
+        let (connected, live) = tokio::join!(
+            relayer_connect(&register_info, state.clone()),
+            accept_relayer(&listener, &pu)
+        );
+        connected.unwrap();
+
+        // the first connection closing leaves the route of the second alone
+        drop(stale);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(state.route_table.contains(&node_id("c1@c")));
+        assert_eq!(1, state.connections.lock().unwrap().len());
+        assert_eq!(1, state.balancer.get(&node_id("c1@c")).in_flight);
+
+        drop(live);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!state.route_table.contains(&node_id("c1@c")));
+        assert_eq!(0, state.balancer.get(&node_id("c1@c")).in_flight);
+    });
+    assert!(state.connections.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_migrate_node() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    let (a_tx, mut a_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("a1@a"), a_tx).unwrap();
+    let (b_tx, _b_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("b1@b"), b_tx).unwrap();
+    for id in ["a1@a", "b1@b"] {
+        state.pub_keys.insert(node_id(id), pu.clone());
+    }
+    state
+        .topics
+        .subscribe(&node_id("b1@b"), "orders.*")
+        .unwrap();
+    let to_b1 = |content: &str| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new(content.to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        ..Default::default()
+    };
+
+    // store-and-forward is off, still nothing for b1b is lost while it moves
+    assert!(begin_migration(&state, &node_id("a2@a")).is_err());
+    begin_migration(&state, &node_id("b1@b")).unwrap();
+    begin_migration(&state, &node_id("b1@b")).unwrap();
+    assert!(!state.route_table.contains(&node_id("b1@b")));
+    for content in ["one", "two"] {
+        rt.block_on(dispatch_msg(&state, &node_id("a1@a"), to_b1(content)))
+            .unwrap();
+    }
+    assert!(a_rx.try_recv().is_err());
+    assert_eq!(2, state.migrations.parking.len(&node_id("b1@b")));
+    assert_eq!(
+        vec![node_id("b1@b")],
+        state.topics.subscribers("orders.new").unwrap()
+    );
+
+    // only the key it had takes it back
+    let register_info = RegisterInfo {
+        addr: Box::new("127.0.0.1:0".to_string()),
+        name: Box::new("b1".to_string()),
+        group: Box::new("b".to_string()),
+    };
+    let (_, other_key) = get_rsa().unwrap();
+    assert!(register_key(&state, &register_info, other_key).is_err());
+    register_key(&state, &register_info, pu.clone()).unwrap();
+
+    let (b_tx, b_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("b1@b"), b_tx).unwrap();
+    let drain = rt.spawn(drain_writer(state.clone(), "b1@b", b_rx, 2));
+    rt.block_on(flush_parked(state.clone(), node_id("b1@b")));
+    let (flushed, mut b_rx) = rt.block_on(drain).unwrap();
+    assert_eq!(vec!["one", "two"], flushed);
+    assert!(!state.migrations.is_moving(&node_id("b1@b")));
+
+    rt.block_on(dispatch_msg(&state, &node_id("a1@a"), to_b1("three")))
+        .unwrap();
+    assert_eq!("three", b_rx.try_recv().unwrap().message.as_str());
+}
+
+#[test]
+fn test_migration_deadline_bounces_parked() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+    state.migrations = Arc::new(Migrations::new(Duration::from_millis(100)));
+    let (a_tx, mut a_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("a1@a"), a_tx).unwrap();
+    for id in ["a1@a", "b1@b"] {
+        state.pub_keys.insert(node_id(id), pu.clone());
+    }
+    let to_b1 = |content: &str| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new(content.to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        ..Default::default()
+    };
+
+    begin_migration(&state, &node_id("b1@b")).unwrap();
+    for content in ["one", "two"] {
+        rt.block_on(dispatch_msg(&state, &node_id("a1@a"), to_b1(content)))
+            .unwrap();
+    }
+    assert_eq!(2, state.migrations.parking.len(&node_id("b1@b")));
+
+    // b1b never registers again, so both come back once the deadline passed
+    rt.spawn(run_migrations(state.clone()));
+    wait_for(|| !state.migrations.is_moving(&node_id("b1@b")));
+    rt.block_on(async {
+        for content in ["one", "two"] {
+            let bounce = tokio::time::timeout(Duration::from_secs(5), a_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(content, bounce.message.as_str());
+            assert_eq!(
+                "node b1@b didn't finish moving in time",
+                bounce.error_msg.unwrap().as_str()
+            );
+        }
+    });
+    assert_eq!(0, state.migrations.parking.len(&node_id("b1@b")));
+    assert_eq!(2, state.dead_letters.len());
+}
+
+#[test]
+fn test_parked_messages_expire() {
+    let parking: Parking<String> = Parking::new(parking::ParkingLimits {
+        ttl: Duration::from_millis(20),
+        max_per_node: 8,
+    });
+    assert!(matches!(
+        parking.park(&node_id("b1@b"), "old".to_string(), false),
+        Ok(parking::ParkResult::Parked)
+    ));
+    std::thread::sleep(Duration::from_millis(30));
+    assert!(matches!(
+        parking.park(&node_id("b1@b"), "new".to_string(), false),
+        Ok(parking::ParkResult::Parked)
+    ));
+    assert_eq!("new", parking.pop(&node_id("b1@b")).unwrap());
+    assert!(parking.pop(&node_id("b1@b")).is_none());
+    assert_eq!(
+        vec![(node_id("b1@b"), "old".to_string())],
+        parking.take_expired()
+    );
+    // still queued until the flush confirms it
+    assert!(!parking.finish(&node_id("b1@b")));
+    parking.done(&node_id("b1@b"), 1);
+    assert!(parking.finish(&node_id("b1@b")));
+}
+
+fn temp_dir(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    path.display().to_string()
+}
+
+fn wal_lines(dir: &str) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+        .map(|content| content.lines().count())
+        .sum()
+}
+
+#[test]
+fn test_durable_parking_survives_restart() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let dir = temp_dir("relayer_wal_restart");
+    let limits = parking::ParkingLimits::default();
+    let message = |content: &str| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new(content.to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        ..Default::default()
+    };
+    let start = || {
+        let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+        state.parking = Some(Arc::new(
+            Parking::open(limits, &dir, Router::get_target_id).unwrap(),
+        ));
+        for id in ["a1@a", "b1@b"] {
+            state.pub_keys.insert(node_id(id), pu.clone());
+        }
+        let (a_tx, _): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+            broadcast::channel(16);
+        add_route(&state, &node_id("a1@a"), a_tx).unwrap();
+        state
+    };
+
+    let state = start();
+    for content in ["one", "two", "three"] {
+        rt.block_on(dispatch_msg(&state, &node_id("a1@a"), message(content)))
+            .unwrap();
+    }
+    // the relayer dies without any shutdown
+    drop(state);
+
+    let state = start();
+    assert_eq!(3, state.parking.as_ref().unwrap().len(&node_id("b1@b")));
+    let (b_tx, b_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&state, &node_id("b1@b"), b_tx).unwrap();
+    let drain = rt.spawn(drain_writer(state.clone(), "b1@b", b_rx, 3));
+    rt.block_on(flush_parked(state.clone(), node_id("b1@b")));
+    let (flushed, _) = rt.block_on(drain).unwrap();
+    assert_eq!(vec!["one", "two", "three"], flushed);
+    drop(state);
+
+    let state = start();
+    assert_eq!(0, state.parking.as_ref().unwrap().len(&node_id("b1@b")));
+    assert_eq!(0, wal_lines(&dir));
+}
+
+#[test]
+fn test_wal_migrates_legacy_ids() {
+    let dir = temp_dir("relayer_wal_legacy");
+    let (mut wal, _) = wal::Wal::open::<BridgeMessage>(&dir).unwrap();
+    let parked = BridgeMessage {
+        to_name: Box::new("b1".to_string()),
+        to_group: Box::new("b".to_string()),
+        message: Box::new("parked before".to_string()),
+        ..Default::default()
+    };
+    let entry = wal::WalEntry {
+        seq: 0,
+        at: std::time::SystemTime::now(),
+        item: parked,
     };
+    wal.push("b1b", &entry).unwrap();
+    drop(wal);
+
+    let limits = parking::ParkingLimits::default();
+    let parking: Parking<BridgeMessage> =
+        Parking::open(limits, &dir, Router::get_target_id).unwrap();
+    assert_eq!(1, parking.len(&node_id("b1@b")));
+    drop(parking);
+    // moved for good, nothing is left under the old id
+    let parking: Parking<BridgeMessage> = Parking::open(limits, &dir, |_| unreachable!()).unwrap();
+    assert_eq!(
+        "parked before",
+        parking.pop(&node_id("b1@b")).unwrap().message.as_str()
+    );
+}
+
+#[test]
+fn test_wal_crash_recovery_and_compaction() {
+    let dir = temp_dir("relayer_wal_crash");
+    let limits = parking::ParkingLimits::default();
+    let parking: Parking<String> = Parking::open(limits, &dir, |_| unreachable!()).unwrap();
+    for content in ["one", "two", "three"] {
+        parking
+            .park(&node_id("b1@b"), content.to_string(), false)
+            .unwrap();
+    }
+    // crash while flushing: popped but never confirmed
+    assert_eq!("one", parking.pop(&node_id("b1@b")).unwrap());
+    assert_eq!("two", parking.pop(&node_id("b1@b")).unwrap());
+    drop(parking);
+
+    // and a record torn in the middle of the write
+    let file = std::fs::read_dir(&dir)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let mut wal = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&file)
+        .unwrap();
+    std::io::Write::write_all(&mut wal, b"{\"Push\":{\"seq\":3,\"at_ms\":1").unwrap();
+    drop(wal);
+
+    let parking: Parking<String> = Parking::open(limits, &dir, |_| unreachable!()).unwrap();
+    assert_eq!(3, parking.len(&node_id("b1@b")));
+    assert_eq!("one", parking.pop(&node_id("b1@b")).unwrap());
+    parking.done(&node_id("b1@b"), 1);
+    // the torn record is gone, new records are readable after it
+    parking
+        .park(&node_id("b1@b"), "four".to_string(), false)
+        .unwrap();
+    drop(parking);
+    let parking: Parking<String> = Parking::open(limits, &dir, |_| unreachable!()).unwrap();
+    let replayed: Vec<String> = std::iter::from_fn(|| parking.pop(&node_id("b1@b"))).collect();
+    assert_eq!(vec!["two", "three", "four"], replayed);
+    parking.done(&node_id("b1@b"), 3);
+
+    for index in 0..100 {
+        parking
+            .park(&node_id("c1@c"), index.to_string(), false)
+            .unwrap();
+    }
+    for _ in 0..90 {
+        parking.pop(&node_id("c1@c")).unwrap();
+    }
+    parking.done(&node_id("c1@c"), 90);
+    // compacted down to the live records
+    assert!(wal_lines(&dir) <= 10);
+    drop(parking);
+    let parking: Parking<String> = Parking::open(limits, &dir, |_| unreachable!()).unwrap();
+    assert_eq!(10, parking.len(&node_id("c1@c")));
+    assert_eq!("90", parking.pop(&node_id("c1@c")).unwrap());
+    drop(parking);
+
+    // a bad record before the end is corruption, not a torn write
+    let file = std::fs::read_dir(&dir)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let original = std::fs::read_to_string(&file).unwrap();
+    std::fs::write(&file, format!("{{\"Done\":{{\"s\n{}", original)).unwrap();
+    assert!(Parking::<String>::open(limits, &dir, |_| unreachable!()).is_err());
+    assert_eq!(original.lines().count() + 1, wal_lines(&dir));
+}
+
+/// A relayer of a federation, with its identity key to pin at its peers.
+fn federated(relayer_id: &str) -> (RelayerState<BridgeMessage>, RsaPublicKey) {
+    let (pr, pu) = get_rsa().unwrap();
+    let mut state = RelayerState::new(pr);
+    state.federation = Some(Arc::new(Federation::new(relayer_id)));
+    (state, pu)
+}
+
+/// Pin each other's keys and link `a` to `b` over loopback.
+async fn link_up(
+    a: &RelayerState<BridgeMessage>,
+    a_key: &RsaPublicKey,
+    b: &RelayerState<BridgeMessage>,
+    b_key: &RsaPublicKey,
+) {
+    let a_fed = a.federation.as_ref().unwrap();
+    let b_fed = b.federation.as_ref().unwrap();
+    a_fed.add_peer(&b_fed.relayer_id, b_key.clone()).unwrap();
+    b_fed.add_peer(&a_fed.relayer_id, a_key.clone()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(listen_peers(b.clone(), listener));
+    assert_eq!(
+        b_fed.relayer_id,
+        connect_peer(a.clone(), &addr).await.unwrap()
+    );
+}
 
-    route_table
-        .lock()
+async fn wait_route(state: &RelayerState<BridgeMessage>, id: &str) -> usize {
+    let id = node_id(id);
+    let federation = state.federation.as_ref().unwrap();
+    for _ in 0..500 {
+        if let Some(hops) = federation.hops_to(&id) {
+            return hops;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("no route to {}", id);
+}
+
+async fn recv_within(rx: &mut BcMsgReceiver<BridgeMessage>) -> BridgeMessage {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
         .unwrap()
-        .insert(bmsg.get_source_id(), send_tx);
+}
+
+#[test]
+fn test_federated_relayers_in_line() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let (a, a_key) = federated("a");
+    let (b, b_key) = federated("b");
+    let (c, c_key) = federated("c");
+    a.pub_keys.insert(node_id("a1@a"), pu);
+    let (a1_tx, mut a1_rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&a, &node_id("a1@a"), a1_tx).unwrap();
+    let mut c_rx = Vec::new();
+    for name in ["c1", "c2"] {
+        let (tx, rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+            broadcast::channel(16);
+        add_route(&c, &node_id(&format!("{}@c", name)), tx).unwrap();
+        c_rx.push(rx);
+    }
+    let message = |to_name: &str, to_group: &str, delivery: Option<Delivery>| BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new(to_name.to_string()),
+        to_group: Box::new(to_group.to_string()),
+        message: Box::new("across relayers".to_string()),
+        sig: Some(sign("a1@a", &pr).unwrap()),
+        delivery,
+        ..Default::default()
+    };
+
+    rt.block_on(async {
+        // a - b - c, c's nodes are two hops away from a
+        link_up(&b, &b_key, &c, &c_key).await;
+        link_up(&a, &a_key, &b, &b_key).await;
+        assert_eq!(2, wait_route(&a, "c1@c").await);
+        assert_eq!(2, wait_route(&c, "a1@a").await);
+
+        dispatch_msg(&a, &node_id("a1@a"), message("c1", "c", None))
+            .await
+            .unwrap();
+        let got = recv_within(&mut c_rx[0]).await;
+        assert_eq!("across relayers", got.message.as_str());
+        assert_eq!(node_id("a1@a"), got.get_source_id());
+
+        let multicast = Delivery::Multicast {
+            exclude_sender: false,
+            report: true,
+        };
+        dispatch_msg(&a, &node_id("a1@a"), message("", "c", Some(multicast)))
+            .await
+            .unwrap();
+        for (rx, name) in c_rx.iter_mut().zip(["c1", "c2"]) {
+            assert_eq!(name, recv_within(rx).await.to_name.as_str());
+        }
+        let report = recv_within(&mut a1_rx).await;
+        match report.control {
+            Some(ControlEvent::DeliveryReport(results)) => {
+                assert_eq!(1, results.len());
+                assert_eq!("c", results[0].target);
+                assert!(results[0].error.is_none());
+            }
+            _ => panic!("expect a delivery report"),
+        }
+
+        // c bounces a message for a node it doesn't have, the bounce goes
+        // back through b and reaches a1 signed by a
+        let path = vec!["a".to_string(), "b".to_string()];
+        let scope = Scope::Node {
+            id: node_id("c9@c"),
+        };
+        let lost = message("c9", "c", None);
+        assert_eq!(
+            AuditDecision::Bounced,
+            route_forwarded(&c, "b", path, scope, lost, false).unwrap()
+        );
+        let bounce = recv_within(&mut a1_rx).await;
+        assert!(relayer_verify(&bounce, &a_key).is_ok());
+        assert_eq!("can't find target", bounce.error_msg.unwrap().as_str());
+    });
+
+    // a relayer whose key isn't pinned can't link
+    let (d, _) = federated("d");
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(listen_peers(b.clone(), listener));
+        assert!(connect_peer(d, &addr).await.is_err());
+    });
+}
+
+#[test]
+fn test_forwarding_loops_and_hop_limit() {
+    let (a, _) = federated("a");
+    let (tx, mut rx): (BcMsgSender<BridgeMessage>, BcMsgReceiver<BridgeMessage>) =
+        broadcast::channel(16);
+    add_route(&a, &node_id("a1@a"), tx).unwrap();
+    let message = BridgeMessage {
+        from_name: Box::new("x1".to_string()),
+        from_group: Box::new("x".to_string()),
+        to_name: Box::new("a1".to_string()),
+        to_group: Box::new("a".to_string()),
+        message: Box::new("going round".to_string()),
+        ..Default::default()
+    };
+    let node = || Scope::Node {
+        id: node_id("a1@a"),
+    };
+    let path = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<String>>();
+
+    // went through a before
+    let looped = route_forwarded(
+        &a,
+        "b",
+        path(&["x", "a", "b"]),
+        node(),
+        message.clone(),
+        false,
+    );
+    assert_eq!(Err("routing loop".to_string()), looped);
+    // through more relayers than allowed
+    let max_hops = a.federation.as_ref().unwrap().max_hops;
+    let long: Vec<String> = (0..=max_hops).map(|hop| format!("r{}", hop)).collect();
+    let too_far = route_forwarded(&a, "b", long, node(), message.clone(), false);
+    assert_eq!(Err("hop limit reached".to_string()), too_far);
+    let reasons: Vec<String> = a
+        .dead_letters
+        .list()
+        .into_iter()
+        .map(|letter| letter.reason)
+        .collect();
+    assert_eq!(vec!["routing loop", "hop limit reached"], reasons);
+
+    assert_eq!(
+        Ok(AuditDecision::Delivered),
+        route_forwarded(&a, "b", path(&["x", "b"]), node(), message.clone(), false)
+    );
+
+    // a forwarded message posing as a relayer notice is dropped, a notice
+    // goes on signed by a
+    let mut forged = message;
+    forged.error_msg = Some(Box::new("can't find target".to_string()));
+    let posing = route_forwarded(&a, "b", path(&["x", "b"]), node(), forged.clone(), false);
+    assert_eq!(
+        Err("relayer fields don't match the frame".to_string()),
+        posing
+    );
+    assert_eq!(
+        Ok(AuditDecision::Delivered),
+        route_forwarded(&a, "b", path(&["x", "b"]), node(), forged, true)
+    );
+    assert!(rx.try_recv().unwrap().error_msg.is_none());
+    let notice = rx.try_recv().unwrap();
+    let a_key = RsaPublicKey::from(a.identity_key.as_ref());
+    assert!(relayer_verify(&notice, &a_key).is_ok());
+}
+
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn wait_for(check: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if check() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("condition not met in time");
+}
+
+#[test]
+fn test_cluster_fails_over() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let (node_pr, node_pu) = get_rsa().unwrap();
+    let members: Vec<(String, String)> = ["r0", "r1", "r2"]
+        .iter()
+        .map(|id| (id.to_string(), free_addr()))
+        .collect();
+    let mut instances = Vec::new();
+    for (id, _) in &members {
+        let mut cluster = Cluster::new(id, members.clone());
+        cluster.heartbeat_interval = Duration::from_millis(100);
+        let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+        state.cluster = Some(Arc::new(cluster));
+        let instance_rt = get_runtime();
+        instance_rt.spawn(run_cluster(state.clone()));
+        // started in listed order, so r0 leads
+        wait_for(|| state.cluster.as_ref().unwrap().leader() == Some("r0".to_string()));
+        instances.push((instance_rt, state));
+    }
+
+    // a machine with node a1 registered at the leader
+    let machine = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let register_info = RegisterInfo {
+        addr: Box::new(machine.local_addr().unwrap().to_string()),
+        name: Box::new("a1".to_string()),
+        group: Box::new("a".to_string()),
+    };
+    let r0 = instances[0].1.clone();
+    record_registration(&r0, &register_info, &node_pu).unwrap();
+    r0.pub_keys.insert(node_id("a1@a"), node_pu.clone());
+    let connecting = RegisterInfo {
+        addr: register_info.addr.clone(),
+        name: register_info.name.clone(),
+        group: register_info.group.clone(),
+    };
+    instances[0].0.spawn(async move {
+        relayer_connect(&connecting, r0).await.unwrap();
+    });
+    let _first = rt.block_on(accept_relayer(&machine, &pu));
+    for (_, follower) in &instances[1..] {
+        wait_for(|| follower.pub_keys.contains(&node_id("a1@a")));
+        assert!(record_registration(follower, &register_info, &node_pu).is_err());
+    }
+
+    // the leader dies, r1 takes over and connects to the machine
+    let (leader_rt, _) = instances.remove(0);
+    leader_rt.shutdown_background();
+    let mut second = rt
+        .block_on(async {
+            tokio::time::timeout(Duration::from_secs(5), accept_relayer(&machine, &pu)).await
+        })
+        .unwrap();
+    for (_, instance) in &instances {
+        wait_for(|| instance.cluster.as_ref().unwrap().leader() == Some("r1".to_string()));
+    }
+
+    let bmsg = BridgeMessage {
+        from_name: Box::new("a1".to_string()),
+        from_group: Box::new("a".to_string()),
+        to_name: Box::new("a1".to_string()),
+        to_group: Box::new("a".to_string()),
+        message: Box::new("after failover".to_string()),
+        sig: Some(sign("a1@a", &node_pr).unwrap()),
+        ..Default::default()
+    };
+    let echoed = rt.block_on(async {
+        let mut serialized = serde_json::to_string(&bmsg).unwrap();
+        serialized.push_str(frame_common::codec::DELIMITER);
+        second.write_all(serialized.as_bytes()).await.unwrap();
+        let mut decoder = FrameDecoder::new(4096);
+        let mut buf = [0; 4096];
+        loop {
+            let size = second.read(&mut buf).await.unwrap();
+            assert!(size > 0);
+            if let Some(frame) = decoder.push(&buf[..size]).pop() {
+                let msg: BridgeMessage = serde_json::from_str(&frame.unwrap()).unwrap();
+                break msg;
+            }
+        }
+    });
+    assert_eq!("after failover", echoed.message.as_str());
+    assert!(echoed.error_msg.is_none());
+}
+
+#[test]
+fn test_cluster_replicates_moves() {
+    let (pr, _) = get_rsa().unwrap();
+    let (_, node_pu) = get_rsa().unwrap();
+    let members: Vec<(String, String)> = ["r0", "r1"]
+        .iter()
+        .map(|id| (id.to_string(), free_addr()))
+        .collect();
+    let mut instances = Vec::new();
+    for (id, _) in &members {
+        let mut cluster = Cluster::new(id, members.clone());
+        cluster.heartbeat_interval = Duration::from_millis(100);
+        let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+        state.cluster = Some(Arc::new(cluster));
+        state.migrations = Arc::new(Migrations::new(Duration::from_millis(500)));
+        let instance_rt = get_runtime();
+        instance_rt.spawn(run_cluster(state.clone()));
+        wait_for(|| state.cluster.as_ref().unwrap().leader() == Some("r0".to_string()));
+        instances.push((instance_rt, state));
+    }
+    let r0 = instances[0].1.clone();
+    let r1 = instances[1].1.clone();
+    let register_info = RegisterInfo {
+        addr: Box::new(free_addr()),
+        name: Box::new("a1".to_string()),
+        group: Box::new("a".to_string()),
+    };
+    register_key(&r0, &register_info, node_pu).unwrap();
+    wait_for(|| r1.pub_keys.contains(&node_id("a1@a")));
+
+    // the follower knows a1a moves, so it would park for it on taking over
+    begin_migration(&r0, &node_id("a1@a")).unwrap();
+    wait_for(|| r1.migrations.is_moving(&node_id("a1@a")));
+    assert!(r1.cluster.as_ref().unwrap().is_moving(&node_id("a1@a")));
+
+    // the leader gives up on it, and so does the follower
+    instances[0].0.spawn(run_migrations(r0.clone()));
+    wait_for(|| !r0.migrations.is_moving(&node_id("a1@a")));
+    wait_for(|| !r1.migrations.is_moving(&node_id("a1@a")));
+    assert!(!r1.cluster.as_ref().unwrap().is_moving(&node_id("a1@a")));
+}
+
+#[test]
+fn test_cluster_leader_steps_down() {
+    let rt = get_runtime();
+    let (pr, pu) = get_rsa().unwrap();
+    let (_, node_pu) = get_rsa().unwrap();
+    let r0_addr = free_addr();
+    let r1_addr = free_addr();
+    let start = |id: &str, members: Vec<(String, String)>| {
+        let mut cluster = Cluster::new(id, members);
+        cluster.heartbeat_interval = Duration::from_millis(100);
+        let mut state: RelayerState<BridgeMessage> = RelayerState::new(pr.clone());
+        state.cluster = Some(Arc::new(cluster));
+        let instance_rt = get_runtime();
+        instance_rt.spawn(run_cluster(state.clone()));
+        (instance_rt, state)
+    };
+
+    // r1 leads while r0 is down, and takes a1
+    let (r1_rt, r1) = start(
+        "r1",
+        vec![
+            ("r0".to_string(), r0_addr.clone()),
+            ("r1".to_string(), r1_addr.clone()),
+        ],
+    );
+    wait_for(|| r1.cluster.as_ref().unwrap().is_leader());
+    let machine = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let register_info = RegisterInfo {
+        addr: Box::new(machine.local_addr().unwrap().to_string()),
+        name: Box::new("a1".to_string()),
+        group: Box::new("a".to_string()),
+    };
+    record_registration(&r1, &register_info, &node_pu).unwrap();
+    let connecting = RegisterInfo {
+        addr: register_info.addr.clone(),
+        name: register_info.name.clone(),
+        group: register_info.group.clone(),
+    };
+    let leader = r1.clone();
+    r1_rt.spawn(async move {
+        relayer_connect(&connecting, leader).await.unwrap();
+    });
+    let mut first = rt.block_on(accept_relayer(&machine, &pu));
 
-    pub_keys.lock().unwrap().insert(bmsg.get_source_id(), pu);
+    // r0 comes up without reaching r1, so it leads as well
+    let (_r0_rt, r0) = start(
+        "r0",
+        vec![("r0".to_string(), r0_addr), ("r1".to_string(), free_addr())],
+    );
+    wait_for(|| r0.cluster.as_ref().unwrap().is_leader());
 
-    assert_eq!(true, transfer_msg(route_table, pub_keys, bmsg).is_ok());
+    // r1 hears r0, follows it and hands over a1, which r0 connects to
+    let _second = rt
+        .block_on(async {
+            tokio::time::timeout(Duration::from_secs(5), accept_relayer(&machine, &pu)).await
+        })
+        .unwrap();
+    wait_for(|| r1.cluster.as_ref().unwrap().leader() == Some("r0".to_string()));
+    assert!(r0.pub_keys.contains(&node_id("a1@a")));
+    assert!(r0.cluster.as_ref().unwrap().is_leader());
+    let mut buf = [0; 16];
+    let closed = rt.block_on(async {
+        tokio::time::timeout(Duration::from_secs(5), first.read(&mut buf)).await
+    });
+    assert_eq!(0, closed.unwrap().unwrap());
 }
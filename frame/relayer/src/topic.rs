@@ -0,0 +1,180 @@
+//! Topic subscriptions for publish/subscribe messages.
+//!
+//! Topics are dot separated, like `orders.eu.created`. A pattern matches a
+//! topic segment by segment, where `*` matches exactly one segment and `#`
+//! matches any number of segments, including none. Matching takes time in
+//! proportion to the segments of pattern and topic, both capped.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use frame_common::id::NodeId;
+
+/// Most segments in a pattern or topic.
+pub const MAX_SEGMENTS: usize = 16;
+/// Most patterns one node can subscribe to.
+pub const MAX_SUBSCRIPTIONS: usize = 64;
+
+/// Check a pattern a node wants to subscribe to.
+pub fn check_pattern(pattern: &str) -> Result<(), String> {
+    if pattern.is_empty() || pattern.split('.').any(|segment| segment.is_empty()) {
+        return Err(format!("invalid topic pattern {:?}", pattern));
+    }
+    if pattern.split('.').count() > MAX_SEGMENTS {
+        return Err(format!(
+            "more than {} segments in {:?}",
+            MAX_SEGMENTS, pattern
+        ));
+    }
+    let partial_wildcard = pattern
+        .split('.')
+        .any(|segment| segment.len() > 1 && segment.contains(['*', '#']));
+    if partial_wildcard {
+        return Err(format!("wildcard must be a whole segment in {:?}", pattern));
+    }
+    Ok(())
+}
+
+/// Check a topic a node publishes to. Topics can't contain wildcards.
+pub fn check_topic(topic: &str) -> Result<(), String> {
+    check_pattern(topic)?;
+    if topic.contains(['*', '#']) {
+        return Err(format!("can't publish to wildcard topic {:?}", topic));
+    }
+    Ok(())
+}
+
+/// `pattern` with runs of `#` collapsed into one, which matches the same.
+fn normalize(pattern: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in pattern.split('.') {
+        if segment != "#" || segments.last() != Some(&"#") {
+            segments.push(segment);
+        }
+    }
+    segments.join(".")
+}
+
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let topic: Vec<&str> = topic.split('.').collect();
+    // matched[j]: the pattern so far matches the first j topic segments
+    let mut matched = vec![false; topic.len() + 1];
+    matched[0] = true;
+    for segment in pattern.split('.') {
+        let mut next = vec![false; topic.len() + 1];
+        if segment == "#" {
+            let mut reached = false;
+            for (j, slot) in next.iter_mut().enumerate() {
+                reached |= matched[j];
+                *slot = reached;
+            }
+        } else {
+            for j in 1..=topic.len() {
+                next[j] = matched[j - 1] && (segment == "*" || segment == topic[j - 1]);
+            }
+        }
+        matched = next;
+    }
+    matched[topic.len()]
+}
+
+/// Patterns each node id subscribed to.
+#[derive(Default)]
+pub struct Topics {
+    subscriptions: Mutex<HashMap<NodeId, BTreeSet<String>>>,
+}
+
+impl Topics {
+    pub fn subscribe(&self, id: &NodeId, pattern: &str) -> Result<(), String> {
+        check_pattern(pattern)?;
+        let pattern = normalize(pattern);
+        let mut lock = self.subscriptions.lock().map_err(|err| err.to_string())?;
+        let patterns = lock.entry(id.clone()).or_default();
+        if patterns.len() >= MAX_SUBSCRIPTIONS && !patterns.contains(&pattern) {
+            return Err(format!("more than {} subscriptions", MAX_SUBSCRIPTIONS));
+        }
+        patterns.insert(pattern);
+        Ok(())
+    }
+
+    /// Returns false if `id` wasn't subscribed to `pattern`.
+    pub fn unsubscribe(&self, id: &NodeId, pattern: &str) -> Result<bool, String> {
+        let pattern = normalize(pattern);
+        let mut lock = self.subscriptions.lock().map_err(|err| err.to_string())?;
+        let removed = lock
+            .get_mut(id)
+            .map(|patterns| patterns.remove(&pattern))
+            .unwrap_or(false);
+        lock.retain(|_, patterns| !patterns.is_empty());
+        Ok(removed)
+    }
+
+    /// Drop every subscription of `id`, when its connection goes away.
+    pub fn forget(&self, id: &NodeId) {
+        if let Ok(mut lock) = self.subscriptions.lock() {
+            lock.remove(id);
+        }
+    }
+
+    pub fn patterns(&self, id: &NodeId) -> Vec<String> {
+        self.subscriptions
+            .lock()
+            .map(|lock| lock.get(id).into_iter().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Ids with at least one pattern matching `topic`.
+    pub fn subscribers(&self, topic: &str) -> Result<Vec<NodeId>, String> {
+        let lock = self.subscriptions.lock().map_err(|err| err.to_string())?;
+        Ok(lock
+            .iter()
+            .filter(|(_, patterns)| patterns.iter().any(|pattern| topic_matches(pattern, topic)))
+            .map(|(id, _)| id.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("orders.created", "orders.created"));
+        assert!(topic_matches("orders.*", "orders.created"));
+        assert!(!topic_matches("orders.*", "orders.eu.created"));
+        assert!(!topic_matches("orders.*", "orders"));
+        assert!(topic_matches("orders.#", "orders"));
+        assert!(topic_matches("orders.#", "orders.eu.created"));
+        assert!(topic_matches("#.created", "orders.eu.created"));
+        assert!(topic_matches("*.eu.#", "orders.eu.created"));
+        assert!(!topic_matches("billing.#", "orders.created"));
+
+        assert!(check_pattern("orders.*").is_ok());
+        assert!(check_pattern("orders..created").is_err());
+        assert!(check_pattern("orders.cre*").is_err());
+        assert!(check_topic("orders.*").is_err());
+    }
+
+    #[test]
+    fn test_many_wildcards_stay_fast() {
+        let pattern = vec!["#"; MAX_SEGMENTS - 1].join(".") + ".x";
+        let topic = vec!["a"; MAX_SEGMENTS].join(".");
+        assert!(!topic_matches(&pattern, &topic));
+        assert!(topic_matches(&pattern, &(topic.clone() + ".x")));
+        assert!(check_pattern(&(pattern.clone() + ".y")).is_err());
+
+        let topics = Topics::default();
+        let id = NodeId::new("a1", "a").unwrap();
+        topics.subscribe(&id, &pattern).unwrap();
+        assert_eq!(vec!["#.x".to_string()], topics.patterns(&id));
+        assert!(topics.unsubscribe(&id, "#.#.x").unwrap());
+        for index in 0..MAX_SUBSCRIPTIONS {
+            topics.subscribe(&id, &format!("t{}", index)).unwrap();
+        }
+        assert!(topics.subscribe(&id, "t0").is_ok());
+        assert!(topics.subscribe(&id, "one.more").is_err());
+    }
+}
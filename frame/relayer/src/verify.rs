@@ -0,0 +1,178 @@
+//! Signature checks for incoming messages, kept away from the routing locks.
+//!
+//! RSA verification is the most expensive thing the relayer does per message,
+//! so it runs on tokio's blocking threads, at most `workers` at a time, and
+//! successful checks are remembered for a while so a node sending many
+//! messages doesn't pay for the same proof again. The cache is split into
+//! shards by key, each behind its own lock, so concurrent checks rarely meet.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use frame_common::{get_hash, to_hex, verify};
+
+use crate::routes::DEFAULT_SHARDS;
+use rsa::{PublicKeyParts, RsaPublicKey};
+use tokio::sync::Semaphore;
+
+pub struct Verifier {
+    permits: Semaphore,
+    caches: Vec<Mutex<VerifyCache>>,
+}
+
+impl Verifier {
+    /// `cache_capacity` is shared out evenly between the shards.
+    pub fn new(workers: usize, cache_capacity: usize, cache_ttl: Duration) -> Verifier {
+        let per_shard = cache_capacity.div_ceil(DEFAULT_SHARDS);
+        Verifier {
+            permits: Semaphore::new(workers.max(1)),
+            caches: (0..DEFAULT_SHARDS)
+                .map(|_| Mutex::new(VerifyCache::new(per_shard, cache_ttl)))
+                .collect(),
+        }
+    }
+
+    /// Wait for a free verification worker.
+    pub async fn acquire(&self) -> Result<tokio::sync::SemaphorePermit<'_>, String> {
+        self.permits.acquire().await.map_err(|err| err.to_string())
+    }
+
+    /// Check `sig` over `data` with `public_key`, consulting the cache first.
+    pub fn verify(
+        &self,
+        data: &str,
+        public_key: &RsaPublicKey,
+        sig: &Vec<u8>,
+    ) -> Result<(), String> {
+        let key = cache_key(data, public_key, sig);
+        if self.cache_hit(&key) {
+            return Ok(());
+        }
+        verify(data, public_key, sig)?;
+        if let Ok(mut cache) = self.cache_of(&key).lock() {
+            cache.insert(key);
+        }
+        Ok(())
+    }
+
+    fn cache_hit(&self, key: &str) -> bool {
+        self.cache_of(key)
+            .lock()
+            .map(|cache| cache.contains(key))
+            .unwrap_or(false)
+    }
+
+    /// Keys are hex digests, so their first digits spread evenly.
+    fn cache_of(&self, key: &str) -> &Mutex<VerifyCache> {
+        let index = key
+            .get(..4)
+            .and_then(|prefix| usize::from_str_radix(prefix, 16).ok())
+            .unwrap_or(0);
+        &self.caches[index % self.caches.len()]
+    }
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        let workers = std::thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1);
+        Verifier::new(workers, 4096, Duration::from_secs(300))
+    }
+}
+
+/// The key binds the signed data, the signature and the public key, so a
+/// node re-registering with a new key can't ride on an old entry.
+fn cache_key(data: &str, public_key: &RsaPublicKey, sig: &[u8]) -> String {
+    let material = format!(
+        "{}|{}|{}",
+        data,
+        to_hex(sig),
+        to_hex(&public_key.n().to_bytes_be())
+    );
+    to_hex(&get_hash(&material))
+}
+
+/// Bounded set of recently verified proofs, evicted oldest first. `order`
+/// holds every insert; one whose key was inserted again since is stale and
+/// only waits to reach the front.
+struct VerifyCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, Instant>,
+    order: VecDeque<(String, Instant)>,
+}
+
+impl VerifyCache {
+    fn new(capacity: usize, ttl: Duration) -> VerifyCache {
+        VerifyCache {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        matches!(self.entries.get(key), Some(at) if at.elapsed() < self.ttl)
+    }
+
+    fn insert(&mut self, key: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        self.entries.insert(key.clone(), now);
+        self.order.push_back((key, now));
+        while let Some((oldest, at)) = self.order.front() {
+            let current = self.entries.get(oldest) == Some(at);
+            let evict = self.entries.len() > self.capacity || at.elapsed() >= self.ttl;
+            if current && !evict {
+                break;
+            }
+            if let Some((oldest, _)) = self.order.pop_front() {
+                if current {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_stays_bounded() {
+        let mut cache = VerifyCache::new(2, Duration::from_millis(20));
+        cache.insert("a".to_string());
+        cache.insert("b".to_string());
+        cache.insert("c".to_string());
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b") && cache.contains("c"));
+
+        // expired entries go once they reach the front, inserted again or not
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!cache.contains("b"));
+        for _ in 0..10 {
+            cache.insert("b".to_string());
+        }
+        assert!(cache.contains("b"));
+        assert_eq!(1, cache.entries.len());
+        assert_eq!(1, cache.order.len());
+    }
+
+    #[test]
+    fn test_cache_shards_spread() {
+        let verifier = Verifier::new(1, 64, Duration::from_secs(60));
+        let used: std::collections::HashSet<*const Mutex<VerifyCache>> = (0..256)
+            .map(|index| to_hex(&get_hash(&index.to_string())))
+            .map(|key| verifier.cache_of(&key) as *const _)
+            .collect();
+        assert_eq!(DEFAULT_SHARDS, used.len());
+    }
+}
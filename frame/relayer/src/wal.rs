@@ -0,0 +1,265 @@
+//! Write-ahead log behind the store-and-forward queues.
+//!
+//! Every destination gets one file in the log directory. A parked message is
+//! appended as a `Push` record and synced before it counts as parked; once it
+//! was delivered a `Done` record follows. Opening the directory replays the
+//! files, so a restarted relayer gets back every message that wasn't done.
+//! A torn record at the end of a file, left by a crash in the middle of a
+//! write, is dropped; a bad record anywhere else fails the replay, and the
+//! file is left alone. When most records of a file are dead it is rewritten
+//! with just the live ones.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use frame_common::to_hex;
+use log::error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Files are compacted once they hold this many records and less than half
+/// of them are live, and removed once none are.
+const COMPACT_AFTER: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+enum Record<M> {
+    Push { seq: u64, at_ms: u64, item: M },
+    Done { seq: u64 },
+}
+
+/// A message that wasn't delivered yet.
+pub struct WalEntry<M> {
+    pub seq: u64,
+    pub at: SystemTime,
+    pub item: M,
+}
+
+/// Live entries of every destination, oldest first.
+pub type Replayed<M> = HashMap<String, Vec<WalEntry<M>>>;
+
+struct WalFile {
+    file: File,
+    records: usize,
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    files: HashMap<String, WalFile>,
+}
+
+impl Wal {
+    /// Open the log in `dir` and replay it.
+    pub fn open<M: Serialize + DeserializeOwned>(dir: &str) -> Result<(Wal, Replayed<M>), String> {
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        let mut wal = Wal {
+            dir: PathBuf::from(dir),
+            files: HashMap::new(),
+        };
+        let mut queues = HashMap::new();
+        for dir_entry in fs::read_dir(dir).map_err(|err| err.to_string())? {
+            let path = dir_entry.map_err(|err| err.to_string())?.path();
+            let id = match id_of(&path) {
+                Some(id) => id,
+                None => continue,
+            };
+            let entries: Vec<WalEntry<M>> = replay(&path)?;
+            // rewrite right away, which also cuts off a torn tail
+            wal.compact(&id, entries.iter())?;
+            if !entries.is_empty() {
+                queues.insert(id, entries);
+            }
+        }
+        Ok((wal, queues))
+    }
+
+    pub fn push<M: Serialize>(&mut self, id: &str, entry: &WalEntry<M>) -> Result<(), String> {
+        let record = Record::Push {
+            seq: entry.seq,
+            at_ms: to_millis(entry.at),
+            item: &entry.item,
+        };
+        self.write(id, &[record], true)
+    }
+
+    /// Like `push`, but leaves syncing to the caller, so one `sync_data` on
+    /// the file from `handle` can cover many pushes.
+    pub fn append<M: Serialize>(&mut self, id: &str, entry: &WalEntry<M>) -> Result<(), String> {
+        let record = Record::Push {
+            seq: entry.seq,
+            at_ms: to_millis(entry.at),
+            item: &entry.item,
+        };
+        self.write(id, &[record], false)
+    }
+
+    /// A handle on the current file of `id`, if it has one.
+    pub fn handle(&self, id: &str) -> Result<Option<File>, String> {
+        match self.files.get(id) {
+            Some(wal_file) => wal_file
+                .file
+                .try_clone()
+                .map(Some)
+                .map_err(|err| err.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    /// Mark `seqs` of `id` delivered. `live` are the entries still waiting,
+    /// written again if the file gets compacted.
+    pub fn done<'a, M: Serialize + 'a>(
+        &mut self,
+        id: &str,
+        seqs: &[u64],
+        live: impl ExactSizeIterator<Item = &'a WalEntry<M>>,
+    ) -> Result<(), String> {
+        if seqs.is_empty() {
+            return Ok(());
+        }
+        let records: Vec<Record<&M>> = seqs.iter().map(|&seq| Record::Done { seq }).collect();
+        self.write(id, &records, true)?;
+        let records = self.files.get(id).map(|file| file.records).unwrap_or(0);
+        let live_count = live.len();
+        if live_count == 0 || (records >= COMPACT_AFTER && live_count * 2 < records) {
+            self.compact(id, live)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the file of `id` with just `live`, or remove it if there are none.
+    pub fn compact<'a, M: Serialize + 'a>(
+        &mut self,
+        id: &str,
+        live: impl Iterator<Item = &'a WalEntry<M>>,
+    ) -> Result<(), String> {
+        let path = self.path_of(id);
+        let tmp_path = path.with_extension("tmp");
+        let mut records = 0;
+        {
+            let mut tmp = File::create(&tmp_path).map_err(|err| err.to_string())?;
+            for entry in live {
+                let record = Record::Push {
+                    seq: entry.seq,
+                    at_ms: to_millis(entry.at),
+                    item: &entry.item,
+                };
+                write_record(&mut tmp, &record)?;
+                records += 1;
+            }
+            tmp.sync_all().map_err(|err| err.to_string())?;
+        }
+        self.files.remove(id);
+        if records == 0 {
+            let _ = fs::remove_file(&tmp_path);
+            let _ = fs::remove_file(&path);
+            return Ok(());
+        }
+        fs::rename(&tmp_path, &path).map_err(|err| err.to_string())?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|err| err.to_string())?;
+        self.files.insert(id.to_string(), WalFile { file, records });
+        Ok(())
+    }
+
+    fn write<M: Serialize>(
+        &mut self,
+        id: &str,
+        records: &[Record<M>],
+        sync: bool,
+    ) -> Result<(), String> {
+        if !self.files.contains_key(id) {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path_of(id))
+                .map_err(|err| err.to_string())?;
+            self.files
+                .insert(id.to_string(), WalFile { file, records: 0 });
+        }
+        let wal_file = self.files.get_mut(id).unwrap();
+        for record in records {
+            write_record(&mut wal_file.file, record)?;
+            wal_file.records += 1;
+        }
+        if !sync {
+            return Ok(());
+        }
+        wal_file.file.sync_data().map_err(|err| err.to_string())
+    }
+
+    fn path_of(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.wal", to_hex(id.as_bytes())))
+    }
+}
+
+fn write_record<M: Serialize>(file: &mut File, record: &Record<M>) -> Result<(), String> {
+    let mut line = serde_json::to_string(record).map_err(|err| err.to_string())?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+        .map_err(|err| err.to_string())
+}
+
+fn replay<M: DeserializeOwned>(path: &Path) -> Result<Vec<WalEntry<M>>, String> {
+    let data = fs::read(path).map_err(|err| err.to_string())?;
+    let mut lines: Vec<&[u8]> = data.split(|byte| *byte == b'\n').collect();
+    // what follows the last newline: nothing, or a record cut short
+    let tail = lines.pop().unwrap_or_default();
+    let mut live = BTreeMap::new();
+    for (index, line) in lines.into_iter().enumerate() {
+        let record = serde_json::from_slice::<Record<M>>(line).map_err(|err| {
+            format!(
+                "wal {} record {} corrupt,error={}",
+                path.display(),
+                index,
+                err
+            )
+        })?;
+        apply(&mut live, record);
+    }
+    if !tail.is_empty() {
+        match serde_json::from_slice::<Record<M>>(tail) {
+            Ok(record) => apply(&mut live, record),
+            Err(err) => error!(
+                "wal {} ends with a torn record,error={}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    Ok(live.into_values().collect())
+}
+
+fn apply<M>(live: &mut BTreeMap<u64, WalEntry<M>>, record: Record<M>) {
+    match record {
+        Record::Push { seq, at_ms, item } => {
+            let at = UNIX_EPOCH + Duration::from_millis(at_ms);
+            live.insert(seq, WalEntry { seq, at, item });
+        }
+        Record::Done { seq } => {
+            live.remove(&seq);
+        }
+    }
+}
+
+fn id_of(path: &Path) -> Option<String> {
+    if path.extension()? != "wal" {
+        return None;
+    }
+    let hex = path.file_stem()?.to_str()?;
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn to_millis(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}